@@ -24,6 +24,7 @@ pkgs.callPackage (
       rustPlatform.bindgenHook
       pkgs.qemu
       pkgs.xxd
+      pkgs.dosfstools
       riscv-cross.stdenv.cc
       pkgs.lldb 
     ];
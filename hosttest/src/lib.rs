@@ -0,0 +1,35 @@
+//! The parts of the kernel that don't touch hardware, built for the host so
+//! their tests run with `cargo test`.
+//!
+//! The files are reexported at the same paths as in the kernel, so their
+//! `crate::` imports resolve unchanged.
+
+#![no_std]
+
+#[path = "../../kernel/src/dev/block/device.rs"]
+pub mod device;
+#[path = "../../kernel/src/fs/fat32.rs"]
+pub mod fat32;
+#[path = "../../kernel/src/fs/inode.rs"]
+pub mod inode;
+#[path = "../../kernel/src/sync/mutex.rs"]
+pub mod mutex;
+
+pub mod alloc {
+    pub extern crate alloc;
+    pub use alloc::*;
+}
+
+pub mod sync {
+    pub use crate::mutex;
+}
+
+pub mod dev {
+    pub mod block {
+        pub use crate::device::*;
+    }
+}
+
+pub mod fs {
+    pub use crate::{fat32, inode::*};
+}
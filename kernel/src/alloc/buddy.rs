@@ -78,6 +78,27 @@ impl Buddy {
         }
     }
 
+    /// Number of free blocks on each order's list, index `i` holding blocks of `2^(i+12)` bytes.
+    pub fn free_counts(&self) -> [usize; MAX_ORDER + 1] {
+        let mut counts = [0; MAX_ORDER + 1];
+        for (count, mut current) in counts.iter_mut().zip(self.free_area.iter().copied()) {
+            while let Some(ptr) = current {
+                *count += 1;
+                current = unsafe { (*ptr.as_ptr()).next };
+            }
+        }
+        counts
+    }
+
+    /// Total bytes sitting on the free lists.
+    pub fn free_bytes(&self) -> usize {
+        self.free_counts()
+            .iter()
+            .enumerate()
+            .map(|(order, count)| count * order_size(order))
+            .sum()
+    }
+
     /// # Safety
     ///
     /// .
@@ -1,13 +1,20 @@
-use core::alloc::{GlobalAlloc, Layout};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 pub mod buddy;
 pub mod page_vec;
 pub mod slab;
+pub mod track;
 
 pub extern crate alloc;
 pub use alloc::*;
 
-use crate::{alloc::slab::SlabAllocator, arch::page::Page, mem::Pointer, sync::mutex::CriticalSpinLock};
+use crate::{
+    alloc::slab::SlabAllocator, arch::page::Page, mem::Pointer, println,
+    sync::mutex::CriticalSpinLock,
+};
 
 #[global_allocator]
 pub static KALLOC: Kalloc = Kalloc::new();
@@ -28,24 +35,32 @@ impl Default for Kalloc {
 
 static SLAB: CriticalSpinLock<SlabAllocator> = CriticalSpinLock::new(SlabAllocator::new());
 
+/// Pages handed out directly from [`crate::mem::pages`] for allocations too big for the slabs.
+static LARGE_PAGES: AtomicUsize = AtomicUsize::new(0);
+static LARGE_PAGES_HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+
 unsafe impl GlobalAlloc for Kalloc {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        if layout.size() <= core::mem::size_of::<Page>()/2
+        let ptr = if layout.size() <= core::mem::size_of::<Page>()/2
             && layout.align() < core::mem::align_of::<Page>()/2
         {
             SLAB.lock().alloc(layout)
         } else {
-           unsafe {
-                crate::mem::pages::pages_zeroed(
-                    layout.size().div_ceil(core::mem::size_of::<Page>()),
-                )
+            let pages = layout.size().div_ceil(core::mem::size_of::<Page>());
+            let total = LARGE_PAGES.fetch_add(pages, Ordering::Relaxed) + pages;
+            LARGE_PAGES_HIGH_WATER.fetch_max(total, Ordering::Relaxed);
+            unsafe {
+                crate::mem::pages::pages_zeroed(pages)
                 .virt()
                 .cast()
             }
-        }
+        };
+        track::record(ptr, layout.size());
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        track::forget(ptr);
         if layout.size() <= core::mem::size_of::<Page>()/2
             && layout.align() < core::mem::align_of::<Page>()/2
         {
@@ -53,10 +68,12 @@ unsafe impl GlobalAlloc for Kalloc {
                 SLAB.lock().free(ptr, layout)
             }
         } else {
+            let pages = layout.size().div_ceil(core::mem::size_of::<Page>());
+            LARGE_PAGES.fetch_sub(pages, Ordering::Relaxed);
             unsafe {
                 crate::mem::pages::free_pages_contiguous(
                     Pointer::from_virt(ptr.cast()),
-                    layout.size().div_ceil(core::mem::size_of::<Page>()),
+                    pages,
                 );
             }
         }
@@ -81,3 +98,33 @@ pub unsafe fn init() {
     SLAB.lock().add_cache(Layout::new::<[usize; 128]>());
     SLAB.lock().add_cache(Layout::new::<[usize; 256]>());
 }
+
+/// Prints usage of every slab cache, of the large allocations and what is left in the buddy allocator.
+pub fn dump_stats() {
+    println!("slab caches:");
+    println!("    {:>6} {:>6} {:>8} {:>8} {:>6}", "size", "align", "in use", "peak", "pages");
+    for cache in SLAB.lock().stats() {
+        println!(
+            "    {:>6} {:>6} {:>8} {:>8} {:>6}",
+            cache.layout.size(),
+            cache.layout.align(),
+            cache.in_use,
+            cache.high_water,
+            cache.pages
+        );
+    }
+    println!(
+        "large allocations: {} pages, peak {} pages",
+        LARGE_PAGES.load(Ordering::Relaxed),
+        LARGE_PAGES_HIGH_WATER.load(Ordering::Relaxed)
+    );
+
+    let buddy = crate::mem::pages::BUDDY.lock();
+    println!("buddy free blocks:");
+    for (order, count) in buddy.free_counts().iter().enumerate() {
+        if *count != 0 {
+            println!("    2^({order}+12): {count}");
+        }
+    }
+    println!("buddy free: {} KiB", buddy.free_bytes() / 1024);
+}
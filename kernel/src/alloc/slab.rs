@@ -19,6 +19,21 @@ struct ListNode {
 struct Cache {
     free_list: Option<NonNull<ListNode>>,
     layout: Layout,
+    in_use: usize,
+    pages: usize,
+    high_water: usize,
+}
+
+/// Usage of a single slab cache.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub layout: Layout,
+    /// Objects currently handed out.
+    pub in_use: usize,
+    /// Pages backing the cache, slab pages are never given back.
+    pub pages: usize,
+    /// Most objects ever in use at once.
+    pub high_water: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -58,6 +73,9 @@ impl Caches {
             Cache {
                 free_list: None,
                 layout,
+                in_use: 0,
+                pages: 0,
+                high_water: 0,
             },
         );
     }
@@ -150,6 +168,7 @@ impl Pages {
             }
         }
         cache.free_list = NonNull::new(slab.virt().cast());
+        cache.pages += 1;
     }
 
     fn remove(&mut self, index: usize) {
@@ -182,6 +201,15 @@ impl SlabAllocator {
         self.caches.add_cache(layout);
     }
 
+    pub fn stats(&self) -> impl Iterator<Item = CacheStats> + '_ {
+        self.caches.caches.iter().map(|cache| CacheStats {
+            layout: cache.layout,
+            in_use: cache.in_use,
+            pages: cache.pages,
+            high_water: cache.high_water,
+        })
+    }
+
     pub fn alloc(&mut self, layout: Layout) -> *mut u8 {
         let cache = self.caches.find_closest_cache(Self::round_layout(layout));
 
@@ -204,6 +232,9 @@ impl SlabAllocator {
             page.free -= 1;
             page.allocated += 1;
 
+            cache.in_use += 1;
+            cache.high_water = cache.high_water.max(cache.in_use);
+
 
             next.as_ptr().cast()
         } else {
@@ -236,6 +267,7 @@ impl SlabAllocator {
 
         // TODO reclaim completely free pages?
         let cache = self.caches.find_cache(page.layout);
+        cache.in_use -= 1;
         unsafe {
             ptr.cast::<ListNode>().write(ListNode {
                 next: cache.free_list,
@@ -0,0 +1,106 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{arch::trace, println, sync::mutex::CriticalSpinLock};
+
+/// Live allocations that can be tracked at once, anything past it is only counted.
+const MAX_TRACKED: usize = 1024;
+
+/// Return addresses kept per allocation.
+pub const TRACE_DEPTH: usize = 6;
+
+/// Frames belonging to the allocator itself, skipped when recording a call site.
+const SKIP_FRAMES: usize = 2;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub ptr: usize,
+    pub size: usize,
+    pub trace: [usize; TRACE_DEPTH],
+}
+
+struct Tracker {
+    records: [Option<Record>; MAX_TRACKED],
+    /// Allocations made while the table was full.
+    dropped: usize,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static TRACKER: CriticalSpinLock<Tracker> = CriticalSpinLock::new(Tracker {
+    records: [None; MAX_TRACKED],
+    dropped: 0,
+});
+
+/// Starts or stops recording call sites of heap allocations. Starting over
+/// forgets whatever was recorded before.
+pub fn set_enabled(enabled: bool) {
+    if enabled {
+        let mut tracker = TRACKER.lock();
+        tracker.records.fill(None);
+        tracker.dropped = 0;
+    }
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[inline(always)]
+pub(super) fn record(ptr: *mut u8, size: usize) {
+    if !enabled() || ptr.is_null() {
+        return;
+    }
+    let mut record = Record {
+        ptr: ptr as usize,
+        size,
+        trace: [0; TRACE_DEPTH],
+    };
+    trace::capture(SKIP_FRAMES, &mut record.trace);
+
+    let mut tracker = TRACKER.lock();
+    match tracker.records.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => *slot = Some(record),
+        None => tracker.dropped += 1,
+    }
+}
+
+pub(super) fn forget(ptr: *mut u8) {
+    if !enabled() {
+        return;
+    }
+    let mut tracker = TRACKER.lock();
+    if let Some(slot) = tracker
+        .records
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|record| record.ptr == ptr as usize))
+    {
+        *slot = None;
+    }
+}
+
+/// Calls `f` with every recorded allocation that hasn't been freed yet.
+pub fn for_each_outstanding(mut f: impl FnMut(&Record)) {
+    let tracker = TRACKER.lock();
+    for record in tracker.records.iter().flatten() {
+        f(record);
+    }
+}
+
+/// Prints every outstanding allocation with the call stack it was made from.
+pub fn dump_outstanding() {
+    let mut count = 0;
+    let mut bytes = 0;
+    for_each_outstanding(|record| {
+        count += 1;
+        bytes += record.size;
+        println!("{:#018x} {:>8} bytes", record.ptr, record.size);
+        for pc in record.trace.iter().take_while(|pc| **pc != 0) {
+            println!("    at {pc:#018x}");
+        }
+    });
+    println!(
+        "{count} outstanding allocations, {bytes} bytes, {} untracked",
+        TRACKER.lock().dropped
+    );
+}
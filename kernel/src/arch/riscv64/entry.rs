@@ -1,7 +1,7 @@
 use core::{
     arch::asm,
     fmt::Write,
-    sync::atomic::{AtomicBool, fence},
+    sync::atomic::{AtomicBool, Ordering, fence},
 };
 
 use crate::{
@@ -14,8 +14,9 @@ use crate::{
     std::stdio,
 };
 
+/// Only reached when we were entered in M-mode, in which case we are our own SBI firmware.
 #[allow(unsafe_op_in_unsafe_fn)]
-unsafe extern "C" fn m_mode_setup(_: usize, _: *const u8, _vma: usize, pma: usize) {
+unsafe extern "C" fn m_mode_setup(hart_id: usize, dtb_ptr: *const u8, _vma: usize, pma: usize) {
     uart::early_pre_vm();
     crate::stdio::set_sout(early_print);
     relocate_kernel(pma);
@@ -23,69 +24,22 @@ unsafe extern "C" fn m_mode_setup(_: usize, _: *const u8, _vma: usize, pma: usiz
     riscv::register::mtvec::write(riscv::register::mtvec::Mtvec::from_bits(early_panic as *mut() as usize));
 
     println!("Entered M-Mode");
+    ENTERED_IN_M_MODE.store(true, Ordering::Relaxed);
 
-    let mut medeleg = riscv::register::medeleg::read();
-    medeleg.set_breakpoint(true);
-    medeleg.set_illegal_instruction(true);
-    medeleg.set_instruction_fault(true);
-    medeleg.set_instruction_misaligned(true);
-    medeleg.set_instruction_page_fault(true);
-    medeleg.set_load_fault(true);
-    medeleg.set_load_misaligned(true);
-    medeleg.set_store_fault(true);
-    medeleg.set_store_page_fault(true);
-    medeleg.set_supervisor_env_call(true);
-    medeleg.set_user_env_call(true);
-    riscv::register::medeleg::write(medeleg);
-
-    let mut mideleg = riscv::register::mideleg::read();
-    mideleg.set_sext(true);
-    mideleg.set_ssoft(true);
-    mideleg.set_stimer(true);
-    riscv::register::mideleg::write(mideleg);
-
-    riscv::register::pmpcfg0::set_pmp(
-        0,
-        riscv::register::Range::OFF,
-        riscv::register::Permission::RWX,
-        false,
-    );
-    riscv::register::pmpcfg0::set_pmp(
-        1,
-        riscv::register::Range::TOR,
-        riscv::register::Permission::RWX,
-        false,
-    );
-    riscv::register::pmpaddr0::write(0);
-    riscv::register::pmpaddr1::write(usize::MAX);
-
-    riscv::register::mie::clear_mext();
-    riscv::register::mie::clear_msoft();
-    riscv::register::mie::clear_mtimer();
-
-    riscv::register::mie::set_sext();
-    riscv::register::mie::set_ssoft();
-    riscv::register::mie::set_stimer();
+    let dtb = Dtb::from_ptr(dtb_ptr).unwrap();
+    super::mtrap::init(&dtb);
+    super::mtrap::init_hart(hart_id);
 
     let mut mstatus = riscv::register::mstatus::read();
     mstatus.set_mpie(false);
     mstatus.set_mpp(riscv::register::mstatus::MPP::Supervisor);
     riscv::register::mstatus::write(mstatus);
 
-    riscv::register::mcounteren::set_cy();
-    for i in 0..64 {
-        _ = riscv::register::mcounteren::try_set_hpm(i);
-    }
-    riscv::register::mcounteren::set_ir();
-    riscv::register::mcounteren::set_tm();
-
-    riscv::register::mtvec::write(riscv::register::mtvec::Mtvec::new(
-        early_panic as *mut () as usize,
-        riscv::register::stvec::TrapMode::Direct,
-    ));
-
     println!("Configured M-Mode");
 
+    // the other harts may now leave `_start` for `mtrap::secondary`
+    super::mtrap::READY.store(true, Ordering::Release);
+
     asm!(
         "
         lla t0, 0f
@@ -97,6 +51,15 @@ unsafe extern "C" fn m_mode_setup(_: usize, _: *const u8, _vma: usize, pma: usiz
 
 static FIRST: AtomicBool = AtomicBool::new(true);
 
+/// Set by `m_mode_setup`. Otherwise a firmware like OpenSBI started us in S-mode,
+/// skipping M-mode setup, and provides the SBI itself.
+static ENTERED_IN_M_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Whether the SBI is served by our own firmware in `mtrap` rather than the one that booted us.
+pub fn builtin_firmware() -> bool {
+    ENTERED_IN_M_MODE.load(Ordering::Relaxed)
+}
+
 pub static mut TRAMPOLINE_ROOT_PAGE: PageTable = PageTable {
     entries: [PageTableEntry::new(); 512],
 };
@@ -141,6 +104,9 @@ unsafe extern "C" fn setup_vm_trampoline(
     crate::stdio::set_sout(early_print);
     relocate_kernel(pma);
 
+    if !builtin_firmware() {
+        println!("Entered in S-Mode, relying on the SBI firmware");
+    }
 
     assert!(pma.is_multiple_of(1 << (12 + 9)), "pma not properly aligned {pma:#x?}");
     assert!(vma.is_multiple_of(1 << (12 + 9)), "vma not properly aligned {vma:#x?}");
@@ -217,6 +183,11 @@ unsafe extern "C" fn setup_vm(_: usize, dtb_ptr: *const u8, vma: usize, pma: usi
 
     let supplier = || unsafe { crate::mem::pages::pages_zeroed(1).cast() };
 
+    let levels = crate::arch::page::probe_levels(supplier, |table| {
+        crate::mem::pages::free_page(table.cast())
+    });
+    println!("Paging mode: {:?}", crate::arch::page::mode_for(levels));
+
     let mut kernel_map = PageTableRoot::new(supplier);
 
     // virt <-> phys
@@ -289,7 +260,7 @@ unsafe extern "C" fn setup_vm(_: usize, dtb_ptr: *const u8, vma: usize, pma: usi
 
     asm!("sfence.vma");
     riscv::register::satp::set(
-        riscv::register::satp::Mode::Sv39,
+        crate::arch::page::satp_mode(),
         0,
         kernel_map.root().phys() as usize >> 12,
     );
@@ -307,10 +278,12 @@ unsafe extern "C" fn setup_vm(_: usize, dtb_ptr: *const u8, vma: usize, pma: usi
 
     crate::alloc::init();
 
+    *crate::mem::KERNEL_MAP.lock() = Some(kernel_map);
+
     println!("Completed kernel meory map");
 }
 
-unsafe extern "C" fn early_panic() {
+pub(super) unsafe extern "C" fn early_panic() {
     uart::early_pre_vm();
     early_print("\n\nearly panic\n\n");
 
@@ -332,6 +305,12 @@ core::arch::global_asm!(
 .global _start
 _start:
 
+    // entered in M-mode every hart comes here, only the first one boots
+    lla t0, {ticket}
+    li t1, 1
+    amoadd.w t1, t1, (t0)
+    bnez t1, {secondary}
+
     // clear bss
     lla a3, _bss_start
     lla a4, _bss_end
@@ -360,18 +339,20 @@ _start:
     csrs sie, t0
     csrs sstatus, t0
 
-    // lla t0, .LmModeDone
-    // csrw stvec, t0
-        
-    //     lla t0, {panic}
-    //     csrw mtvec, t0
-        
-    //     call {m_mode_setup}
-    //     move a0, s1
-    //     move a1, s2
-    //     move a2, s3
-    //     move a3, s4   
-    // .LmModeDone:
+    // writing mtvec traps to .LmModeDone when we already run in S-mode
+    lla t0, .LmModeDone
+    csrw stvec, t0
+
+        lla t0, {panic}
+        csrw mtvec, t0
+
+        call {m_mode_setup}
+        move a0, s1
+        move a1, s2
+        move a2, s3
+        move a3, s4
+    .balign 4
+    .LmModeDone:
 
     lla t0, {panic}
     csrw stvec, t0
@@ -427,6 +408,8 @@ _start:
 ",
     panic = sym early_panic,
     m_mode_setup = sym m_mode_setup,
+    ticket = sym super::mtrap::BOOT_TICKET,
+    secondary = sym super::mtrap::mtrap_secondary_start,
     setup_vm_trampoline = sym setup_vm_trampoline,
     setup_vm = sym setup_vm,
     entry = sym kernel_entry,
@@ -4,7 +4,9 @@ pub mod entry;
 pub mod mtrap;
 pub mod page;
 pub mod reloc;
+pub mod smp;
 pub mod strap;
+pub mod tlb;
 pub mod trace;
 
 pub fn halt() -> ! {
@@ -21,7 +23,36 @@ pub fn link_addr() -> usize {
     out
 }
 
+/// Reads the per-hart pointer kept in `tp` while running kernel code.
+#[inline(always)]
+pub fn tp() -> usize {
+    let out;
+    unsafe {
+        asm!("mv {}, tp", out(reg) out);
+    }
+    out
+}
+
+/// This hart's [`strap::PerCpu`], only valid once [`strap::init`] installed it.
+#[inline(always)]
+pub fn per_cpu() -> &'static strap::PerCpu {
+    unsafe { &*(tp() as *const strap::PerCpu) }
+}
+
+/// Reads a field of this hart's [`strap::PerCpu`], like `per_cpu!(hart_id)`.
+#[macro_export]
+macro_rules! per_cpu {
+    ($field:ident) => {
+        $crate::arch::per_cpu().$field
+    };
+}
 
+/// Id of the hart we are running on.
+///
+/// A task may migrate right after reading it, so it is only stable with interrupts disabled.
+pub fn current_hart() -> usize {
+    crate::per_cpu!(hart_id)
+}
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
@@ -31,6 +62,52 @@ pub struct Frame {
     pub sstatus: riscv::register::sstatus::Sstatus,
 }
 
+impl Frame {
+    pub const RA: usize = 0;
+    pub const SP: usize = 1;
+    pub const TP: usize = 3;
+    pub const FP: usize = 7;
+    pub const A0: usize = 9;
+    pub const A1: usize = 10;
+    pub const A7: usize = 16;
+
+    /// A frame which `strap_return` resumes in S-mode at `pc`, running on `sp`
+    /// with interrupts enabled.
+    pub fn kernel(pc: usize, sp: usize) -> Self {
+        let mut sstatus = riscv::register::sstatus::read();
+        sstatus.set_spie(true);
+        sstatus.set_spp(riscv::register::sstatus::SPP::Supervisor);
+
+        let mut frame = Frame {
+            pc,
+            regs: [0; 31],
+            sstatus,
+        };
+        frame.regs[Self::SP] = sp;
+        frame.regs[Self::TP] = tp();
+        frame
+    }
+
+    /// A frame which `strap_return` resumes in U-mode at `pc` with the user stack `sp`.
+    pub fn user(pc: usize, sp: usize) -> Self {
+        let mut sstatus = riscv::register::sstatus::read();
+        sstatus.set_spie(true);
+        sstatus.set_spp(riscv::register::sstatus::SPP::User);
+
+        let mut frame = Frame {
+            pc,
+            regs: [0; 31],
+            sstatus,
+        };
+        frame.regs[Self::SP] = sp;
+        frame
+    }
+
+    pub fn from_user(&self) -> bool {
+        self.sstatus.spp() == riscv::register::sstatus::SPP::User
+    }
+}
+
 impl Default for Frame{
     fn default() -> Self {
         Self { 
@@ -44,5 +121,14 @@ impl Default for Frame{
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct Context{
-    pub frame: Frame
+    /// Where this context's trap frame was saved when it was switched out.
+    pub frame: *mut Frame
+}
+
+impl Context {
+    pub const fn new() -> Self {
+        Self {
+            frame: core::ptr::null_mut(),
+        }
+    }
 }
\ No newline at end of file
@@ -1,316 +1,786 @@
-// use core::{
-//     arch::global_asm,
-//     mem::MaybeUninit,
-//     ptr::{NonNull, addr_of_mut},
-// };
-
-// use riscv::register::{mcause, stvec::Stvec};
-
-// use crate::println;
-
-// #[repr(C)]
-// #[derive(Clone, Copy, Debug)]
-// pub struct Task {
-//     pub next: NonNull<Task>,
-//     pub stack: *mut u8,
-//     pub frame: TrapFrame,
-// }
-
-// #[repr(C)]
-// #[derive(Clone, Copy, Debug)]
-// pub struct TrapCtx {
-//     pub scratch: usize,
-//     pub task: NonNull<Task>,
-// }
-
-// #[repr(C)]
-// #[derive(Clone, Copy, Debug)]
-// pub struct TrapFrame {
-//     pub pc: usize,
-//     pub regs: [usize; 31],
-//     pub sstatus: riscv::register::mstatus::Mstatus,
-// }
-
-// global_asm!(
-//         r#"
-//     .globl  strap_vector
-
-//     .section .text.strap_vector,"ax",@progbits
-//     .globl strap_vector
-
-//     .balign 4
-//     strap_vector:
-
-//         csrrw x31, sscratch, x31  // swap x31 and sscratch, x31 -> TrapCtx
-//         sd sp, 0(x31)            // TrapCtx->scratch = sp
-
-//         csrr sp, sstatus
-//         andi sp, sp, (1 << 8)   # isolate SPP bit (bit 8)
-
-//         bnez sp, .Lsupervisor
-//             // we are comming from user mode (untrusted stack)
-//             ld sp, 8(x31)            // sp = TrapCtx->task
-//             ld sp, 8(x31)            // sp = Task->kernel_stack
-//             j .Lend
-//         .Lsupervisor:
-//             ld sp, 0(x31)            // sp = TrapCtx->scratch
-//         .Lend:
-
-//         addi sp, sp, -{frame_size}
-
-//         // general registers
-//         sd x1, 1 * 8( sp )
-//         // sp
-//         sd x3, 3 * 8( sp )
-//         sd x4, 4 * 8( sp )
-//         sd x5, 5 * 8( sp )
-//         sd x6, 6 * 8( sp )
-//         sd x7, 7 * 8( sp )
-//         sd x8, 8 * 8( sp )
-//         sd x9, 9 * 8( sp )
-//         sd x10, 10 * 8( sp )
-//         sd x11, 11 * 8( sp )
-//         sd x12, 12 * 8( sp )
-//         sd x13, 13 * 8( sp )
-//         sd x14, 14 * 8( sp )
-//         sd x15, 15 * 8( sp )
-//         sd x16, 16 * 8( sp )
-//         sd x17, 17 * 8( sp )
-//         sd x18, 18 * 8( sp )
-//         sd x19, 19 * 8( sp )
-//         sd x20, 20 * 8( sp )
-//         sd x21, 21 * 8( sp )
-//         sd x22, 22 * 8( sp )
-//         sd x23, 23 * 8( sp )
-//         sd x24, 24 * 8( sp )
-//         sd x25, 25 * 8( sp )
-//         sd x26, 26 * 8( sp )
-//         sd x27, 27 * 8( sp ) // at this point we've saved all "save" registers
-
-//         ld x1, 0(x31) // load stack ptr from ctx scratch
-//         sd x1, 2 * 8( sp ) // save previous stack ptr
-
-//         move s0, x31             // save TrapCtx
-//         csrrw x31, sscratch, x31 // restore sscratch
-
-//         sd x28, 28 * 8( sp )
-//         sd x29, 29 * 8( sp )
-//         sd x30, 30 * 8( sp )
-//         sd x31, 31 * 8( sp )
-
-//         csrr t0, sstatus
-//         sd t0, 32 * 8( sp )
-
-//         addi a0, sp, 0
-//         csrr a1, scause
-//         csrr a2, sepc
-//         csrr a3, stval
-
-//         // save PC
-//         sd a2, 0( sp )
-
-//         jal {handler}
-
-//         strap_return:
-
-//         ld t0, 0(sp)
-//         csrw sepc, t0
-
-//         ld t0, 32 * 8(sp)
-//         csrw sstatus, t0
-
-//         ld x1, 1 * 8( sp )
-//         ld x3, 3 * 8( sp )
-//         ld x4, 4 * 8( sp )
-//         ld x5, 5 * 8( sp )
-//         ld x6, 6 * 8( sp )
-//         ld x7, 7 * 8( sp )
-//         ld x8, 8 * 8( sp )
-//         ld x9, 9 * 8( sp )
-//         ld x10, 10 * 8( sp )
-//         ld x11, 11 * 8( sp )
-//         ld x12, 12 * 8( sp )
-//         ld x13, 13 * 8( sp )
-//         ld x14, 14 * 8( sp )
-//         ld x15, 15 * 8( sp )
-//         ld x16, 16 * 8( sp )
-//         ld x17, 17 * 8( sp )
-//         ld x18, 18 * 8( sp )
-//         ld x19, 19 * 8( sp )
-//         ld x20, 20 * 8( sp )
-//         ld x21, 21 * 8( sp )
-//         ld x22, 22 * 8( sp )
-//         ld x23, 23 * 8( sp )
-//         ld x24, 24 * 8( sp )
-//         ld x25, 25 * 8( sp )
-//         ld x26, 26 * 8( sp )
-//         ld x27, 27 * 8( sp )
-//         ld x28, 28 * 8( sp )
-//         ld x29, 29 * 8( sp )
-//         ld x30, 30 * 8( sp )
-//         ld x31, 31 * 8( sp )
-
-//         ld x2, 2 * 8( sp ) // restore previous stack
-
-//         sret
-//     "#,
-//     handler = sym mtrap_handler,
-//     frame_size = const core::mem::size_of::<TrapFrame>(),
-// );
-
-// pub extern "C" fn mtrap_handler(
-//     frame: &mut TrapFrame,
-//     scause: mcause::Mcause,
-//     sepc: usize,
-//     stval: usize,
-// ) {
-//     println!("M-Trap {frame:x?}");
-
-//     if scause.is_exception() {
-//         let instr_enc = unsafe {(sepc as *const u16).read_volatile()};
-//         if instr_enc & 0b11 != 0b11 {
-//             frame.pc += 2;
-//         } else if instr_enc & 0b11100 != 0b11100 {
-//             frame.pc += 4;
-//         } else if instr_enc & 0b111111 != 0b011111 {
-//             frame.pc += 6;
-//         } else if instr_enc & 0b1111111 != 0b0111111 {
-//             frame.pc += 8;
-//         } else if instr_enc & 0b1111111 == 0b1111111 {
-//             frame.pc += 10 + 16 * ((instr_enc >> 12) as usize & 0b111);
-//         }
-
-//         let desc = match scause.code() {
-//             0 => "Instruction address misaligned",
-//             1 => "Instruction access fault",
-//             2 => "Illegal instruction",
-//             3 => {
-//                 println!("Breakpoint");
-//                 return;
-//             },
-//             4 => "Load address misaligned",
-//             5 => "Load access fault",
-//             6 => "Store address mimsaligned",
-//             7 => "Store access fault",
-//             8 => "Env call from U-mode",
-//             9 => "Env call from S-mode",
-//             11 => {
-//                 // csr::
-//                 println!(
-//                     "\nEnv call from M-mode hardid: \"{}\"... returning",
-//                     riscv::register::marchid::read().bits()
-//                 );
-//                 // println!("{:#?}", frame);
-//                 // timer::mdelay(6000);
-//                 return;
-//             }
-//             12 | 13 | 15 => {
-//                 let desc = match scause.code() {
-//                     12 => "Instruction page fault",
-//                     13 => "Page fault on load",
-//                     15 => "Page fault on store",
-//                     _ => "",
-//                 };
-
-//                 let stap = riscv::register::satp::read();
-//                 println!("{:?}, {:?}, 0x{:x?}", stap.mode(), stap.asid(), stap.ppn());
-
-//                 panic!(
-//                     "\n\n\n{desc}:\nscause: {scause:016x?}, mepc: 0x{sepc:016x}, mtval: 0x{stval:016x}, \nCannot continue resetting\n\n"
-//                 );
-//             }
-//             _ => "Unknown exception",
-//         };
-//         panic!(
-//             "\n\n\n{desc}:\nscause: {scause:016x?}, mepc: 0x{sepc:016x}, mtval: 0x{stval:016x}, \nCannot continue resetting\n\n"
-//         );
-//     } else {
-//         match scause.code() {
-//             0x7 => {}
-//             0xb => {
-//                 // let pending = unsafe { plic::mclaim_int() };
-//                 // use core::sync::atomic::Ordering;
-//                 // if pending != 0 {
-//                 //     if let Some(ptr) = PLIC_HANDLERS
-//                 //         .get(pending as usize)
-//                 //         .and_then(|v: &AtomicPtr<()>| NonNull::new(v.load(Ordering::Acquire)))
-//                 //     {
-//                 //         let func: fn() = unsafe { core::mem::transmute(ptr) };
-//                 //         func()
-//                 //     } else {
-//                 //         panic!("\n\n\nplic: 0x{pending:016x}, mtval: 0x{mtval:016x}\nUnknown plic interrupt value. Cannot continue resetting\n\n");
-//                 //     }
-//                 //     unsafe {
-//                 //         plic::mint_complete(pending);
-//                 //     }
-//                 // } else {
-//                 //     panic!("\n\n\nscause: 0x{scause:016x}, mepc: 0x{mepc:016x}, mtval: 0x{mtval:016x}\nPlic Interrupt but no pending interrupt found? Cannot continue resetting\n\n");
-//                 // }
-//             }
-//             _ => {
-//                 panic!(
-//                     "\n\n\nscause: 0x{scause:016x?}, mepc: 0x{sepc:016x}, mtval: 0x{stval:016x}\nCannot continue resetting\n\n"
-//                 );
-//             }
-//         }
-//     }
-// }
-
-// static mut CORE_TRAP_CTX: MaybeUninit<TrapCtx> = MaybeUninit::zeroed();
-// static mut INIT_TASK: MaybeUninit<Task> = MaybeUninit::zeroed();
-
-// type InitTask = unsafe extern "C" fn(hart_id: usize, dtb_ptr: *const u8) -> !;
-
-// /// # Safety
-// ///
-// /// .
-// pub unsafe fn init(init: InitTask, hart_id: usize, dtb_ptr: *const u8) -> ! {
-//     unsafe extern "C" {
-//         #[link_name = "strap_vector"]
-//         pub fn strap_vector();
-//     }
-//     unsafe {
-//         riscv::register::stvec::write(Stvec::new(
-//             strap_vector as *const () as usize,
-//             riscv::register::stvec::TrapMode::Direct,
-//         ));
-//     }
-
-//     #[allow(static_mut_refs)]
-//     unsafe {
-//         let task = NonNull::new_unchecked(INIT_TASK.as_mut_ptr());
-//         *addr_of_mut!((*task.as_ptr()).next) = task;
-//         *addr_of_mut!((*CORE_TRAP_CTX.as_mut_ptr()).task) = task;
-//         riscv::register::sscratch::write(CORE_TRAP_CTX.as_ptr() as usize);
-
-//         println!("{:?}", CORE_TRAP_CTX.as_ptr());
-//     }
-
-//     let sp = crate::mem::KernelLayout::new().stack.end;
-
-//     let mut sstatus = riscv::register::sstatus::read();
-//     sstatus.set_spie(true);
-//     sstatus.set_spp(riscv::register::sstatus::SPP::Supervisor);
-
-//     let mut frame = TrapFrame {
-//         pc: init as usize,
-//         regs: [0; 31],
-//         sstatus,
-//     };
-//     frame.regs[1] = sp;
-//     frame.regs[9] = hart_id;
-//     frame.regs[10] = dtb_ptr as usize;
-
-//     println!("Beginning Init Task");
-//     unsafe {
-//         core::arch::asm!(
-
-//             "
-//             ebreak
-//             move sp, {0}
-//             tail strap_return",
-//             in(reg) &mut frame,
-//             options(noreturn, nostack)
-//         )
-//     }
-// }
+//! SBI firmware, used when the kernel is entered in M-mode instead of being
+//! loaded by OpenSBI.
+//!
+//! [`init`] and [`init_hart`] point `mtvec` of every hart at `mtrap_vector`,
+//! after which S-mode reaches us through `ecall` with the standard SBI calling
+//! convention. The base, TIME, IPI, RFENCE, HSM, SRST and DBCN extensions are
+//! implemented on top of the CLINT, the syscon and the boot UART.
+//!
+//! Traps are taken with paging off, long after the kernel relocated itself to
+//! its virtual address. Everything reached from [`mtrap_handler`] therefore
+//! has to stick to PC relative code: no trait objects, no formatting and no
+//! panics, which also rules out indexing that isn't checked by hand.
+
+use core::{
+    arch::{asm, global_asm},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+};
+
+use riscv::register::{mcause, mhartid, mie, mstatus};
+
+use crate::{
+    arch::{Frame, smp::MAX_HARTS},
+    dev::{syscon::Syscon, uart::Uart16550},
+    dtb::{Dtb, DtbNodes, DtbProperties},
+    println,
+    sbi::*,
+    timer::clint::Clint,
+};
+
+/// M-mode stack of every hart, only in use while a trap is handled.
+const STACK_SIZE: usize = 4096;
+
+/// Reported through the base extension, not a registered implementation id.
+const IMPL_ID: usize = 0x6b65726e;
+const IMPL_VERSION: usize = 1;
+/// SBI 2.0.
+const SPEC_VERSION: usize = 2 << 24;
+
+const MIP_SSIP: usize = 1 << 1;
+const MIP_STIP: usize = 1 << 5;
+
+/// Work one hart leaves for another before raising its machine software interrupt.
+const PENDING_IPI: usize = 1 << 0;
+const PENDING_FENCE_I: usize = 1 << 1;
+const PENDING_SFENCE_VMA: usize = 1 << 2;
+/// A stopped hart was asked to start, see [`Hart::start_addr`].
+const PENDING_START: usize = 1 << 3;
+
+struct Hart {
+    /// HSM state, one of the `SBI_HSM_STATE_*` values.
+    state: AtomicUsize,
+    start_addr: AtomicUsize,
+    opaque: AtomicUsize,
+    /// `PENDING_*` bits.
+    pending: AtomicUsize,
+}
+
+impl Hart {
+    const fn new() -> Self {
+        Self {
+            state: AtomicUsize::new(SBI_HSM_STATE_STARTED),
+            start_addr: AtomicUsize::new(0),
+            opaque: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+        }
+    }
+}
+
+static HARTS: [Hart; MAX_HARTS] = [const { Hart::new() }; MAX_HARTS];
+
+/// Harts which went through [`init_hart`], one bit per hart id.
+static PRESENT: AtomicUsize = AtomicUsize::new(0);
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+static mut STACKS: [Stack; MAX_HARTS] = [const { Stack([0; STACK_SIZE]) }; MAX_HARTS];
+
+static CLINT_BASE: AtomicUsize = AtomicUsize::new(0);
+static UART_BASE: AtomicUsize = AtomicUsize::new(0);
+/// RAM from the DTB, the only memory S-mode may hand us buffers in.
+static RAM_START: AtomicUsize = AtomicUsize::new(0);
+static RAM_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Most bytes one debug console call moves, callers are told how many and retry.
+const CONSOLE_MAX_LEN: usize = 256;
+static mut SYSCON: Option<Syscon> = None;
+
+/// Every hart enters `_start` at once, the first to take a ticket boots the kernel.
+///
+/// Both live in `.data` since the losers look at them while the winner clears `.bss`.
+#[unsafe(link_section = ".data")]
+pub static BOOT_TICKET: AtomicU32 = AtomicU32::new(0);
+
+/// Set once [`init`] is done, the other harts wait for it before they touch any state.
+#[unsafe(link_section = ".data")]
+pub static READY: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TrapFrame {
+    pub pc: usize,
+    pub regs: [usize; 31],
+}
+
+global_asm!(
+    r#"
+    .section .text.mtrap_vector,"ax",@progbits
+    .globl mtrap_vector
+    .balign 4
+    mtrap_vector:
+        csrrw sp, mscratch, sp      // mscratch holds the top of this hart's M-mode stack
+        addi sp, sp, -{frame_size}
+
+        sd x1, 1 * 8( sp )
+        sd x3, 3 * 8( sp )
+        sd x4, 4 * 8( sp )
+        sd x5, 5 * 8( sp )
+        sd x6, 6 * 8( sp )
+        sd x7, 7 * 8( sp )
+        sd x8, 8 * 8( sp )
+        sd x9, 9 * 8( sp )
+        sd x10, 10 * 8( sp )
+        sd x11, 11 * 8( sp )
+        sd x12, 12 * 8( sp )
+        sd x13, 13 * 8( sp )
+        sd x14, 14 * 8( sp )
+        sd x15, 15 * 8( sp )
+        sd x16, 16 * 8( sp )
+        sd x17, 17 * 8( sp )
+        sd x18, 18 * 8( sp )
+        sd x19, 19 * 8( sp )
+        sd x20, 20 * 8( sp )
+        sd x21, 21 * 8( sp )
+        sd x22, 22 * 8( sp )
+        sd x23, 23 * 8( sp )
+        sd x24, 24 * 8( sp )
+        sd x25, 25 * 8( sp )
+        sd x26, 26 * 8( sp )
+        sd x27, 27 * 8( sp )
+        sd x28, 28 * 8( sp )
+        sd x29, 29 * 8( sp )
+        sd x30, 30 * 8( sp )
+        sd x31, 31 * 8( sp )
+
+        csrr t0, mscratch           // the interrupted sp
+        sd t0, 2 * 8( sp )
+        csrr t0, mepc
+        sd t0, 0( sp )
+
+        mv a0, sp
+        call {handler}
+
+        ld t0, 0( sp )
+        csrw mepc, t0
+        ld t0, 2 * 8( sp )
+        csrw mscratch, t0
+
+        ld x1, 1 * 8( sp )
+        ld x3, 3 * 8( sp )
+        ld x4, 4 * 8( sp )
+        ld x5, 5 * 8( sp )
+        ld x6, 6 * 8( sp )
+        ld x7, 7 * 8( sp )
+        ld x8, 8 * 8( sp )
+        ld x9, 9 * 8( sp )
+        ld x10, 10 * 8( sp )
+        ld x11, 11 * 8( sp )
+        ld x12, 12 * 8( sp )
+        ld x13, 13 * 8( sp )
+        ld x14, 14 * 8( sp )
+        ld x15, 15 * 8( sp )
+        ld x16, 16 * 8( sp )
+        ld x17, 17 * 8( sp )
+        ld x18, 18 * 8( sp )
+        ld x19, 19 * 8( sp )
+        ld x20, 20 * 8( sp )
+        ld x21, 21 * 8( sp )
+        ld x22, 22 * 8( sp )
+        ld x23, 23 * 8( sp )
+        ld x24, 24 * 8( sp )
+        ld x25, 25 * 8( sp )
+        ld x26, 26 * 8( sp )
+        ld x27, 27 * 8( sp )
+        ld x28, 28 * 8( sp )
+        ld x29, 29 * 8( sp )
+        ld x30, 30 * 8( sp )
+        ld x31, 31 * 8( sp )
+
+        addi sp, sp, {frame_size}
+        csrrw sp, mscratch, sp      // back to the interrupted sp, mscratch to our stack top
+        mret
+    "#,
+    handler = sym mtrap_handler,
+    frame_size = const core::mem::size_of::<TrapFrame>(),
+);
+
+fn stack_top(hart: usize) -> usize {
+    (&raw mut STACKS) as usize + (hart + 1) * STACK_SIZE
+}
+
+fn vector() -> usize {
+    let addr;
+    unsafe {
+        asm!("lla {}, mtrap_vector", out(reg) addr);
+    }
+    addr
+}
+
+fn clint() -> Option<Clint> {
+    match CLINT_BASE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(unsafe { Clint::new(base) }),
+    }
+}
+
+fn console() -> Option<Uart16550> {
+    match UART_BASE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(unsafe { Uart16550::new(base as *mut ()) }),
+    }
+}
+
+fn hart(hart: usize) -> Option<&'static Hart> {
+    if hart < MAX_HARTS && PRESENT.load(Ordering::Acquire) & (1 << hart) != 0 {
+        HARTS.get(hart)
+    } else {
+        None
+    }
+}
+
+/// Finds the devices the firmware drives. Runs on the boot hart while the
+/// kernel is still relocated to its physical address.
+pub fn init(dtb: &Dtb) {
+    let cells = dtb.root().addr_size_cells();
+    let base = |compatible: &[u8]| {
+        dtb.nodes().compatible(compatible).next().map(|node| {
+            let [start, _size] = node
+                .properties()
+                .expect_value(b"reg", |stream| stream.usize_cells_arr(cells));
+            start
+        })
+    };
+
+    let clint = base(b"riscv,clint0").expect("no CLINT, the firmware can't do timers or IPIs");
+    CLINT_BASE.store(clint, Ordering::Relaxed);
+    if let Some(uart) = base(b"ns16550a") {
+        UART_BASE.store(uart, Ordering::Relaxed);
+    }
+    let ram = crate::mem::physical_region(dtb);
+    RAM_START.store(ram.start, Ordering::Relaxed);
+    RAM_END.store(ram.end, Ordering::Relaxed);
+    unsafe {
+        SYSCON = Some(Syscon::discover(dtb));
+    }
+
+    println!("SBI firmware: CLINT at {clint:#x}");
+}
+
+/// Configures M-mode on the calling hart and installs the trap vector. Also
+/// run by the secondaries, at which point only PC relative code is safe.
+#[allow(unsafe_op_in_unsafe_fn)]
+pub unsafe fn init_hart(hart: usize) {
+    let mut medeleg = riscv::register::medeleg::read();
+    medeleg.set_breakpoint(true);
+    medeleg.set_illegal_instruction(true);
+    medeleg.set_instruction_fault(true);
+    medeleg.set_instruction_misaligned(true);
+    medeleg.set_instruction_page_fault(true);
+    medeleg.set_load_fault(true);
+    medeleg.set_load_misaligned(true);
+    medeleg.set_store_fault(true);
+    medeleg.set_store_page_fault(true);
+    medeleg.set_supervisor_env_call(false);
+    medeleg.set_user_env_call(true);
+    riscv::register::medeleg::write(medeleg);
+
+    let mut mideleg = riscv::register::mideleg::read();
+    mideleg.set_sext(true);
+    mideleg.set_ssoft(true);
+    mideleg.set_stimer(true);
+    riscv::register::mideleg::write(mideleg);
+
+    riscv::register::pmpcfg0::set_pmp(
+        0,
+        riscv::register::Range::OFF,
+        riscv::register::Permission::RWX,
+        false,
+    );
+    riscv::register::pmpcfg0::set_pmp(
+        1,
+        riscv::register::Range::TOR,
+        riscv::register::Permission::RWX,
+        false,
+    );
+    riscv::register::pmpaddr0::write(0);
+    riscv::register::pmpaddr1::write(usize::MAX);
+
+    riscv::register::mcounteren::set_cy();
+    for i in 0..64 {
+        _ = riscv::register::mcounteren::try_set_hpm(i);
+    }
+    riscv::register::mcounteren::set_ir();
+    riscv::register::mcounteren::set_tm();
+
+    riscv::register::mscratch::write(stack_top(hart));
+    riscv::register::mtvec::write(riscv::register::mtvec::Mtvec::new(
+        vector(),
+        riscv::register::stvec::TrapMode::Direct,
+    ));
+
+    mie::clear_mext();
+    mie::clear_mtimer();
+    mie::set_msoft();
+
+    mie::set_sext();
+    mie::set_ssoft();
+    mie::set_stimer();
+
+    PRESENT.fetch_or(1 << hart, Ordering::Release);
+}
+
+/// Where the harts that lost the race in `_start` continue, on their M-mode
+/// stack. They wait stopped until S-mode starts them through HSM.
+unsafe extern "C" fn secondary(hart: usize) -> ! {
+    if let Some(state) = HARTS.get(hart) {
+        state.state.store(SBI_HSM_STATE_STOPPED, Ordering::Release);
+    }
+    unsafe {
+        init_hart(hart);
+    }
+    park(hart)
+}
+
+extern "C" fn mtrap_handler(frame: &mut TrapFrame) {
+    let cause = mcause::read();
+    let hart = mhartid::read();
+
+    if cause.is_exception() {
+        match cause.code() {
+            // ecall from S-mode
+            9 => {
+                frame.pc += 4;
+                let ret = call(hart, frame);
+                frame.regs[Frame::A0] = ret.error as usize;
+                frame.regs[Frame::A1] = ret.value as usize;
+            }
+            _ => fatal(frame),
+        }
+    } else {
+        match cause.code() {
+            // machine timer, forwarded to S-mode until it sets the next deadline
+            7 => unsafe {
+                mie::clear_mtimer();
+                asm!("csrs mip, {}", in(reg) MIP_STIP);
+            },
+            // machine software, another hart left us some work
+            3 => {
+                if let Some(clint) = clint() {
+                    unsafe { clint.clear_ipi(hart) };
+                }
+                handle_pending(hart);
+            }
+            _ => fatal(frame),
+        }
+    }
+}
+
+/// Reports a trap we can't do anything about and stops the hart.
+fn fatal(frame: &TrapFrame) -> ! {
+    if let Some(mut uart) = console() {
+        uart.write_str("\n\nunexpected M-mode trap\nmcause: ");
+        write_hex(&mut uart, mcause::read().bits());
+        uart.write_str("\nmepc: ");
+        write_hex(&mut uart, frame.pc);
+        uart.write_str("\nmtval: ");
+        write_hex(&mut uart, riscv::register::mtval::read());
+        uart.write_str("\n");
+    }
+    super::halt()
+}
+
+fn write_hex(uart: &mut Uart16550, value: usize) {
+    for shift in (0..usize::BITS).step_by(4).rev() {
+        let nibble = (value >> shift) as u8 & 0xf;
+        uart.putc(if nibble < 10 {
+            b'0' + nibble
+        } else {
+            b'a' + nibble - 10
+        });
+    }
+}
+
+fn ok(value: usize) -> SbiRet {
+    SbiRet {
+        error: SBI_SUCCESS,
+        value: value as isize,
+    }
+}
+
+fn err(error: isize) -> SbiRet {
+    SbiRet { error, value: 0 }
+}
+
+fn call(hart: usize, frame: &mut TrapFrame) -> SbiRet {
+    let regs = &frame.regs;
+    let [a0, a1, a2] = [regs[Frame::A0], regs[Frame::A0 + 1], regs[Frame::A0 + 2]];
+    let fid = regs[Frame::A7 - 1];
+    let ext = regs[Frame::A7];
+
+    match ext {
+        SBI_EXT_BASE => base(fid, a0),
+        SBI_EXT_TIME if fid == SBI_FID_SET_TIMER => set_timer(hart, a0 as u64),
+        SBI_EXT_LEGACY_SET_TIMER => set_timer(hart, a0 as u64),
+        SBI_EXT_IPI if fid == SBI_FID_SEND_IPI => send_ipi(a0, a1),
+        SBI_EXT_RFENCE => rfence(hart, fid, a0, a1),
+        SBI_EXT_HSM => hsm(hart, fid, a0, a1, a2),
+        SBI_EXT_SRST if fid == SBI_FID_SYSTEM_RESET => system_reset(a0, a1),
+        SBI_EXT_DBCN => debug_console(fid, a0, a1, a2),
+        _ => err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn supported(ext: usize) -> bool {
+    matches!(
+        ext,
+        SBI_EXT_BASE
+            | SBI_EXT_TIME
+            | SBI_EXT_IPI
+            | SBI_EXT_RFENCE
+            | SBI_EXT_HSM
+            | SBI_EXT_SRST
+            | SBI_EXT_DBCN
+    )
+}
+
+fn base(fid: usize, a0: usize) -> SbiRet {
+    match fid {
+        SBI_FID_GET_SPEC_VERSION => ok(SPEC_VERSION),
+        SBI_FID_GET_IMPL_ID => ok(IMPL_ID),
+        SBI_FID_GET_IMPL_VERSION => ok(IMPL_VERSION),
+        SBI_FID_PROBE_EXTENSION => ok(supported(a0) as usize),
+        SBI_FID_GET_MVENDORID => ok(unsafe {
+            let value;
+            asm!("csrr {}, mvendorid", out(reg) value);
+            value
+        }),
+        SBI_FID_GET_MARCHID => ok(unsafe {
+            let value;
+            asm!("csrr {}, marchid", out(reg) value);
+            value
+        }),
+        SBI_FID_GET_MIMPID => ok(unsafe {
+            let value;
+            asm!("csrr {}, mimpid", out(reg) value);
+            value
+        }),
+        _ => err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn set_timer(hart: usize, deadline: u64) -> SbiRet {
+    let Some(clint) = clint() else {
+        return err(SBI_ERR_FAILED);
+    };
+    unsafe {
+        clint.set_timer_deadline(hart, deadline);
+        asm!("csrc mip, {}", in(reg) MIP_STIP);
+        mie::set_mtimer();
+    }
+    ok(0)
+}
+
+/// The harts selected by an SBI hart mask, `None` if any of them doesn't exist.
+fn harts_in(mask: usize, mask_base: usize) -> Option<usize> {
+    let present = PRESENT.load(Ordering::Acquire);
+    if mask_base == usize::MAX {
+        return Some(present);
+    }
+    if mask_base >= MAX_HARTS {
+        return if mask == 0 { Some(0) } else { None };
+    }
+    let harts = mask << mask_base;
+    // bits shifted out or harts that never showed up
+    if harts >> mask_base != mask || harts & !present != 0 {
+        return None;
+    }
+    Some(harts)
+}
+
+fn for_each_hart(harts: usize, mut f: impl FnMut(usize, &'static Hart)) {
+    for id in 0..MAX_HARTS {
+        if harts & (1 << id) != 0
+            && let Some(hart) = HARTS.get(id)
+        {
+            f(id, hart);
+        }
+    }
+}
+
+/// Leaves `work` for every hart in `harts` and interrupts them.
+fn post(harts: usize, work: usize) {
+    let clint = clint();
+    for_each_hart(harts, |id, hart| {
+        hart.pending.fetch_or(work, Ordering::AcqRel);
+        if let Some(clint) = clint {
+            unsafe { clint.send_ipi(id) };
+        }
+    });
+}
+
+/// Does what other harts asked of this one.
+fn handle_pending(hart: usize) {
+    let Some(state) = HARTS.get(hart) else {
+        return;
+    };
+    let pending = state.pending.load(Ordering::Acquire) & !PENDING_START;
+    if pending & PENDING_FENCE_I != 0 {
+        unsafe { asm!("fence.i") };
+    }
+    if pending & PENDING_SFENCE_VMA != 0 {
+        unsafe { asm!("sfence.vma") };
+    }
+    if pending & PENDING_IPI != 0 {
+        unsafe { asm!("csrs mip, {}", in(reg) MIP_SSIP) };
+    }
+    // cleared only now, a hart waiting on a fence must not see it before it happened
+    state.pending.fetch_and(!pending, Ordering::AcqRel);
+}
+
+fn send_ipi(mask: usize, mask_base: usize) -> SbiRet {
+    let Some(harts) = harts_in(mask, mask_base) else {
+        return err(SBI_ERR_INVALID_PARAM);
+    };
+    post(harts, PENDING_IPI);
+    ok(0)
+}
+
+/// Remote fences flush everything, the address range and ASID are only a hint.
+fn rfence(hart: usize, fid: usize, mask: usize, mask_base: usize) -> SbiRet {
+    let work = match fid {
+        SBI_FID_REMOTE_FENCE_I => PENDING_FENCE_I,
+        SBI_FID_REMOTE_SFENCE_VMA | SBI_FID_REMOTE_SFENCE_VMA_ASID => PENDING_SFENCE_VMA,
+        _ => return err(SBI_ERR_NOT_SUPPORTED),
+    };
+    let Some(harts) = harts_in(mask, mask_base) else {
+        return err(SBI_ERR_INVALID_PARAM);
+    };
+
+    post(harts, work);
+    for_each_hart(harts, |_, target| {
+        while target.pending.load(Ordering::Acquire) & work != 0 {
+            // whoever we wait on may be waiting on us in turn
+            handle_pending(hart);
+            core::hint::spin_loop();
+        }
+    });
+    ok(0)
+}
+
+fn hsm(hart: usize, fid: usize, a0: usize, a1: usize, a2: usize) -> SbiRet {
+    match fid {
+        SBI_FID_HART_START => {
+            let Some(target) = self::hart(a0) else {
+                return err(SBI_ERR_INVALID_PARAM);
+            };
+            if target
+                .state
+                .compare_exchange(
+                    SBI_HSM_STATE_STOPPED,
+                    SBI_HSM_STATE_START_PENDING,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_err()
+            {
+                return err(SBI_ERR_ALREADY_AVAILABLE);
+            }
+            target.start_addr.store(a1, Ordering::Relaxed);
+            target.opaque.store(a2, Ordering::Relaxed);
+            post(1 << a0, PENDING_START);
+            ok(0)
+        }
+        SBI_FID_HART_STOP => {
+            if let Some(state) = HARTS.get(hart) {
+                state.state.store(SBI_HSM_STATE_STOPPED, Ordering::Release);
+            }
+            park(hart)
+        }
+        SBI_FID_HART_GET_STATUS => match self::hart(a0) {
+            Some(target) => ok(target.state.load(Ordering::Acquire)),
+            None => err(SBI_ERR_INVALID_PARAM),
+        },
+        SBI_FID_HART_SUSPEND => suspend(hart, a0, a1, a2),
+        _ => err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Waits for an interrupt, then either returns to the caller or, for a
+/// non retentive suspend, restarts S-mode at `resume_addr`.
+fn suspend(hart: usize, kind: usize, resume_addr: usize, opaque: usize) -> SbiRet {
+    if kind != SBI_HSM_SUSPEND_RETENTIVE && kind != SBI_HSM_SUSPEND_NON_RETENTIVE {
+        return err(SBI_ERR_INVALID_PARAM);
+    }
+    let Some(state) = HARTS.get(hart) else {
+        return err(SBI_ERR_FAILED);
+    };
+
+    state.state.store(SBI_HSM_STATE_SUSPENDED, Ordering::Release);
+    // pending interrupts wake us even though M-mode runs with them masked
+    riscv::asm::wfi();
+    state.state.store(SBI_HSM_STATE_STARTED, Ordering::Release);
+
+    if kind == SBI_HSM_SUSPEND_NON_RETENTIVE {
+        enter_supervisor(hart, resume_addr, opaque)
+    }
+    ok(0)
+}
+
+/// Holds a stopped hart until someone starts it through HSM.
+fn park(hart: usize) -> ! {
+    let Some(state) = HARTS.get(hart) else {
+        super::halt()
+    };
+    unsafe {
+        mie::clear_mtimer();
+        asm!("csrc mip, {}", in(reg) MIP_STIP);
+    }
+
+    loop {
+        if let Some(clint) = clint() {
+            unsafe { clint.clear_ipi(hart) };
+        }
+        // fences aimed at a stopped hart are trivially done
+        handle_pending(hart);
+        if state.pending.fetch_and(!PENDING_START, Ordering::AcqRel) & PENDING_START != 0 {
+            break;
+        }
+        riscv::asm::wfi();
+    }
+
+    state.state.store(SBI_HSM_STATE_STARTED, Ordering::Release);
+    enter_supervisor(
+        hart,
+        state.start_addr.load(Ordering::Relaxed),
+        state.opaque.load(Ordering::Relaxed),
+    )
+}
+
+/// Drops to S-mode at `addr` with paging off, `a0` set to the hart id and `a1` to `opaque`.
+fn enter_supervisor(hart: usize, addr: usize, opaque: usize) -> ! {
+    unsafe {
+        let mut status = mstatus::read();
+        status.set_mpp(mstatus::MPP::Supervisor);
+        status.set_mpie(false);
+        status.set_sie(false);
+        mstatus::write(status);
+
+        riscv::register::mscratch::write(stack_top(hart));
+        asm!(
+            "
+            csrw satp, zero
+            sfence.vma
+            csrw mepc, {addr}
+            mret
+            ",
+            addr = in(reg) addr,
+            in("a0") hart,
+            in("a1") opaque,
+            options(noreturn)
+        )
+    }
+}
+
+fn system_reset(kind: usize, reason: usize) -> SbiRet {
+    if reason > SBI_RESET_REASON_SYSTEM_FAILURE && reason < 0xf000_0000 {
+        return err(SBI_ERR_INVALID_PARAM);
+    }
+    let syscon = unsafe { (&raw const SYSCON).read() };
+    let Some(syscon) = syscon else {
+        return err(SBI_ERR_NOT_SUPPORTED);
+    };
+    match kind {
+        SBI_RESET_TYPE_SHUTDOWN => syscon.request_poweroff(),
+        SBI_RESET_TYPE_COLD_REBOOT | SBI_RESET_TYPE_WARM_REBOOT => syscon.request_reboot(),
+        _ => return err(SBI_ERR_INVALID_PARAM),
+    }
+    err(SBI_ERR_FAILED)
+}
+
+/// The part of the `len` bytes at `base` S-mode may pass us, at most [`CONSOLE_MAX_LEN`]
+/// of them and all in RAM.
+fn console_buffer(base: usize, len: usize) -> Option<(usize, usize)> {
+    let len = len.min(CONSOLE_MAX_LEN);
+    let end = base.checked_add(len)?;
+    let ram = RAM_START.load(Ordering::Relaxed)..RAM_END.load(Ordering::Relaxed);
+    (base != 0 && base >= ram.start && end <= ram.end).then_some((base, len))
+}
+
+/// The debug console takes physical addresses, which is all we have anyway.
+fn debug_console(fid: usize, len: usize, base_lo: usize, base_hi: usize) -> SbiRet {
+    let Some(mut uart) = console() else {
+        return err(SBI_ERR_NOT_SUPPORTED);
+    };
+    let buffer = || match base_hi {
+        0 => console_buffer(base_lo, len),
+        _ => None,
+    };
+    match fid {
+        SBI_FID_CONSOLE_WRITE => {
+            let Some((base, len)) = buffer() else {
+                return err(SBI_ERR_INVALID_PARAM);
+            };
+            let bytes = unsafe { core::slice::from_raw_parts(base as *const u8, len) };
+            uart.write_bytes(bytes);
+            ok(len)
+        }
+        SBI_FID_CONSOLE_READ => {
+            let Some((base, len)) = buffer() else {
+                return err(SBI_ERR_INVALID_PARAM);
+            };
+            let bytes = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, len) };
+            let mut read = 0;
+            for byte in bytes {
+                let Some(c) = uart.try_getc() else {
+                    break;
+                };
+                *byte = c;
+                read += 1;
+            }
+            ok(read)
+        }
+        SBI_FID_CONSOLE_WRITE_BYTE => {
+            uart.putc(len as u8);
+            ok(0)
+        }
+        _ => err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+unsafe extern "C" {
+    /// Where the harts that lost the race for [`BOOT_TICKET`] jump from `_start`,
+    /// with the hart id in `a0` and nothing set up.
+    pub(super) fn mtrap_secondary_start();
+}
+
+global_asm!(
+    "
+        .option push
+        .option norelax
+.section .text
+.global mtrap_secondary_start
+.balign 4
+mtrap_secondary_start:
+    li t0, {max_harts}
+    bgeu a0, t0, 2f
+
+    lla t0, {ready}
+    1:
+        lbu t1, 0(t0)
+        beqz t1, 1b
+    fence r, rw
+
+    // sp = top of STACKS[a0]
+    lla sp, {stacks}
+    addi t0, a0, 1
+    li t1, {stack_size}
+    mul t0, t0, t1
+    add sp, sp, t0
+    tail {secondary}
+
+    2:
+        wfi
+        j 2b
+
+.size mtrap_secondary_start, . - mtrap_secondary_start
+.type mtrap_secondary_start, @function
+
+    .option pop
+",
+    max_harts = const MAX_HARTS,
+    ready = sym READY,
+    stacks = sym STACKS,
+    stack_size = const STACK_SIZE,
+    secondary = sym secondary,
+);
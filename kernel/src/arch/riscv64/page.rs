@@ -1,3 +1,7 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use riscv::register::satp;
+
 use crate::mem::Pointer;
 
 #[repr(C, align(4096))]
@@ -144,60 +148,28 @@ impl<T: PhysToVirt> VirtToPhys for VirtToPhysPageTranslation<T> {
 
     fn virt_to_phys<P>(&self, virt: VirtPtr<P>) -> Result<PhyPtr<P>, Self::Error> {
         let virt = virt.0 as *mut () as usize;
-        let ppn2 = (virt >> (9 + 9 + 12)) & ((1 << 9) - 1);
-        let ppn1 = (virt >> (9 + 12)) & ((1 << 9) - 1);
-        let ppn0 = (virt >> (12)) & ((1 << 9) - 1);
-
-        let root = unsafe {
-            &*self
-                .root
-                .to_virt(&self.trans)
-                .map_err(PageTableAddressTranslationError::PhysicalToVirtualTranslationError)?
-                .0
-        };
-        let lvl1 = root.entries[ppn2];
-        if !lvl1.valid() {
-            return Err(PageTableAddressTranslationError::NotMapped);
-        }
-        if lvl1.is_leaf() {
-            let addr = extract_bits(lvl1.ppn() << 12, 12 + 9 + 9, 9)
-                | extract_bits(virt as u64, 0, 12 + 9 + 9);
-            return Ok(PhyPtr(addr as *mut P));
-        }
 
-        let curr = unsafe {
-            &*PhyPtr((lvl1.ppn() << 12) as *mut PageTable)
-                .to_virt(&self.trans)
-                .map_err(PageTableAddressTranslationError::PhysicalToVirtualTranslationError)?
-                .0
-        };
-        let lvl2 = curr.entries[ppn1];
-        if !lvl2.valid() {
-            return Err(PageTableAddressTranslationError::NotMapped);
-        }
-        if lvl2.is_leaf() {
-            let addr = extract_bits(lvl1.ppn() << 12, 12 + 9 + 9, 9 + 9)
-                | extract_bits(virt as u64, 0, 12 + 9);
-            return Ok(PhyPtr(addr as *mut P));
-        }
-
-        let curr = unsafe {
-            &*PhyPtr((lvl2.ppn() << 12) as *mut PageTable)
-                .to_virt(&self.trans)
-                .map_err(PageTableAddressTranslationError::PhysicalToVirtualTranslationError)?
-                .0
-        };
-        let lvl3 = curr.entries[ppn0];
-        if !lvl3.valid() {
-            return Err(PageTableAddressTranslationError::NotMapped);
-        }
-        if lvl3.is_leaf() {
-            let addr =
-                extract_bits(lvl1.ppn() << 12, 12, 9 + 9 + 9) | extract_bits(virt as u64, 0, 12);
-            Ok(PhyPtr(addr as *mut P))
-        } else {
-            Err(PageTableAddressTranslationError::PageTableMalformed)
+        let mut table = self.root;
+        for level in (0..levels()).rev() {
+            let curr = unsafe {
+                &*table
+                    .to_virt(&self.trans)
+                    .map_err(PageTableAddressTranslationError::PhysicalToVirtualTranslationError)?
+                    .0
+            };
+            let entry = curr.entries[index(virt, level)];
+            if !entry.valid() {
+                return Err(PageTableAddressTranslationError::NotMapped);
+            }
+            if entry.is_leaf() {
+                let offset = 12 + 9 * level as u64;
+                let addr = extract_bits(entry.ppn() << 12, offset, 56 - offset)
+                    | extract_bits(virt as u64, 0, offset);
+                return Ok(PhyPtr(addr as *mut P));
+            }
+            table = PhyPtr((entry.ppn() << 12) as *mut PageTable);
         }
+        Err(PageTableAddressTranslationError::PageTableMalformed)
     }
 }
 
@@ -227,17 +199,17 @@ impl PageTable {
             vpt: usize,
             entry: PageTableEntry,
             level: usize,
+            depth: usize,
         ) -> core::fmt::Result {
-            for _ in 0..=level {
+            for _ in 0..=depth {
                 out.write_char('|')?;
             }
-            let range = [1 << (9 + 9 + 12), 1 << (9 + 12), 1 << (12)];
             write!(
                 out,
                 "0x{:016x} -> 0x{:08x}..0x{:08x} ",
                 vpt,
                 entry.ppn() << 12,
-                (entry.ppn() << 12) + range[level]
+                (entry.ppn() << 12) + level_size(level) as u64
             )?;
             out.write_str(if entry.strong_order() { "S0" } else { "--" })?;
             out.write_char(if entry.bufferable() { 'b' } else { '_' })?;
@@ -258,45 +230,37 @@ impl PageTable {
             writeln!(out)?;
             Ok(())
         }
-        for (i, entry) in unsafe { *root.virt() }.entries.iter().enumerate() {
-            if !entry.valid() {
-                continue;
-            }
-            let vpt = i << (9 + 9 + 12);
-            let vpt = (((vpt as isize) << (64 - 39)) >> (64 - 39)) as usize;
-            if entry.is_leaf() {
-                disp_entry(&mut out, vpt, *entry, 0)?;
-                continue;
-            }
-            writeln!(out, "|0x{:08x}", entry.ppn() << 12)?;
-            let level =
-                unsafe { &*Pointer::from_phys((entry.ppn() << 12) as *mut PageTable).virt() };
-            for (i, entry) in level.entries.iter().enumerate() {
+        fn disp_level<T: core::fmt::Write>(
+            out: &mut T,
+            table: Pointer<PageTable>,
+            level: usize,
+            base: usize,
+        ) -> core::fmt::Result {
+            let depth = levels() - 1 - level;
+            for (i, entry) in unsafe { &*table.virt() }.entries.iter().enumerate() {
                 if !entry.valid() {
                     continue;
                 }
-                let vpt = vpt + (i << (9 + 12));
+                let vpt = base + (i << (12 + 9 * level));
+                let vpt = if depth == 0 { canonical(vpt) } else { vpt };
                 if entry.is_leaf() {
-                    disp_entry(&mut out, vpt, *entry, 1)?;
+                    disp_entry(out, vpt, *entry, level, depth)?;
                     continue;
                 }
-                writeln!(out, "||0x{:08x}", entry.ppn() << 12)?;
-                let level =
-                    unsafe { &*Pointer::from_phys((entry.ppn() << 12) as *mut PageTable).virt() };
-                for (i, entry) in level.entries.iter().enumerate() {
-                    if !entry.valid() {
-                        continue;
-                    }
-                    let vpt = vpt + (i << 12);
-                    if entry.is_leaf() {
-                        disp_entry(&mut out, vpt, *entry, 2)?;
-                        continue;
-                    } else {
-                        writeln!(out, "||invalid :3")?;
-                    }
+                // printed before the heap is up, so no allocating here
+                for _ in 0..=depth {
+                    out.write_char('|')?;
                 }
+                if level == 0 {
+                    writeln!(out, "invalid :3")?;
+                    continue;
+                }
+                writeln!(out, "0x{:08x}", entry.ppn() << 12)?;
+                disp_level(out, Pointer::from_phys((entry.ppn() << 12) as *mut PageTable), level - 1, vpt)?;
             }
+            Ok(())
         }
+        disp_level(&mut out, root, levels() - 1, 0)?;
         Ok(())
     }
 }
@@ -519,6 +483,109 @@ pub struct PageTableRoot {
     root: crate::mem::Pointer<PageTable>,
 }
 
+unsafe impl Send for PageTableRoot {}
+
+static LEVELS: AtomicUsize = AtomicUsize::new(3);
+
+/// Number of page table levels in use, 3 for Sv39, 4 for Sv48 and 5 for Sv57.
+pub fn levels() -> usize {
+    LEVELS.load(Ordering::Relaxed)
+}
+
+/// Picks the paging mode every page table is walked with. Must happen before
+/// the kernel map is built.
+pub fn set_levels(levels: usize) {
+    assert!((3..=5).contains(&levels), "unsupported page table depth {levels}");
+    LEVELS.store(levels, Ordering::Relaxed);
+}
+
+pub fn mode_for(levels: usize) -> satp::Mode {
+    match levels {
+        3 => satp::Mode::Sv39,
+        4 => satp::Mode::Sv48,
+        5 => satp::Mode::Sv57,
+        _ => panic!("unsupported page table depth {levels}"),
+    }
+}
+
+/// The `satp` mode matching [`levels`].
+pub fn satp_mode() -> satp::Mode {
+    mode_for(levels())
+}
+
+/// Number of virtual address bits translated in the current mode.
+pub fn va_bits() -> usize {
+    12 + 9 * levels()
+}
+
+/// Bytes mapped by a single leaf at `level`, 0 being a regular page.
+pub const fn level_size(level: usize) -> usize {
+    1 << (12 + 9 * level)
+}
+
+/// Strips the sign extension off a virtual address, leaving the bits the page tables index.
+fn va_mask() -> usize {
+    (1 << va_bits()) - 1
+}
+
+/// Sign extends a virtual address from the top translated bit.
+fn canonical(virt: usize) -> usize {
+    let shift = 64 - va_bits();
+    (((virt << shift) as isize) >> shift) as usize
+}
+
+const fn index(virt: usize, level: usize) -> usize {
+    (virt >> (12 + 9 * level)) & ((1 << 9) - 1)
+}
+
+/// Finds the deepest paging mode the hart supports and makes it the one [`levels`] reports.
+///
+/// `satp` ignores writes with a mode it does not implement, so each deeper mode is
+/// tried with a root whose first and last entries point back at the root in use.
+/// That way the address space looks the same whether or not the write sticks.
+///
+/// # Safety
+///
+/// Translation must be on with an Sv39 root, the original `satp` is restored afterwards.
+pub unsafe fn probe_levels(
+    supplier: impl Fn() -> crate::mem::Pointer<PageTable>,
+    free: impl Fn(crate::mem::Pointer<PageTable>),
+) -> usize {
+    let original = satp::read();
+    let mut inner = original.ppn() as u64;
+    let mut wrappers = [None; 2];
+    let mut levels = 3;
+
+    for (wrapper, candidate) in wrappers.iter_mut().zip([4, 5]) {
+        let root = supplier();
+        let table = unsafe { &mut *root.virt() };
+        table.entries[0] = PageTableEntry::NON_LEAF.set_ppn(inner);
+        table.entries[511] = PageTableEntry::NON_LEAF.set_ppn(inner);
+        *wrapper = Some(root);
+
+        unsafe {
+            satp::set(mode_for(candidate), 0, root.phys() as usize >> 12);
+            core::arch::asm!("sfence.vma");
+        }
+        if satp::read().mode() != mode_for(candidate) {
+            break;
+        }
+        levels = candidate;
+        inner = root.phys() as u64 >> 12;
+    }
+
+    unsafe {
+        satp::set(original.mode(), original.asid(), original.ppn());
+        core::arch::asm!("sfence.vma");
+    }
+    for root in wrappers.into_iter().flatten() {
+        free(root);
+    }
+
+    set_levels(levels);
+    levels
+}
+
 impl PageTableRoot {
     /// # Safety
     ///
@@ -545,28 +612,18 @@ impl PageTableRoot {
     ) -> Result<(), ()> {
         let mut size = size.next_multiple_of(1 << 12);
         while size > 0 {
-            if virt.is_multiple_of(1 << (12 + 18))
-                && phys.is_multiple_of(1 << (12 + 18))
-                && size >= 1 << (12 + 18)
-            {
-                self.map_phys_huge_huge_page(virt, phys, entry, &supplier)?;
-                size -= 1 << (12 + 18);
-                virt += 1 << (12 + 18);
-                phys += 1 << (12 + 18);
-            } else if virt.is_multiple_of(1 << (12 + 9))
-                && phys.is_multiple_of(1 << (12 + 9))
-                && size >= 1 << (12 + 9)
-            {
-                self.map_phys_huge_page(virt, phys, entry, &supplier)?;
-                size -= 1 << (12 + 9);
-                virt += 1 << (12 + 9);
-                phys += 1 << (12 + 9);
-            } else {
-                self.map_phys_page(virt, phys, entry, &supplier)?;
-                size -= 1 << 12;
-                virt += 1 << 12;
-                phys += 1 << 12;
-            }
+            let level = (1..=2)
+                .rev()
+                .find(|&level| {
+                    virt.is_multiple_of(level_size(level))
+                        && phys.is_multiple_of(level_size(level))
+                        && size >= level_size(level)
+                })
+                .unwrap_or(0);
+            self.map_phys_leaf(virt, phys, entry, level, &supplier)?;
+            size -= level_size(level);
+            virt += level_size(level);
+            phys += level_size(level);
         }
         Ok(())
     }
@@ -578,15 +635,7 @@ impl PageTableRoot {
         entry: PageTableEntry,
         supplier: impl Fn() -> crate::mem::Pointer<PageTable>,
     ) -> Result<(), ()> {
-        let ppn2 = (virt >> (9 + 9 + 12)) & ((1 << 9) - 1);
-
-        let curr = unsafe { &mut *self.root.virt() };
-        if curr.entries[ppn2].valid() {
-            return Err(());
-        }
-
-        curr.entries[ppn2] = entry.set_ppn(phys as u64 >> 12);
-        Ok(())
+        self.map_phys_leaf(virt, phys, entry, 2, supplier)
     }
 
     pub fn map_phys_huge_page(
@@ -596,29 +645,7 @@ impl PageTableRoot {
         entry: PageTableEntry,
         supplier: impl Fn() -> crate::mem::Pointer<PageTable>,
     ) -> Result<(), ()> {
-        let ppn2 = (virt >> (9 + 9 + 12)) & ((1 << 9) - 1);
-        let ppn1 = (virt >> (9 + 12)) & ((1 << 9) - 1);
-
-        let mut curr = unsafe { &mut *self.root.virt() };
-
-        if curr.entries[ppn2].valid() && curr.entries[ppn2].is_leaf() {
-            return Err(());
-        }
-
-        if !curr.entries[ppn2].valid() {
-            curr.entries[ppn2] = PageTableEntry::new()
-                .set_valid(true)
-                .set_ppn(supplier().phys() as u64 >> 12);
-        }
-        let page = Pointer::from_phys((curr.entries[ppn2].ppn() << 12) as *mut PageTable);
-        curr = unsafe { &mut *(page.virt()) };
-
-        if curr.entries[ppn1].valid() {
-            return Err(());
-        }
-
-        curr.entries[ppn1] = entry.set_ppn(phys as u64 >> 12);
-        Ok(())
+        self.map_phys_leaf(virt, phys, entry, 1, supplier)
     }
 
     pub fn map_phys_page(
@@ -628,34 +655,185 @@ impl PageTableRoot {
         entry: PageTableEntry,
         supplier: impl Fn() -> crate::mem::Pointer<PageTable>,
     ) -> Result<(), ()> {
-        let ppn2 = (virt >> (9 + 9 + 12)) & ((1 << 9) - 1);
-        let ppn1 = (virt >> (9 + 12)) & ((1 << 9) - 1);
-        let ppn0 = (virt >> (12)) & ((1 << 9) - 1);
+        self.map_phys_leaf(virt, phys, entry, 0, supplier)
+    }
+
+    /// Maps `virt` to `phys` with a leaf at `level`, creating any missing tables above it.
+    pub fn map_phys_leaf(
+        &mut self,
+        virt: usize,
+        phys: usize,
+        entry: PageTableEntry,
+        level: usize,
+        supplier: impl Fn() -> crate::mem::Pointer<PageTable>,
+    ) -> Result<(), ()> {
+        let slot = self.walk_create(virt, level, supplier)?;
+        if slot.valid() {
+            return Err(());
+        }
+        *slot = entry.set_ppn(phys as u64 >> 12);
+        Ok(())
+    }
 
+    /// The entry for `virt` in the table at `level`, creating the tables leading there.
+    fn walk_create(
+        &mut self,
+        virt: usize,
+        level: usize,
+        supplier: impl Fn() -> crate::mem::Pointer<PageTable>,
+    ) -> Result<&mut PageTableEntry, ()> {
         let mut curr = unsafe { &mut *self.root.virt() };
 
-        for ppn in [ppn2, ppn1] {
-            if curr.entries[ppn].valid() && curr.entries[ppn].is_leaf() {
+        for upper in (level + 1..levels()).rev() {
+            let entry = &mut curr.entries[index(virt, upper)];
+            if entry.valid() && entry.is_leaf() {
                 return Err(());
             }
-            if !curr.entries[ppn].valid() {
-                curr.entries[ppn] = PageTableEntry::new()
-                    .set_valid(true)
-                    .set_ppn(supplier().phys() as u64 >> 12);
+            if !entry.valid() {
+                *entry = PageTableEntry::NON_LEAF.set_ppn(supplier().phys() as u64 >> 12);
             }
 
-            let page = Pointer::from_phys((curr.entries[ppn].ppn() << 12) as *mut PageTable);
+            let page = Pointer::from_phys((entry.ppn() << 12) as *mut PageTable);
             curr = unsafe { &mut *(page.virt()) };
         }
 
-        if curr.entries[ppn0].valid() {
+        Ok(&mut curr.entries[index(virt, level)])
+    }
+
+    /// The physical address `virt` maps to along with the leaf entry mapping it.
+    pub fn translate(&self, virt: usize) -> Option<(usize, PageTableEntry)> {
+        let mut curr = unsafe { &*self.root.virt() };
+        for level in (0..levels()).rev() {
+            let entry = curr.entries[index(virt, level)];
+            if !entry.valid() {
+                return None;
+            }
+            if entry.is_leaf() {
+                let offset = virt & (level_size(level) - 1);
+                return Some((((entry.ppn() << 12) as usize) + offset, entry));
+            }
+            curr = unsafe { &*Pointer::from_phys((entry.ppn() << 12) as *mut PageTable).virt() };
+        }
+        None
+    }
+
+    /// Removes every mapping in `virt..virt + size`, handing each removed leaf and
+    /// the number of bytes it covered to `release`. Page tables left empty are
+    /// returned to [`crate::mem::pages`].
+    ///
+    /// Huge pages only partially covered by the range are refused, mappings
+    /// before the offending one have already been removed by then. The caller is
+    /// responsible for flushing the TLB, and must not pass a range in the kernel
+    /// half of a space which shares those tables with others.
+    pub fn unmap_region(
+        &mut self,
+        virt: usize,
+        size: usize,
+        mut release: impl FnMut(PageTableEntry, usize),
+    ) -> Result<(), ()> {
+        if !virt.is_multiple_of(1 << 12) {
             return Err(());
         }
+        let start = virt & va_mask();
+        let range = start..start + size.next_multiple_of(1 << 12);
+        unsafe { Self::unmap_table(self.root, levels() - 1, 0, &range, &mut release) }.map(|_| ())
+    }
+
+    /// Returns whether `table` is empty afterwards.
+    unsafe fn unmap_table(
+        table: crate::mem::Pointer<PageTable>,
+        level: usize,
+        base: usize,
+        range: &core::ops::Range<usize>,
+        release: &mut dyn FnMut(PageTableEntry, usize),
+    ) -> Result<bool, ()> {
+        let span = level_size(level);
+        let entries = unsafe { &mut (*table.virt()).entries };
+        for (i, slot) in entries.iter_mut().enumerate() {
+            let start = base + i * span;
+            if start + span <= range.start || start >= range.end || !slot.valid() {
+                continue;
+            }
+            let entry = *slot;
+            if entry.is_leaf() {
+                if start < range.start || start + span > range.end {
+                    return Err(());
+                }
+                *slot = PageTableEntry::new();
+                release(entry, span);
+            } else {
+                let next = Pointer::from_phys((entry.ppn() << 12) as *mut PageTable);
+                if unsafe { Self::unmap_table(next, level - 1, start, range, release)? } {
+                    *slot = PageTableEntry::new();
+                    unsafe { crate::mem::pages::free_page(next.cast()) };
+                }
+            }
+        }
+        Ok(entries.iter().all(|entry| !entry.valid()))
+    }
+
+    /// Replaces the R/W/X/U bits of every mapping in `virt..virt + size` with those
+    /// of `perms`, unmapped pages are skipped. The caller is responsible for flushing the TLB.
+    pub fn change_perms(&mut self, virt: usize, size: usize, perms: PageTableEntry) -> Result<(), ()> {
+        // an entry without R/W/X would turn into a pointer to a page table
+        if !virt.is_multiple_of(1 << 12) || !perms.is_leaf() {
+            return Err(());
+        }
+        const MASK: u64 = 0b11110;
+        let start = virt & va_mask();
+        let range = start..start + size.next_multiple_of(1 << 12);
+        unsafe {
+            Self::for_each_leaf(self.root, levels() - 1, 0, &range, &mut |entry: &mut PageTableEntry| {
+                *entry = PageTableEntry((entry.0 & !MASK) | (perms.0 & MASK));
+            })
+        }
+    }
 
-        curr.entries[ppn0] = entry.set_ppn(phys as u64 >> 12);
+    unsafe fn for_each_leaf(
+        table: crate::mem::Pointer<PageTable>,
+        level: usize,
+        base: usize,
+        range: &core::ops::Range<usize>,
+        f: &mut dyn FnMut(&mut PageTableEntry),
+    ) -> Result<(), ()> {
+        let span = level_size(level);
+        for (i, slot) in unsafe { (*table.virt()).entries.iter_mut() }.enumerate() {
+            let start = base + i * span;
+            if start + span <= range.start || start >= range.end || !slot.valid() {
+                continue;
+            }
+            if slot.is_leaf() {
+                if start < range.start || start + span > range.end {
+                    return Err(());
+                }
+                f(slot);
+            } else {
+                let next = Pointer::from_phys((slot.ppn() << 12) as *mut PageTable);
+                unsafe { Self::for_each_leaf(next, level - 1, start, range, f)? };
+            }
+        }
         Ok(())
     }
 
+    /// The leaf entry mapping `virt` at whatever level it sits, `None` if unmapped.
+    pub fn leaf_mut(&mut self, virt: usize) -> Option<&mut PageTableEntry> {
+        let mut curr = unsafe { &mut *self.root.virt() };
+
+        for level in (1..levels()).rev() {
+            let entry = &mut curr.entries[index(virt, level)];
+            if !entry.valid() {
+                return None;
+            }
+            if entry.is_leaf() {
+                return Some(entry);
+            }
+            let page = Pointer::from_phys((entry.ppn() << 12) as *mut PageTable);
+            curr = unsafe { &mut *(page.virt()) };
+        }
+
+        Some(&mut curr.entries[index(virt, 0)]).filter(|entry| entry.valid())
+    }
+
     pub fn map_region(
         &mut self,
         mut virt: usize,
@@ -665,43 +843,24 @@ impl PageTableRoot {
     ) -> Result<(), ()> {
         let mut size = size.next_multiple_of(1 << 12);
         while size > 0 {
-            if virt.is_multiple_of(1 << (12 + 18))
-                && size >= 1 << (12 + 18)
-            {
-                self.map_huge_huge_page(virt, entry, &supplier)?;
-                size -= 1 << (12 + 18);
-                virt += 1 << (12 + 18);
-            } else if virt.is_multiple_of(1 << (12 + 9))
-                && size >= 1 << (12 + 9)
-            {
-                self.map_huge_page(virt, entry, &supplier)?;
-                size -= 1 << (12 + 9);
-                virt += 1 << (12 + 9);
-            } else {
-                self.map_page(virt, entry, &supplier)?;
-                size -= 1 << 12;
-                virt += 1 << 12;
-            }
+            let level = (1..=2)
+                .rev()
+                .find(|&level| virt.is_multiple_of(level_size(level)) && size >= level_size(level))
+                .unwrap_or(0);
+            self.map_leaf(virt, entry, level, &supplier)?;
+            size -= level_size(level);
+            virt += level_size(level);
         }
         Ok(())
     }
 
-
-        pub fn map_huge_huge_page(
+    pub fn map_huge_huge_page(
         &mut self,
         virt: usize,
         entry: PageTableEntry,
         supplier: impl Fn() -> crate::mem::Pointer<PageTable>,
     ) -> Result<(), ()> {
-        let ppn2 = (virt >> (9 + 9 + 12)) & ((1 << 9) - 1);
-
-        let curr = unsafe { &mut *self.root.virt() };
-        if curr.entries[ppn2].valid() {
-            return Err(());
-        }
-
-        // curr.entries[ppn2] = entry.set_ppn(phys as u64 >> 12);
-        Ok(())
+        self.map_leaf(virt, entry, 2, supplier)
     }
 
     pub fn map_huge_page(
@@ -710,29 +869,7 @@ impl PageTableRoot {
         entry: PageTableEntry,
         supplier: impl Fn() -> crate::mem::Pointer<PageTable>,
     ) -> Result<(), ()> {
-        let ppn2 = (virt >> (9 + 9 + 12)) & ((1 << 9) - 1);
-        let ppn1 = (virt >> (9 + 12)) & ((1 << 9) - 1);
-
-        let mut curr = unsafe { &mut *self.root.virt() };
-
-        if curr.entries[ppn2].valid() && curr.entries[ppn2].is_leaf() {
-            return Err(());
-        }
-
-        if !curr.entries[ppn2].valid() {
-            curr.entries[ppn2] = PageTableEntry::new()
-                .set_valid(true)
-                .set_ppn(supplier().phys() as u64 >> 12);
-        }
-        let page = Pointer::from_phys((curr.entries[ppn2].ppn() << 12) as *mut PageTable);
-        curr = unsafe { &mut *(page.virt()) };
-
-        if curr.entries[ppn1].valid() {
-            return Err(());
-        }
-
-        // curr.entries[ppn1] = entry.set_ppn(phys as u64 >> 12);
-        Ok(())
+        self.map_leaf(virt, entry, 1, supplier)
     }
 
     pub fn map_page(
@@ -741,31 +878,22 @@ impl PageTableRoot {
         entry: PageTableEntry,
         supplier: impl Fn() -> crate::mem::Pointer<PageTable>,
     ) -> Result<(), ()> {
-        let ppn2 = (virt >> (9 + 9 + 12)) & ((1 << 9) - 1);
-        let ppn1 = (virt >> (9 + 12)) & ((1 << 9) - 1);
-        let ppn0 = (virt >> (12)) & ((1 << 9) - 1);
-
-        let mut curr = unsafe { &mut *self.root.virt() };
-
-        for ppn in [ppn2, ppn1] {
-            if curr.entries[ppn].valid() && curr.entries[ppn].is_leaf() {
-                return Err(());
-            }
-            if !curr.entries[ppn].valid() {
-                curr.entries[ppn] = PageTableEntry::new()
-                    .set_valid(true)
-                    .set_ppn(supplier().phys() as u64 >> 12);
-            }
-
-            let page = Pointer::from_phys((curr.entries[ppn].ppn() << 12) as *mut PageTable);
-            curr = unsafe { &mut *(page.virt()) };
-        }
+        self.map_leaf(virt, entry, 0, supplier)
+    }
 
-        if curr.entries[ppn0].valid() {
+    fn map_leaf(
+        &mut self,
+        virt: usize,
+        _entry: PageTableEntry,
+        level: usize,
+        supplier: impl Fn() -> crate::mem::Pointer<PageTable>,
+    ) -> Result<(), ()> {
+        let slot = self.walk_create(virt, level, supplier)?;
+        if slot.valid() {
             return Err(());
         }
 
-        // curr.entries[ppn0] = entry.set_ppn(phys as u64 >> 12);
+        // *slot = entry.set_ppn(phys as u64 >> 12);
         Ok(())
     }
 }
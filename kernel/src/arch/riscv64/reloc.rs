@@ -1,17 +1,4 @@
-#[cfg(target_pointer_width = "64")]
-type ElfAddr = u64;
-#[cfg(target_pointer_width = "32")]
-type ElfAddr = u32;
-
-#[repr(C)]
-#[derive(Copy, Clone)]
-struct ElfRela {
-    r_offset: ElfAddr,
-    r_info: ElfAddr,
-    r_addend: ElfAddr,
-}
-
-const R_RISCV_RELATIVE: ElfAddr = 3;
+use crate::elf::{ElfAddr, ElfRela, R_RISCV_RELATIVE};
 
 /// # Safety
 /// 
@@ -0,0 +1,181 @@
+//! Secondary hart bring-up.
+//!
+//! Only the boot hart goes through `_start`. The others sit stopped in the SBI
+//! firmware until [`init`] starts them with the HSM extension at
+//! `secondary_start`, which enters the kernel's address space on a stack
+//! allocated for them and continues in [`secondary_entry`].
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    alloc::boxed::Box,
+    dtb::{ByteStream, Dtb, DtbNodes, DtbProperties},
+    println,
+    task::{KernelStack, Task},
+    timer::{Duration, Instant},
+};
+
+/// Harts the kernel can manage, bounded by the hart masks used for IPIs.
+pub const MAX_HARTS: usize = usize::BITS as usize;
+
+/// How long [`init`] waits for a started hart to check in.
+const START_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Harts running kernel code, the boot hart included.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+
+/// Handed to a starting hart through the `opaque` argument of `hart_start`.
+#[repr(C)]
+struct Boot {
+    /// Read by `secondary_start`, which relies on this layout.
+    satp: usize,
+    stack_top: usize,
+    stack: KernelStack,
+}
+
+pub fn online() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Starts every hart listed under `/cpus` other than the calling one and waits
+/// for them to come online.
+pub fn init(dtb: &Dtb) {
+    if !crate::sbi::has(crate::sbi::Extension::Hsm) {
+        println!("No SBI HSM extension, staying on one hart");
+        return;
+    }
+    let Some(cpus) = dtb.root().childern().nammed(b"cpus").next() else {
+        return;
+    };
+
+    let (start, satp) = {
+        let map = crate::mem::KERNEL_MAP.lock();
+        let map = map.as_ref().expect("kernel map not initialized");
+        let satp = ((super::page::satp_mode() as usize) << 60) | (map.root().phys() as usize >> 12);
+        let (start, _) = map
+            .translate(secondary_start as *const () as usize)
+            .expect("secondary_start is not mapped");
+        (start, satp)
+    };
+
+    let this = super::current_hart();
+    for cpu in cpus.childern() {
+        let is_cpu = cpu
+            .properties()
+            .find(b"device_type")
+            .is_some_and(|ty| ty.contains_str(b"cpu"));
+        let enabled = cpu
+            .properties()
+            .find(b"status")
+            .is_none_or(|status| status.contains_str(b"okay"));
+        let Some(hart) = cpu.properties().find_value(b"reg", ByteStream::u32) else {
+            continue;
+        };
+        let hart = hart as usize;
+        if !is_cpu || !enabled || hart == this || hart >= MAX_HARTS {
+            continue;
+        }
+
+        let stack = KernelStack::alloc();
+        let boot = Box::into_raw(Box::new(Boot {
+            satp,
+            stack_top: stack.top(),
+            stack,
+        }));
+
+        let expected = online() + 1;
+        if let Err(err) = crate::sbi::sbi_hart_start(hart, start, boot as usize) {
+            println!("Could not start hart {hart}: {err:?}");
+            drop(unsafe { Box::from_raw(boot) });
+            continue;
+        }
+
+        let deadline = Instant::now() + START_TIMEOUT;
+        while online() < expected {
+            if Instant::now() >= deadline {
+                // it may still turn up and owns `boot` now, so that can't be freed
+                println!("Hart {hart} did not come online");
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    println!("{} harts online", online());
+}
+
+/// First Rust code run by a secondary hart, already on its own stack and in
+/// the kernel's address space.
+unsafe extern "C" fn secondary_entry(hart_id: usize, boot: *mut Boot) -> ! {
+    let Boot { stack, .. } = *unsafe { Box::from_raw(boot) };
+
+    unsafe {
+        super::strap::init(hart_id);
+    }
+    crate::task::sched::init_hart(Task::idle(stack));
+
+    unsafe {
+        riscv::register::sie::set_ssoft();
+        riscv::register::sie::set_sext();
+    }
+    crate::timer::init_hart();
+
+    ONLINE.fetch_add(1, Ordering::Release);
+    println!("Hart {hart_id} online");
+
+    super::halt()
+}
+
+unsafe extern "C" {
+    fn secondary_start();
+}
+
+core::arch::global_asm!(
+    "
+        .option push
+        .option norelax
+.section .text
+.global secondary_start
+.balign 4
+secondary_start:
+    // a0: hart id, a1: virtual address of the hart's `Boot`, paging is off
+
+    lla t0, {panic}
+    csrw stvec, t0
+
+    // the boot trampoline still maps both physical memory and the kernel
+    lla t0, {trampoline}
+    srli t0, t0, 12
+    li t1, 0x8000000000000000
+    or t0, t0, t1
+    sfence.vma
+    csrw satp, t0
+    sfence.vma
+
+    // continue at our virtual address
+    lga t1, KERNEL_LINK_ADDR
+    lla t2, _kernel_start
+    sub t1, t1, t2
+    lla t0, 0f
+    add t0, t0, t1
+    jr t0
+    0:
+
+    ld t0, 0(a1)
+    sfence.vma
+    csrw satp, t0
+    sfence.vma
+
+    ld sp, 8(a1)
+    mv tp, zero
+    call {entry}
+
+.size secondary_start, . - secondary_start
+.type secondary_start, @function
+
+    .option pop
+",
+    panic = sym super::entry::early_panic,
+    trampoline = sym super::entry::TRAMPOLINE_ROOT_PAGE,
+    entry = sym secondary_entry,
+);
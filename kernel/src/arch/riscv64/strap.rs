@@ -5,6 +5,8 @@ use riscv::register::{scause, stvec::Stvec};
 use crate::{arch::Frame, println};
 
 
+/// Data private to one hart, kept in `tp` while running kernel code and read
+/// through [`crate::per_cpu!`]. The trap vector relies on the first two fields.
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 pub struct PerCpu{
@@ -99,6 +101,12 @@ global_asm!(
 
         jal {handler}
 
+        // the handler returns the frame to resume, which may belong to another task
+        mv sp, a0
+
+        // only now that we are off its stack may another hart run the previous task
+        jal {finish_switch}
+
 
 
 
@@ -167,6 +175,7 @@ global_asm!(
         sret
     "#,
     handler = sym strap_handler,
+    finish_switch = sym crate::task::sched::finish_switch,
     frame_size = const core::mem::size_of::<Frame>(),
 );
 
@@ -175,7 +184,26 @@ pub extern "C" fn strap_handler(
     scause: scause::Scause,
     sepc: usize,
     stval: usize,
-) {
+) -> *mut Frame {
+    if scause.is_exception() && scause.code() == 8 {
+        // skip over the ecall so we resume after it
+        frame.pc = sepc + 4;
+        let mut args = [0; 7];
+        args.copy_from_slice(&frame.regs[Frame::A0..Frame::A7]);
+        let [a0, a1] = unsafe { crate::syscall::syscall(frame, frame.regs[Frame::A7], args) };
+        frame.regs[Frame::A0] = a0;
+        frame.regs[Frame::A1] = a1;
+        return frame;
+    }
+
+    if scause.is_exception()
+        && matches!(scause.code(), 12 | 13 | 15)
+        && stval < crate::mem::space::USER_END
+        && let Some(space) = crate::task::sched::current_space()
+    {
+        return user_page_fault(frame, &space, scause.code(), stval);
+    }
+
     if scause.is_exception() {
         println!("{frame:x?}");
         let instr_enc = unsafe { (sepc as *const u16).read_volatile() };
@@ -197,7 +225,7 @@ pub extern "C" fn strap_handler(
             2 => "Illegal instruction",
             3 => {
                 println!("Breakpoint");
-                return;
+                return frame;
             }
             4 => "Load address misaligned",
             5 => "Load access fault",
@@ -210,7 +238,7 @@ pub extern "C" fn strap_handler(
                     "\nEnv call from M-mode hardid: \"{}\"... returning",
                     riscv::register::marchid::read().bits()
                 );
-                return;
+                return frame;
             }
             12 | 13 | 15 => {
                 let desc = match scause.code() {
@@ -222,6 +250,7 @@ pub extern "C" fn strap_handler(
 
                 let stap = riscv::register::satp::read();
                 println!("{:?}, {:?}, 0x{:x?}", stap.mode(), stap.asid(), stap.ppn());
+                fault_backtrace(frame, sepc);
 
                 panic!(
                     "\n\n\n{desc}:\nscause: {scause:016x?}, mepc: 0x{sepc:016x}, mtval: 0x{stval:016x}, \nCannot continue resetting\n\n"
@@ -229,16 +258,23 @@ pub extern "C" fn strap_handler(
             }
             _ => "Unknown exception",
         };
+        fault_backtrace(frame, sepc);
         panic!(
             "\n\n\n{desc}:\nscause: {scause:016x?}, mepc: 0x{sepc:016x}, mtval: 0x{stval:016x}, \nCannot continue resetting\n\n"
         );
     } else {
         match scause.code() {
-            0x5 => {
-                println!("Timer Interrupt");
+            0x1 => {
+                unsafe {
+                    asm!("csrc sip, {}", in(reg) 1 << 1);
+                }
+                super::tlb::handle_shootdown();
+                crate::task::sched::schedule(frame)
             }
+            0x5 => crate::task::sched::tick(frame),
             0x9 => {
-                panic!("External S-Mode interrupt");
+                crate::interrupt::handle_external();
+                frame
             }
             0xb => {
                 panic!("External M-Mode interrupt");
@@ -252,6 +288,49 @@ pub extern "C" fn strap_handler(
     }
 }
 
+/// Symbolizes where a fatal exception was raised, if it came from the kernel.
+fn fault_backtrace(frame: &Frame, sepc: usize) {
+    use riscv::register::sstatus::SPP;
+
+    if frame.sstatus.spp() == SPP::Supervisor {
+        unsafe { crate::panic::print_backtrace_at(sepc, frame.regs[Frame::FP]) }
+    }
+}
+
+/// Demand pages a fault on user memory. A genuine violation by the task kills
+/// it, syscalls fault in user buffers before touching them so one from the
+/// kernel is a bug.
+fn user_page_fault(
+    frame: &mut Frame,
+    space: &crate::mem::space::AddressSpace,
+    code: usize,
+    stval: usize,
+) -> *mut Frame {
+    use crate::mem::space::Access;
+
+    let access = match code {
+        12 => Access::Execute,
+        13 => Access::Read,
+        _ => Access::Write,
+    };
+    if space.handle_fault(stval, access).is_ok() {
+        return frame;
+    }
+
+    if frame.sstatus.spp() == riscv::register::sstatus::SPP::Supervisor {
+        // exiting here would leave whatever locks the syscall holds taken
+        fault_backtrace(frame, frame.pc);
+        panic!("Kernel {access:?} of unchecked user memory at 0x{stval:016x}, pc: 0x{:016x}", frame.pc);
+    }
+
+    println!(
+        "Segmentation fault: task {:?} {access:?} at 0x{stval:016x}, pc: 0x{:016x}",
+        crate::task::sched::current_id(),
+        frame.pc
+    );
+    crate::task::sched::exit(crate::task::EXIT_SEGFAULT)
+}
+
 /// # Safety
 ///
 /// .
@@ -262,6 +341,8 @@ pub unsafe fn init(hart_id: usize){
     }
     unsafe {
         riscv::register::sscratch::write(0);
+        // lets the trap handler and syscalls touch user memory directly
+        riscv::register::sstatus::set_sum();
         riscv::register::stvec::write(Stvec::new(
             strap_vector as *const () as usize,
             riscv::register::stvec::TrapMode::Direct,
@@ -273,6 +354,7 @@ pub unsafe fn init(hart_id: usize){
             hart_id,
         }));
         asm!("move tp, {0}", in(reg) ptr);
+        super::tlb::mark_online(hart_id);
         riscv::asm::ebreak();
         riscv::asm::ebreak();
     }
@@ -285,20 +367,13 @@ type InitTask = unsafe extern "C" fn(hart_id: usize, dtb_ptr: *const u8) -> !;
 /// .
 pub unsafe fn begin_init_task(init: InitTask, hart_id: usize, dtb_ptr: *const u8) -> ! {
 
-    let sp = crate::mem::KernelLayout::new().stack.end;
+    let stack = crate::mem::KernelLayout::new().stack;
 
-    let mut sstatus = riscv::register::sstatus::read();
-    sstatus.set_spie(true);
-    sstatus.set_spp(riscv::register::sstatus::SPP::Supervisor);
+    crate::task::sched::init(crate::task::Task::boot("init"));
 
-    let mut frame = Frame {
-        pc: init as usize,
-        regs: [0; 31],
-        sstatus,
-    };
-    frame.regs[1] = sp;
-    frame.regs[9] = hart_id;
-    frame.regs[10] = dtb_ptr as usize;
+    let mut frame = Frame::kernel(init as usize, stack.end);
+    frame.regs[Frame::A0] = hart_id;
+    frame.regs[Frame::A1] = dtb_ptr as usize;
 
     println!("Beginning Init Task");
     unsafe {
@@ -0,0 +1,125 @@
+use core::{
+    arch::asm,
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::sbi::{self, Extension};
+
+/// Above this many pages a shootdown flushes the whole ASID instead of page by page.
+const FLUSH_PAGES_MAX: usize = 64;
+
+const PAGE_SIZE: usize = 1 << 12;
+
+/// Harts which take part in shootdowns, one bit per hart id.
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+
+/// Held by the hart currently broadcasting a shootdown.
+static BUSY: AtomicBool = AtomicBool::new(false);
+static START: AtomicUsize = AtomicUsize::new(0);
+static END: AtomicUsize = AtomicUsize::new(0);
+static ASID: AtomicUsize = AtomicUsize::new(0);
+/// Harts which still have to flush for the request in flight.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+pub fn flush_page(addr: usize, asid: u16) {
+    unsafe {
+        if asid == 0 {
+            asm!("sfence.vma {}", in(reg) addr);
+        } else {
+            asm!("sfence.vma {}, {}", in(reg) addr, in(reg) asid as usize);
+        }
+    }
+}
+
+/// Flushes every non global translation of `asid`, or everything for ASID 0
+/// since it is shared by the kernel and every space that ran out of ASIDs.
+pub fn flush_asid(asid: u16) {
+    unsafe {
+        if asid == 0 {
+            asm!("sfence.vma");
+        } else {
+            asm!("sfence.vma x0, {}", in(reg) asid as usize);
+        }
+    }
+}
+
+pub fn flush_all() {
+    unsafe {
+        asm!("sfence.vma");
+    }
+}
+
+pub fn flush_range(range: Range<usize>, asid: u16) {
+    if range.len() / PAGE_SIZE > FLUSH_PAGES_MAX {
+        flush_asid(asid);
+    } else {
+        for addr in range.step_by(PAGE_SIZE) {
+            flush_page(addr, asid);
+        }
+    }
+}
+
+/// Lets this hart receive shootdowns, called once it can take software interrupts.
+pub fn mark_online(hart: usize) {
+    ONLINE.fetch_or(1 << hart, Ordering::SeqCst);
+}
+
+/// Flushes `range` of `asid` on every online hart and waits until they all did.
+///
+/// Remote harts are flushed by the SBI RFENCE extension when the firmware has it,
+/// otherwise they are reached with an SBI IPI and flush from [`handle_shootdown`].
+pub fn shootdown(range: Range<usize>, asid: u16) {
+    flush_range(range.clone(), asid);
+
+    let this = 1 << super::current_hart();
+    let others = ONLINE.load(Ordering::SeqCst) & !this;
+    if others == 0 {
+        return;
+    }
+
+    if sbi::has(Extension::Rfence) {
+        let (start, size) = (range.start, range.len());
+        let flushed = if asid == 0 {
+            sbi::sbi_remote_sfence_vma(others, 0, start, size)
+        } else {
+            sbi::sbi_remote_sfence_vma_asid(others, 0, start, size, asid as usize)
+        };
+        if flushed.is_ok() {
+            return;
+        }
+    }
+
+    // serve shootdowns from other harts while we wait, they can't interrupt us here
+    while BUSY
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        handle_shootdown();
+        core::hint::spin_loop();
+    }
+
+    START.store(range.start, Ordering::Relaxed);
+    END.store(range.end, Ordering::Relaxed);
+    ASID.store(asid as usize, Ordering::Relaxed);
+    PENDING.store(others, Ordering::SeqCst);
+
+    sbi::sbi_send_ipi(others, 0).expect("shootdown IPI failed");
+
+    while PENDING.load(Ordering::SeqCst) != 0 {
+        core::hint::spin_loop();
+    }
+    BUSY.store(false, Ordering::Release);
+}
+
+/// Performs the flush another hart asked for, if any. Called from the
+/// supervisor software interrupt handler.
+pub fn handle_shootdown() {
+    let this = 1 << super::current_hart();
+    if PENDING.load(Ordering::SeqCst) & this == 0 {
+        return;
+    }
+    let range = START.load(Ordering::Relaxed)..END.load(Ordering::Relaxed);
+    flush_range(range, ASID.load(Ordering::Relaxed) as u16);
+    PENDING.fetch_and(!this, Ordering::SeqCst);
+}
@@ -9,27 +9,75 @@ impl StackTrace {
     #[inline(always)]
     pub unsafe fn start() -> Option<Self> {
         unsafe {
-            let fp: usize;
-            asm!("mv {}, fp", out(reg) fp);
-
-            let pc_ptr = fp.checked_sub(mem::size_of::<usize>())?;
-            let fp = pc_ptr.checked_sub(mem::size_of::<usize>())?;
-            Some(StackTrace {
-                fp,
-                pc_ptr: pc_ptr as *const usize,
-            })
+            Self::from_fp(fp())
         }
     }
 
     pub unsafe fn next(self) -> Option<Self> {
-        unsafe {
-            let fp = *(self.fp as *const usize);
-            let pc_ptr = fp.checked_sub(mem::size_of::<usize>())?;
-            let fp = pc_ptr.checked_sub(mem::size_of::<usize>())?;
-            Some(StackTrace {
-                fp,
-                pc_ptr: pc_ptr as *const usize,
-            })
+        unsafe { Self::from_fp(*(self.fp as *const usize)) }
+    }
+
+    /// Starts a walk at frame pointer `fp`, as saved in a trap frame.
+    pub unsafe fn from_fp(fp: usize) -> Option<Self> {
+        let pc_ptr = fp.checked_sub(mem::size_of::<usize>())?;
+        let fp = pc_ptr.checked_sub(mem::size_of::<usize>())?;
+        Some(StackTrace {
+            fp,
+            pc_ptr: pc_ptr as *const usize,
+        })
+    }
+
+    /// Return address saved in this frame.
+    pub unsafe fn pc(&self) -> usize {
+        unsafe { *self.pc_ptr }
+    }
+}
+
+/// Current frame pointer.
+#[inline(always)]
+pub fn fp() -> usize {
+    let fp: usize;
+    unsafe { asm!("mv {}, fp", out(reg) fp) };
+    fp
+}
+
+/// Return addresses of the frames starting at frame pointer `fp`.
+///
+/// Stops early at anything that doesn't look like a frame further up the same
+/// stack, since not every stack we run on ends in a null frame pointer.
+///
+/// # Safety
+///
+/// `fp` has to be a frame pointer on a mapped stack.
+pub unsafe fn return_addresses(fp: usize) -> impl Iterator<Item = usize> {
+    const MAX_FRAME: usize = 1 << 20;
+
+    let mut frame = unsafe { StackTrace::from_fp(fp) };
+    core::iter::from_fn(move || {
+        let current = frame.take()?;
+        if current.fp == 0 || !current.fp.is_multiple_of(mem::size_of::<usize>()) {
+            return None;
+        }
+        let pc = unsafe { current.pc() };
+        if pc == 0 {
+            return None;
         }
+        let caller = unsafe { *(current.fp as *const usize) };
+        if caller > current.fp && caller - current.fp <= MAX_FRAME {
+            frame = unsafe { current.next() };
+        }
+        Some(pc)
+    })
+}
+
+/// Fills `out` with return addresses of the calling frames, skipping the first
+/// `skip`, and returns how many were written.
+#[inline(always)]
+pub fn capture(skip: usize, out: &mut [usize]) -> usize {
+    let mut written = 0;
+    for (slot, pc) in out.iter_mut().zip(unsafe { return_addresses(fp()) }.skip(skip)) {
+        *slot = pc;
+        written += 1;
     }
-}
\ No newline at end of file
+    written
+}
@@ -1,3 +0,0 @@
-use crate::{dtb::*, pci::pci, println};
-
-pub fn init(dtb: &Dtb<'_>) {}
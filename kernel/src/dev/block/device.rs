@@ -0,0 +1,46 @@
+//! What a block driver provides, kept apart from the device registry so the
+//! filesystems built on it compile on the host for their tests.
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    /// The access runs past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors.
+    Misaligned,
+    ReadOnly,
+    Unsupported,
+    Io,
+}
+
+/// A disk addressed in [`SECTOR_SIZE`] byte sectors.
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn sector_count(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Fills `buf`, a whole number of sectors, starting at `sector`.
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf`, a whole number of sectors, starting at `sector`.
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Returns once everything written so far is on stable storage.
+    fn flush(&self) -> Result<(), BlockError>;
+}
+
+/// Checks that `len` bytes at `sector` are whole sectors inside a device of `count` sectors.
+pub fn check_access(count: u64, sector: u64, len: usize) -> Result<(), BlockError> {
+    if !len.is_multiple_of(SECTOR_SIZE) {
+        return Err(BlockError::Misaligned);
+    }
+    match sector.checked_add((len / SECTOR_SIZE) as u64) {
+        Some(end) if end <= count => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
@@ -0,0 +1,41 @@
+use crate::{
+    alloc::{sync::Arc, vec::Vec},
+    dtb::*,
+    println,
+    sync::mutex::CriticalSpinLock,
+};
+
+mod device;
+
+pub use device::*;
+
+static DEVICES: CriticalSpinLock<Vec<Arc<dyn BlockDevice>>> = CriticalSpinLock::new(Vec::new());
+
+pub fn register(device: Arc<dyn BlockDevice>) {
+    println!(
+        "Block device {}: {} sectors{}",
+        device.name(),
+        device.sector_count(),
+        if device.read_only() { ", read only" } else { "" }
+    );
+    DEVICES.lock().push(device);
+}
+
+/// Every block device found so far, in discovery order.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|device| device.name() == name).cloned()
+}
+
+pub fn init(_dtb: &Dtb<'_>) {
+    crate::dev::virtio::init();
+
+    for device in devices() {
+        for partition in crate::dev::partition::scan(&device) {
+            register(Arc::new(partition));
+        }
+    }
+}
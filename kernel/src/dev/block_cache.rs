@@ -0,0 +1,349 @@
+//! Write-back cache of page sized blocks between filesystems and block drivers.
+//!
+//! Every [`CachedDevice`] shares one pool of [`CAPACITY`] pages, keyed by
+//! device and block and evicted least recently used first. Writes only reach
+//! the disk on eviction or [`BlockDevice::flush`].
+
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    alloc::{collections::BTreeMap, sync::Arc, vec::Vec},
+    arch::page::Page,
+    dev::block::{BlockDevice, BlockError, SECTOR_SIZE, check_access},
+    mem::pages::{self, PagePtr},
+    println,
+    sync::mutex::CriticalSpinLock,
+};
+
+pub const BLOCK_SIZE: usize = core::mem::size_of::<Page>();
+const SECTORS_PER_BLOCK: u64 = (BLOCK_SIZE / SECTOR_SIZE) as u64;
+
+/// Pages the cache may hold across all devices.
+pub const CAPACITY: usize = 256;
+/// Blocks fetched past a miss that continues a sequential run.
+pub const READ_AHEAD: u64 = 8;
+
+type Key = (usize, u64);
+
+struct Block {
+    page: PagePtr,
+    dirty: bool,
+    /// Position in [`Cache::lru`].
+    stamp: u64,
+}
+
+struct Device {
+    dev: Arc<dyn BlockDevice>,
+    /// Block a sequential reader would want next.
+    next_sequential: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub blocks: usize,
+    pub dirty: usize,
+    pub hits: usize,
+    pub misses: usize,
+    pub writebacks: usize,
+}
+
+struct Cache {
+    blocks: BTreeMap<Key, Block>,
+    /// Blocks by last use, oldest first.
+    lru: BTreeMap<u64, Key>,
+    devices: BTreeMap<usize, Device>,
+    clock: u64,
+    stats: Stats,
+}
+
+unsafe impl Send for Cache {}
+
+static CACHE: CriticalSpinLock<Cache> = CriticalSpinLock::new(Cache {
+    blocks: BTreeMap::new(),
+    lru: BTreeMap::new(),
+    devices: BTreeMap::new(),
+    clock: 0,
+    stats: Stats {
+        blocks: 0,
+        dirty: 0,
+        hits: 0,
+        misses: 0,
+        writebacks: 0,
+    },
+});
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn page_bytes(page: PagePtr) -> &'static mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(page.virt().cast(), BLOCK_SIZE) }
+}
+
+impl Cache {
+    /// Sectors of `block` that exist, the last block of a device may be short.
+    fn block_len(dev: &dyn BlockDevice, block: u64) -> usize {
+        let first = block * SECTORS_PER_BLOCK;
+        (dev.sector_count() - first).min(SECTORS_PER_BLOCK) as usize * SECTOR_SIZE
+    }
+
+    fn touch(&mut self, key: Key) {
+        self.clock += 1;
+        let stamp = self.clock;
+        if let Some(block) = self.blocks.get_mut(&key) {
+            self.lru.remove(&block.stamp);
+            block.stamp = stamp;
+            self.lru.insert(stamp, key);
+        }
+    }
+
+    fn write_back(&mut self, key: Key) -> Result<(), BlockError> {
+        let Some(block) = self.blocks.get(&key) else {
+            return Ok(());
+        };
+        if !block.dirty {
+            return Ok(());
+        }
+        let dev = &self
+            .devices
+            .get(&key.0)
+            .expect("cached block of unknown device")
+            .dev;
+        let len = Self::block_len(&**dev, key.1);
+        dev.write(key.1 * SECTORS_PER_BLOCK, &page_bytes(block.page)[..len])?;
+        self.blocks.get_mut(&key).unwrap().dirty = false;
+        self.stats.dirty -= 1;
+        self.stats.writebacks += 1;
+        Ok(())
+    }
+
+    /// Drops the least recently used block that could be written back. Failing
+    /// that for all of them, the oldest one is dropped with its changes lost
+    /// rather than failing an access to some other block.
+    fn evict(&mut self) -> PagePtr {
+        let oldest = *self.lru.keys().next().expect("cache is empty");
+        let mut stamp = oldest;
+        let mut first_err = None;
+        let stamp = loop {
+            let Err(err) = self.write_back(self.lru[&stamp]) else {
+                break stamp;
+            };
+            let err = *first_err.get_or_insert(err);
+            match self.lru.range(stamp + 1..).next() {
+                Some((&next, _)) => stamp = next,
+                None => {
+                    let key = self.lru[&oldest];
+                    let name = self.devices[&key.0].dev.name();
+                    println!("Lost cached writes to {name} block {}: {err:?}", key.1);
+                    break oldest;
+                }
+            }
+        };
+        let key = self.lru.remove(&stamp).unwrap();
+        let block = self.blocks.remove(&key).unwrap();
+        if block.dirty {
+            self.stats.dirty -= 1;
+        }
+        self.stats.blocks -= 1;
+        block.page
+    }
+
+    /// Makes room for and inserts `key`, reading it from the device unless `fill` is false.
+    fn insert(&mut self, key: Key, fill: bool) -> Result<(), BlockError> {
+        let page = if self.blocks.len() >= CAPACITY {
+            let page = self.evict();
+            page_bytes(page).fill(0);
+            page
+        } else {
+            unsafe { pages::page_zeroed() }
+        };
+
+        if fill {
+            let dev = &self.devices.get(&key.0).expect("unknown device").dev;
+            let len = Self::block_len(&**dev, key.1);
+            if let Err(err) = dev.read(key.1 * SECTORS_PER_BLOCK, &mut page_bytes(page)[..len]) {
+                unsafe { pages::free_page(page) };
+                return Err(err);
+            }
+        }
+
+        self.blocks.insert(
+            key,
+            Block {
+                page,
+                dirty: false,
+                stamp: 0,
+            },
+        );
+        self.stats.blocks += 1;
+        self.touch(key);
+        Ok(())
+    }
+
+    /// The cached page of `key`, loaded if needed. `fill` is false when the
+    /// caller overwrites the whole block anyway.
+    fn get(&mut self, key: Key, fill: bool) -> Result<PagePtr, BlockError> {
+        let device = self.devices.get_mut(&key.0).expect("unknown device");
+        let sequential = device.next_sequential == key.1;
+        device.next_sequential = key.1 + 1;
+
+        if self.blocks.contains_key(&key) {
+            self.stats.hits += 1;
+            self.touch(key);
+        } else {
+            self.stats.misses += 1;
+            self.insert(key, fill)?;
+            if sequential && fill {
+                self.read_ahead(key);
+            }
+        }
+        Ok(self.blocks[&key].page)
+    }
+
+    /// Loads the blocks after `key`, stopping at the first one already cached.
+    fn read_ahead(&mut self, key: Key) {
+        let dev = self.devices[&key.0].dev.clone();
+        let blocks = dev.sector_count().div_ceil(SECTORS_PER_BLOCK);
+        for block in key.1 + 1..(key.1 + 1 + READ_AHEAD).min(blocks) {
+            let next = (key.0, block);
+            if self.blocks.contains_key(&next) || self.insert(next, true).is_err() {
+                break;
+            }
+        }
+        // the block just asked for must not be the next one evicted
+        self.touch(key);
+    }
+
+    fn sync(&mut self, id: usize) -> Result<(), BlockError> {
+        let dirty: Vec<Key> = self
+            .blocks
+            .range((id, 0)..=(id, u64::MAX))
+            .filter(|(_, block)| block.dirty)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in dirty {
+            self.write_back(key)?;
+        }
+        Ok(())
+    }
+}
+
+/// A block device with every access going through the cache.
+pub struct CachedDevice {
+    id: usize,
+    dev: Arc<dyn BlockDevice>,
+}
+
+impl CachedDevice {
+    pub fn new(dev: Arc<dyn BlockDevice>) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        CACHE.lock().devices.insert(
+            id,
+            Device {
+                dev: dev.clone(),
+                next_sequential: 0,
+            },
+        );
+        Self { id, dev }
+    }
+
+    /// Runs `f` over each cached block `sector..sector + len / SECTOR_SIZE` touches,
+    /// with the byte range inside the block and inside the caller's buffer.
+    fn for_each_block(
+        &self,
+        sector: u64,
+        len: usize,
+        mut f: impl FnMut(u64, Range<usize>, Range<usize>) -> Result<(), BlockError>,
+    ) -> Result<(), BlockError> {
+        let mut done = 0;
+        while done < len {
+            let sector = sector + (done / SECTOR_SIZE) as u64;
+            let block = sector / SECTORS_PER_BLOCK;
+            let within = (sector % SECTORS_PER_BLOCK) as usize * SECTOR_SIZE;
+            let count = (BLOCK_SIZE - within).min(len - done);
+            f(block, within..within + count, done..done + count)?;
+            done += count;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn name(&self) -> &str {
+        self.dev.name()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.dev.sector_count()
+    }
+
+    fn read_only(&self) -> bool {
+        self.dev.read_only()
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_access(self.sector_count(), sector, buf.len())?;
+        let mut cache = CACHE.lock();
+        self.for_each_block(sector, buf.len(), |block, inside, outside| {
+            let page = cache.get((self.id, block), true)?;
+            buf[outside].copy_from_slice(&page_bytes(page)[inside]);
+            Ok(())
+        })
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_access(self.sector_count(), sector, buf.len())?;
+        if self.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut guard = CACHE.lock();
+        let cache = &mut *guard;
+        self.for_each_block(sector, buf.len(), |block, inside, outside| {
+            let whole = inside.len() == Cache::block_len(&*self.dev, block);
+            let page = cache.get((self.id, block), !whole)?;
+            page_bytes(page)[inside].copy_from_slice(&buf[outside]);
+            let block = cache.blocks.get_mut(&(self.id, block)).unwrap();
+            if !block.dirty {
+                block.dirty = true;
+                cache.stats.dirty += 1;
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes back every dirty block of the device, then flushes the device itself.
+    fn flush(&self) -> Result<(), BlockError> {
+        CACHE.lock().sync(self.id)?;
+        self.dev.flush()
+    }
+}
+
+impl Drop for CachedDevice {
+    fn drop(&mut self) {
+        let mut guard = CACHE.lock();
+        let cache = &mut *guard;
+        if let Err(err) = cache.sync(self.id) {
+            println!("Lost cached writes to {}: {err:?}", self.dev.name());
+        }
+        let keys: Vec<Key> = cache
+            .blocks
+            .range((self.id, 0)..=(self.id, u64::MAX))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            let block = cache.blocks.remove(&key).unwrap();
+            cache.lru.remove(&block.stamp);
+            if block.dirty {
+                cache.stats.dirty -= 1;
+            }
+            cache.stats.blocks -= 1;
+            unsafe { pages::free_page(block.page) };
+        }
+        cache.devices.remove(&self.id);
+    }
+}
+
+pub fn stats() -> Stats {
+    CACHE.lock().stats
+}
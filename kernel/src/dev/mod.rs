@@ -1,7 +1,11 @@
 pub mod block;
+pub mod block_cache;
 pub mod display;
+pub mod partition;
 pub mod pci;
 pub mod syscon;
 pub mod test_pci;
+pub mod tty;
 pub mod uart;
 pub mod vga;
+pub mod virtio;
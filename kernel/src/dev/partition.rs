@@ -0,0 +1,231 @@
+//! MBR and GPT partition tables, each partition becomes a block device of its own.
+
+use crate::{
+    alloc::{format, string::String, sync::Arc, vec, vec::Vec},
+    dev::block::{BlockDevice, BlockError, SECTOR_SIZE, check_access},
+    util::crc32::crc32,
+};
+
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_GPT: u8 = 0xee;
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+/// Logical partitions followed in an extended partition chain, in case it loops.
+const MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER: usize = 92;
+const GPT_MIN_ENTRY: usize = 128;
+/// Largest entry array read, the usual one is 128 entries of 128 bytes.
+const GPT_MAX_ENTRIES_SIZE: usize = 1 << 20;
+
+/// A slice of another block device.
+pub struct Partition {
+    name: String,
+    dev: Arc<dyn BlockDevice>,
+    start: u64,
+    count: u64,
+}
+
+impl Partition {
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_only(&self) -> bool {
+        self.dev.read_only()
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_access(self.count, sector, buf.len())?;
+        self.dev.read(self.start + sector, buf)
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_access(self.count, sector, buf.len())?;
+        self.dev.write(self.start + sector, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.dev.flush()
+    }
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_sectors(dev: &dyn BlockDevice, sector: u64, count: usize) -> Result<Vec<u8>, BlockError> {
+    let mut buf = vec![0u8; count * SECTOR_SIZE];
+    dev.read(sector, &mut buf)?;
+    Ok(buf)
+}
+
+/// Start and length of every partition on `dev`, empty if it isn't partitioned.
+fn find(dev: &dyn BlockDevice) -> Result<Vec<(u64, u64)>, BlockError> {
+    let mbr = read_sectors(dev, 0, 1)?;
+    if mbr[510] != 0x55 || mbr[511] != 0xaa || looks_like_boot_sector(&mbr) {
+        return Ok(Vec::new());
+    }
+
+    let entries: Vec<&[u8]> = mbr[MBR_ENTRIES..MBR_ENTRIES + 4 * MBR_ENTRY_SIZE]
+        .chunks_exact(MBR_ENTRY_SIZE)
+        .collect();
+    // boot code of an unpartitioned disk rarely passes for four sane entries
+    if entries.iter().any(|entry| entry[0] & 0x7f != 0) {
+        return Ok(Vec::new());
+    }
+
+    if entries.iter().any(|entry| entry[4] == MBR_TYPE_GPT) {
+        if let Some(partitions) = gpt(dev, 1)? {
+            return Ok(partitions);
+        }
+        // the primary table is damaged, the backup lives in the last sector
+        if let Some(partitions) = gpt(dev, dev.sector_count() - 1)? {
+            return Ok(partitions);
+        }
+        return Ok(Vec::new());
+    }
+
+    let mut partitions = Vec::new();
+    for entry in entries {
+        let kind = entry[4];
+        let start = le32(entry, 8) as u64;
+        let count = le32(entry, 12) as u64;
+        if kind == 0 || count == 0 {
+            continue;
+        }
+        if MBR_TYPE_EXTENDED.contains(&kind) {
+            logical(dev, start, &mut partitions)?;
+        } else {
+            partitions.push((start, count));
+        }
+    }
+    Ok(partitions)
+}
+
+/// Whether sector 0 is the boot sector of a FAT volume rather than an MBR.
+fn looks_like_boot_sector(sector: &[u8]) -> bool {
+    (sector[0] == 0xeb || sector[0] == 0xe9)
+        && (&sector[82..87] == b"FAT32" || &sector[54..57] == b"FAT")
+}
+
+/// Walks the chain of extended boot records starting at `base`.
+fn logical(
+    dev: &dyn BlockDevice,
+    base: u64,
+    partitions: &mut Vec<(u64, u64)>,
+) -> Result<(), BlockError> {
+    let mut ebr = base;
+    for _ in 0..MAX_LOGICAL {
+        let sector = read_sectors(dev, ebr, 1)?;
+        if sector[510] != 0x55 || sector[511] != 0xaa {
+            break;
+        }
+        let first = &sector[MBR_ENTRIES..MBR_ENTRIES + MBR_ENTRY_SIZE];
+        let next = &sector[MBR_ENTRIES + MBR_ENTRY_SIZE..MBR_ENTRIES + 2 * MBR_ENTRY_SIZE];
+
+        // the partition is relative to this EBR, the next EBR to the extended partition
+        if first[4] != 0 && le32(first, 12) != 0 {
+            partitions.push((ebr + le32(first, 8) as u64, le32(first, 12) as u64));
+        }
+        if next[4] == 0 || le32(next, 8) == 0 {
+            break;
+        }
+        ebr = base + le32(next, 8) as u64;
+    }
+    Ok(())
+}
+
+/// Partitions of the GPT whose header is at `lba`, `None` if it doesn't check out.
+fn gpt(dev: &dyn BlockDevice, lba: u64) -> Result<Option<Vec<(u64, u64)>>, BlockError> {
+    let mut header = read_sectors(dev, lba, 1)?;
+    if &header[..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let header_size = le32(&header, 12) as usize;
+    if !(GPT_MIN_HEADER..=SECTOR_SIZE).contains(&header_size) || le64(&header, 24) != lba {
+        return Ok(None);
+    }
+    let header_crc = le32(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != header_crc {
+        return Ok(None);
+    }
+
+    let first_usable = le64(&header, 40);
+    let last_usable = le64(&header, 48);
+    let entries_lba = le64(&header, 72);
+    let entry_count = le32(&header, 80) as usize;
+    let entry_size = le32(&header, 84) as usize;
+    let entries_crc = le32(&header, 88);
+    let entries_len = entry_count.saturating_mul(entry_size);
+    if entry_size < GPT_MIN_ENTRY
+        || !entry_size.is_multiple_of(GPT_MIN_ENTRY)
+        || entries_len > GPT_MAX_ENTRIES_SIZE
+        || last_usable >= dev.sector_count()
+    {
+        return Ok(None);
+    }
+
+    let entries = read_sectors(dev, entries_lba, entries_len.div_ceil(SECTOR_SIZE))?;
+    if crc32(&entries[..entries_len]) != entries_crc {
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for entry in entries[..entries_len].chunks_exact(entry_size) {
+        // an all zero type GUID marks an unused entry
+        if entry[..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let first = le64(entry, 32);
+        let last = le64(entry, 40);
+        if first < first_usable || last > last_usable || last < first {
+            continue;
+        }
+        partitions.push((first, last - first + 1));
+    }
+    Ok(Some(partitions))
+}
+
+/// Reads the partition table of `dev` and wraps every partition that fits on it,
+/// named after the device with the partition number appended.
+pub fn scan(dev: &Arc<dyn BlockDevice>) -> Vec<Partition> {
+    let found = match find(&**dev) {
+        Ok(found) => found,
+        Err(err) => {
+            crate::println!("Could not read partition table of {}: {err:?}", dev.name());
+            return Vec::new();
+        }
+    };
+    found
+        .into_iter()
+        .enumerate()
+        .filter(|(_, (start, count))| {
+            start
+                .checked_add(*count)
+                .is_some_and(|end| *start > 0 && end <= dev.sector_count())
+        })
+        .map(|(i, (start, count))| Partition {
+            name: format!("{}p{}", dev.name(), i + 1),
+            dev: dev.clone(),
+            start,
+            count,
+        })
+        .collect()
+}
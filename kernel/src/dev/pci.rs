@@ -284,8 +284,8 @@ impl PCI {
             (align, Bar::MMIO64(_, b)) => {
                 let align = 1u64 << (align.trailing_zeros());
                 let addr = self.mm_64_bump + self.mm_64_reg[0];
-                let alignment_fix = (align - (addr & (align - 1))) & !(align - 1);
-                let addr = addr.next_multiple_of(align) - addr;
+                let alignment_fix = addr.next_multiple_of(align) - addr;
+                let addr = addr + alignment_fix;
 
                 let offset = align + alignment_fix;
                 self.mm_64_bump += offset;
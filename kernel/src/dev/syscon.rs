@@ -1,4 +1,6 @@
 use crate::arch;
+use crate::mem::Pointer;
+use crate::sbi;
 
 use crate::dtb::*;
 use crate::println;
@@ -16,6 +18,12 @@ impl Action {
             ptr: core::ptr::null_mut(),
         }
     }
+
+    fn trigger(&self) {
+        if !self.ptr.is_null() {
+            unsafe { self.ptr.write_volatile(self.value) }
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -29,91 +37,94 @@ static mut SYSCON: Syscon = Syscon {
     reboot: Action::default(),
 };
 
-pub fn init(dtb: &Dtb) {
-    println!("Initializing syscon");
+/// Finds the register and value of one `syscon-poweroff` or `syscon-reboot` node.
+fn discover_action(dtb: &Dtb, compatible: &str) -> Action {
+    let node = dtb
+        .nodes()
+        .compatible(compatible.as_bytes())
+        .next()
+        .unwrap_or_else(|| panic!("no compatible devices for {compatible}"));
+
+    let props = node.properties();
+    let handle = props.expect_value(b"regmap", ByteStream::u32);
+    let offset = props.expect_value(b"offset", ByteStream::u32) as usize;
+    let value = props.expect_value(b"value", ByteStream::u32);
+
+    let node = dtb
+        .nodes()
+        .compatible(b"syscon")
+        .next()
+        .expect("no compatible devices for syscon");
+    let props = node.properties();
+    let [start, _] = props.expect_value(b"reg", ByteStream::u64_array::<2>);
+
+    if handle != props.expect_value(b"phandle", ByteStream::u32) {
+        panic!()
+    }
 
-    println!("Initializing syscon-poweroff");
-    {
-        let node = dtb
-            .nodes()
-            .compatible(b"syscon-poweroff")
-            .next()
-            .expect("no compatible devices for syscon-poweroff");
-
-        let props = node.properties();
-        let handle = props.expect_value(b"regmap", ByteStream::u32);
-        let offset = props.expect_value(b"offset", ByteStream::u32) as usize;
-        let value = props.expect_value(b"value", ByteStream::u32);
-
-        let node = dtb
-            .nodes()
-            .compatible(b"syscon")
-            .next()
-            .expect("no compatible devices for syscon");
-        let props = node.properties();
-        let [start, _] = props.expect_value(b"reg", ByteStream::u64_array::<2>);
-
-        if handle != props.expect_value(b"phandle", ByteStream::u32) {
-            panic!()
-        }
+    Action {
+        value,
+        ptr: (start as usize + offset) as *mut u32,
+    }
+}
 
-        unsafe {
-            SYSCON.poweroff.ptr = (start as usize + offset) as *mut u32;
-            SYSCON.poweroff.value = value;
+impl Syscon {
+    /// Reads the poweroff and reboot registers from the DTB, at their physical addresses.
+    pub fn discover(dtb: &Dtb) -> Self {
+        Self {
+            poweroff: discover_action(dtb, "syscon-poweroff"),
+            reboot: discover_action(dtb, "syscon-reboot"),
         }
     }
 
-    println!("Initializing syscon-reboot");
-    {
-        let node = dtb
-            .nodes()
-            .compatible(b"syscon-reboot")
-            .next()
-            .expect("no compatible devices for syscon-reboot");
-
-        let props = node.properties();
-        let handle = props.expect_value(b"regmap", ByteStream::u32);
-        let offset = props.expect_value(b"offset", ByteStream::u32) as usize;
-        let value = props.expect_value(b"value", ByteStream::u32);
-
-        let node = dtb
-            .nodes()
-            .compatible(b"syscon")
-            .next()
-            .expect("no compatible devices for syscon");
-        let props = node.properties();
-        let [start, _] = props.expect_value(b"reg", ByteStream::u64_array::<2>);
-
-        if handle != props.expect_value(b"phandle", ByteStream::u32) {
-            panic!()
-        }
+    /// Powers the machine off, returning only if that didn't work.
+    pub fn request_poweroff(&self) {
+        self.poweroff.trigger()
+    }
 
-        unsafe {
-            SYSCON.reboot.ptr = (start as usize + offset) as *mut u32;
-            SYSCON.reboot.value = value;
-        }
+    /// Resets the machine, returning only if that didn't work.
+    pub fn request_reboot(&self) {
+        self.reboot.trigger()
+    }
+}
+
+pub fn init(dtb: &Dtb) {
+    println!("Initializing syscon");
+
+    unsafe {
+        SYSCON = Syscon::discover(dtb);
     }
 
     println!("Initialized syscon");
     println!("{:#x?}", unsafe { SYSCON });
 }
 
+/// Writes `action` through the physical memory map, syscon registers aren't mapped otherwise.
+fn trigger_mapped(action: Action, what: &str) {
+    if action.ptr.is_null() {
+        panic!("syscon {what} not initialized")
+    }
+    Action {
+        ptr: Pointer::from_phys(action.ptr).virt(),
+        ..action
+    }
+    .trigger()
+}
+
+/// Powers off through the SBI firmware, falling back to the syscon when it can't.
 pub fn poweroff() -> ! {
-    unsafe {
-        if SYSCON.poweroff.ptr.is_null() {
-            panic!("syscon poweroff not initialized")
-        }
-        SYSCON.poweroff.ptr.write_volatile(SYSCON.poweroff.value);
+    if sbi::has(sbi::Extension::Srst) {
+        sbi::sbi_system_reset(sbi::ResetType::Shutdown, sbi::ResetReason::None);
     }
+    trigger_mapped(unsafe { SYSCON.poweroff }, "poweroff");
     arch::halt()
 }
 
+/// Resets through the SBI firmware, falling back to the syscon when it can't.
 pub fn reboot() -> ! {
-    unsafe {
-        if SYSCON.reboot.ptr.is_null() {
-            panic!("syscon reboot not initialized")
-        }
-        SYSCON.reboot.ptr.write_volatile(SYSCON.reboot.value);
+    if sbi::has(sbi::Extension::Srst) {
+        sbi::sbi_system_reset(sbi::ResetType::ColdReboot, sbi::ResetReason::None);
     }
+    trigger_mapped(unsafe { SYSCON.reboot }, "reboot");
     arch::halt()
 }
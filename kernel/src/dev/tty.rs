@@ -0,0 +1,241 @@
+//! Console on the UART with a canonical mode line discipline.
+//!
+//! Input is edited a line at a time and only handed to readers once it ends in
+//! a newline. Output is queued and drained by the UART's transmit interrupt.
+
+use crate::{
+    dev::uart::uart,
+    sync::mutex::CriticalSpinLock,
+    task::{TaskId, sched},
+    util::ring::RingBuffer,
+};
+
+const TX_SIZE: usize = 4096;
+const INPUT_SIZE: usize = 1024;
+/// Longest line that can be typed, further characters are dropped.
+const LINE_MAX: usize = 255;
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const CTRL_U: u8 = 0x15;
+const DELETE: u8 = 0x7f;
+
+struct Tty {
+    /// Not yet transmitted output.
+    tx: RingBuffer<TX_SIZE>,
+    /// The line being edited.
+    line: RingBuffer<LINE_MAX>,
+    /// Finished lines waiting for a reader.
+    input: RingBuffer<INPUT_SIZE>,
+    /// Newlines in `input`.
+    lines: usize,
+    /// Set by ^C until the blocked reader saw it.
+    interrupted: bool,
+    /// Task blocked in [`read`].
+    reader: Option<TaskId>,
+    attached: bool,
+    echo: bool,
+}
+
+static TTY: CriticalSpinLock<Tty> = CriticalSpinLock::new(Tty {
+    tx: RingBuffer::new(),
+    line: RingBuffer::new(),
+    input: RingBuffer::new(),
+    lines: 0,
+    interrupted: false,
+    reader: None,
+    attached: false,
+    echo: true,
+});
+
+/// [`read`] was cut short by ^C.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interrupted;
+
+impl Tty {
+    fn output(&mut self, byte: u8) {
+        if byte == b'\n' {
+            self.queue(b'\r');
+        }
+        self.queue(byte);
+    }
+
+    fn queue(&mut self, byte: u8) {
+        if !self.attached {
+            uart().putc(byte);
+            return;
+        }
+        while !self.tx.push(byte) {
+            // full, make room the slow way rather than lose output
+            if let Some(byte) = self.tx.pop() {
+                uart().putc(byte);
+            }
+        }
+    }
+
+    /// Moves queued output into the transmitter while it has room, asking for
+    /// an interrupt once it drains if anything is left.
+    fn kick(&mut self) {
+        while !self.tx.is_empty() && uart().can_tx() {
+            if let Some(byte) = self.tx.pop() {
+                uart().try_putc(byte);
+            }
+        }
+        uart().set_interrupts(true, !self.tx.is_empty());
+    }
+
+    fn echo(&mut self, bytes: &[u8]) {
+        if self.echo {
+            for &byte in bytes {
+                self.output(byte);
+            }
+        }
+    }
+
+    fn erase(&mut self) {
+        if self.line.pop_back().is_some() {
+            self.echo(b"\x08 \x08");
+        }
+    }
+
+    /// Feeds one received byte through the line discipline.
+    fn receive(&mut self, byte: u8) {
+        match byte {
+            b'\r' | b'\n' => {
+                self.echo(b"\n");
+                if self.input.len() + self.line.len() + 1 > INPUT_SIZE {
+                    // nobody is reading, drop the line rather than a part of it
+                    self.line.clear();
+                    return;
+                }
+                while let Some(byte) = self.line.pop() {
+                    self.input.push(byte);
+                }
+                self.input.push(b'\n');
+                self.lines += 1;
+                self.wake_reader();
+            }
+            BACKSPACE | DELETE => self.erase(),
+            CTRL_U => {
+                while !self.line.is_empty() {
+                    self.erase();
+                }
+            }
+            CTRL_C => {
+                self.echo(b"^C\n");
+                self.line.clear();
+                if self.reader.is_some() {
+                    self.interrupted = true;
+                    self.wake_reader();
+                }
+            }
+            0x20..=0x7e | b'\t' => {
+                if self.line.push(byte) {
+                    self.echo(&[byte]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            sched::wake(reader);
+        }
+    }
+
+    /// Copies out input up to and including the next newline.
+    fn take_line(&mut self, buf: &mut [u8]) -> usize {
+        let mut read = 0;
+        while read < buf.len()
+            && let Some(byte) = self.input.pop()
+        {
+            buf[read] = byte;
+            read += 1;
+            if byte == b'\n' {
+                self.lines -= 1;
+                break;
+            }
+        }
+        read
+    }
+}
+
+/// Routes UART interrupt `irq` to the console and switches output to the transmit queue.
+pub fn attach(irq: u32) {
+    if crate::interrupt::register(irq, &handle_interrupt).is_err() {
+        crate::println!("Could not register UART interrupt {irq}, console stays polled");
+        return;
+    }
+    let mut tty = TTY.lock();
+    tty.attached = true;
+    uart().set_interrupts(true, false);
+}
+
+fn handle_interrupt(_irq: u32) {
+    let mut tty = TTY.lock();
+    while let Some(byte) = uart().try_getc() {
+        tty.receive(byte);
+    }
+    tty.kick();
+}
+
+/// Writes `str` to the console, translating newlines.
+pub fn write_str(str: &str) {
+    let mut tty = TTY.lock();
+    for &byte in str.as_bytes() {
+        tty.output(byte);
+    }
+    if tty.attached {
+        tty.kick();
+    }
+}
+
+/// Turns echoing of typed characters on or off.
+pub fn set_echo(echo: bool) {
+    TTY.lock().echo = echo;
+}
+
+/// Blocks until a whole line was typed, then copies as much of it as fits in `buf`.
+/// Whatever doesn't fit is left for the next read.
+pub fn read(buf: &mut [u8]) -> Result<usize, Interrupted> {
+    if buf.is_empty() {
+        return Ok(0);
+    }
+    // taken out under the lock and only copied to `buf`, which may be user memory, after
+    let mut line = [0u8; LINE_MAX + 1];
+    let want = buf.len().min(line.len());
+    loop {
+        let mut tty = TTY.lock();
+        if core::mem::take(&mut tty.interrupted) {
+            return Err(Interrupted);
+        }
+        if tty.lines > 0 {
+            let len = tty.take_line(&mut line[..want]);
+            drop(tty);
+            buf[..len].copy_from_slice(&line[..len]);
+            return Ok(len);
+        }
+        let Some(me) = sched::current_id() else {
+            drop(tty);
+            core::hint::spin_loop();
+            continue;
+        };
+        // still holding the lock, so the interrupt can't finish a line before we are marked blocked
+        tty.reader = Some(me);
+        sched::mark_blocked();
+        drop(tty);
+        sched::relax();
+    }
+}
+
+/// Pushes out whatever is still queued by polling, for the panic handler.
+///
+/// Gives up if the queue is locked, since that may be by the code that panicked.
+pub fn flush() {
+    if let Some(mut tty) = TTY.try_lock() {
+        while let Some(byte) = tty.tx.pop() {
+            uart().putc(byte);
+        }
+    }
+}
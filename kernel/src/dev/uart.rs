@@ -32,7 +32,7 @@ pub fn init(dtb: &Dtb) {
         .expect("cannot find ns16550a compatable device");
 
     let props = node.properties();
-    let _interrupts = props.expect_value(b"interrupts", ByteStream::u32);
+    let interrupt = props.expect_value(b"interrupts", ByteStream::u32);
     let _interrupts_parent = props.expect_value(b"interrupt-parent", ByteStream::u32);
     let clock_frequency = props.expect_value(b"clock-frequency", ByteStream::u32);
     let [start, _size] = props.expect_value(b"reg", ByteStream::u64_array::<2>);
@@ -46,6 +46,8 @@ pub fn init(dtb: &Dtb) {
 
     stdio::set_sout(|str| uart().write_str(str));
 
+    crate::dev::tty::attach(interrupt);
+
     // unsafe{
     //     use crate::dev::pci;
 
@@ -96,6 +98,10 @@ const MSR: usize = 0x6;
 // LCR bits
 const LCR_DLAB: u8 = 1 << 7;
 
+// IER bits
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_THR_EMPTY: u8 = 1 << 1;
+
 // LSR bits
 const LSR_DATA_READY: u8 = 1 << 0;
 const LSR_THR_EMPTY: u8 = 1 << 5;
@@ -184,6 +190,28 @@ impl Uart16550 {
         self.write_reg(RBR_THR_DLL, c);
     }
 
+    /// Writes `c` only if the transmitter has room for it.
+    pub fn try_putc(&mut self, c: u8) -> bool {
+        if self.can_tx() {
+            self.write_reg(RBR_THR_DLL, c);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Selects which of received data and an empty transmitter raise the UART interrupt.
+    pub fn set_interrupts(&mut self, rx: bool, tx: bool) {
+        let mut ier = 0;
+        if rx {
+            ier |= IER_RX_AVAILABLE;
+        }
+        if tx {
+            ier |= IER_THR_EMPTY;
+        }
+        self.write_reg(IER_DLM, ier);
+    }
+
     pub fn try_getc(&mut self) -> Option<u8> {
         if self.can_rx() {
             Some(self.read_reg(RBR_THR_DLL))
@@ -52,6 +52,10 @@ impl FrameBuffer {
         self.len
     }
 
+    pub const fn as_ptr(&self) -> *mut Color {
+        self.ptr
+    }
+
     #[must_use]
     pub fn is_empty(&self) -> bool {
         self.len() == 0
@@ -0,0 +1,205 @@
+use crate::{
+    alloc::string::String,
+    arch::page::Page,
+    dev::{
+        block::{BlockDevice, BlockError, SECTOR_SIZE, check_access},
+        pci::PciBdf,
+    },
+    mem::pages::{self, PagePtr},
+    sync::mutex::CriticalSpinLock,
+};
+
+use super::{
+    VirtioError, VirtioPci,
+    queue::{Buffer, VirtQueue},
+};
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+const S_OK: u8 = 0;
+const S_UNSUPP: u8 = 2;
+
+/// Offset of `capacity` in the device configuration.
+const CONFIG_CAPACITY: usize = 0;
+
+const QUEUE_SIZE: u16 = 16;
+
+/// Request header, status byte and bounce buffer share one DMA page.
+const HEADER_OFFSET: usize = 0;
+const STATUS_OFFSET: usize = 16;
+/// Sectors moved per request, through the rest of the DMA pages.
+const BOUNCE_PAGES: usize = 2;
+const BOUNCE_SIZE: usize = BOUNCE_PAGES * core::mem::size_of::<Page>();
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct RequestHeader {
+    kind: u32,
+    _reserved: u32,
+    sector: u64,
+}
+
+struct Inner {
+    transport: VirtioPci,
+    queue: VirtQueue,
+    /// Header and status.
+    request: PagePtr,
+    /// Contiguous data buffer, since callers' buffers may not be physically contiguous.
+    bounce: PagePtr,
+}
+
+unsafe impl Send for Inner {}
+
+pub struct VirtioBlk {
+    name: String,
+    capacity: u64,
+    features: u64,
+    inner: CriticalSpinLock<Inner>,
+}
+
+impl VirtioBlk {
+    /// # Safety
+    ///
+    /// `bdf` must be a virtio block device nobody else is driving.
+    pub unsafe fn new(bdf: PciBdf) -> Result<Self, VirtioError> {
+        let transport = unsafe { VirtioPci::new(bdf)? };
+        let features = transport.negotiate(F_RO | F_FLUSH)?;
+        let queue = transport.setup_queue(0, QUEUE_SIZE)?;
+        transport.driver_ok();
+
+        let capacity = transport.device_config::<u64>(CONFIG_CAPACITY);
+        Ok(Self {
+            name: crate::alloc::format!("virtio-blk@{:02x}:{:02x}.{}", bdf.bus, bdf.dev, bdf.func),
+            capacity,
+            features,
+            inner: CriticalSpinLock::new(Inner {
+                transport,
+                queue,
+                request: unsafe { pages::page_zeroed() },
+                bounce: unsafe { pages::pages_zeroed(BOUNCE_PAGES) },
+            }),
+        })
+    }
+}
+
+impl Inner {
+    /// Runs one request and polls until the device completes it.
+    fn submit(&mut self, kind: u32, sector: u64, data: Option<(usize, bool)>) -> Result<(), BlockError> {
+        let base = self.request.virt().cast::<u8>();
+        unsafe {
+            base.add(HEADER_OFFSET).cast::<RequestHeader>().write_volatile(RequestHeader {
+                kind,
+                _reserved: 0,
+                sector,
+            });
+            base.add(STATUS_OFFSET).write_volatile(0xff);
+        }
+
+        let phys = self.request.phys() as u64;
+        let header = Buffer {
+            phys: phys + HEADER_OFFSET as u64,
+            len: core::mem::size_of::<RequestHeader>() as u32,
+            device_writes: false,
+        };
+        let status = Buffer {
+            phys: phys + STATUS_OFFSET as u64,
+            len: 1,
+            device_writes: true,
+        };
+        let head = match data {
+            Some((len, device_writes)) => {
+                let data = Buffer {
+                    phys: self.bounce.phys() as u64,
+                    len: len as u32,
+                    device_writes,
+                };
+                self.queue.push(&[header, data, status])
+            }
+            None => self.queue.push(&[header, status]),
+        }
+        .ok_or(BlockError::Io)?;
+        self.queue.notify();
+
+        loop {
+            match self.queue.pop_used() {
+                Some((id, _)) if id == head => break,
+                Some(_) => {}
+                None => core::hint::spin_loop(),
+            }
+        }
+        // acknowledge the interrupt it raised, nobody is listening for it
+        self.transport.isr();
+
+        match unsafe { base.add(STATUS_OFFSET).read_volatile() } {
+            S_OK => Ok(()),
+            S_UNSUPP => Err(BlockError::Unsupported),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    fn bounce(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.bounce.virt().cast(), BOUNCE_SIZE) }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_only(&self) -> bool {
+        self.features & F_RO != 0
+    }
+
+    fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_access(self.capacity, sector, buf.len())?;
+        let mut inner = self.inner.lock();
+        for (i, chunk) in buf.chunks_mut(BOUNCE_SIZE).enumerate() {
+            let sector = sector + (i * BOUNCE_SIZE / SECTOR_SIZE) as u64;
+            inner.submit(T_IN, sector, Some((chunk.len(), true)))?;
+            chunk.copy_from_slice(&inner.bounce()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_access(self.capacity, sector, buf.len())?;
+        if self.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut inner = self.inner.lock();
+        for (i, chunk) in buf.chunks(BOUNCE_SIZE).enumerate() {
+            let sector = sector + (i * BOUNCE_SIZE / SECTOR_SIZE) as u64;
+            inner.bounce()[..chunk.len()].copy_from_slice(chunk);
+            inner.submit(T_OUT, sector, Some((chunk.len(), false)))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.features & F_FLUSH == 0 {
+            // without the feature writes are never cached
+            return Ok(());
+        }
+        self.inner.lock().submit(T_FLUSH, 0, None)
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        self.transport.reset();
+        unsafe {
+            pages::free_page(self.request);
+            pages::free_pages_contiguous(self.bounce, BOUNCE_PAGES);
+        }
+    }
+}
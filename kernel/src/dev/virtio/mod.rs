@@ -0,0 +1,279 @@
+//! Virtio 1.x devices over the modern PCI transport.
+
+use crate::{
+    dev::pci::{self, CommandRegister, PciBdf},
+    println,
+};
+
+pub mod blk;
+pub mod queue;
+
+pub use queue::VirtQueue;
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+pub const F_VERSION_1: u64 = 1 << 32;
+
+const PCI_CAP_VENDOR: u8 = 0x09;
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_ISR_CFG: u8 = 3;
+const CAP_DEVICE_CFG: u8 = 4;
+
+// offsets into the common configuration structure
+const COMMON_DFSELECT: usize = 0x00;
+const COMMON_DF: usize = 0x04;
+const COMMON_GFSELECT: usize = 0x08;
+const COMMON_GF: usize = 0x0c;
+const COMMON_NUMQ: usize = 0x12;
+const COMMON_STATUS: usize = 0x14;
+const COMMON_Q_SELECT: usize = 0x16;
+const COMMON_Q_SIZE: usize = 0x18;
+const COMMON_Q_ENABLE: usize = 0x1c;
+const COMMON_Q_NOFF: usize = 0x1e;
+const COMMON_Q_DESCLO: usize = 0x20;
+const COMMON_Q_AVAILLO: usize = 0x28;
+const COMMON_Q_USEDLO: usize = 0x30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtioError {
+    /// A required capability structure is missing from the PCI config space.
+    MissingCapability(u8),
+    /// The device didn't accept the features we can drive it with.
+    FeaturesRejected,
+    NoSuchQueue(u16),
+    QueueUnavailable(u16),
+}
+
+/// Registers of a virtio device, found through its vendor specific PCI capabilities.
+pub struct VirtioPci {
+    bdf: PciBdf,
+    common: *mut u8,
+    notify: *mut u8,
+    notify_multiplier: u32,
+    isr: *mut u8,
+    device: *mut u8,
+}
+
+unsafe impl Send for VirtioPci {}
+
+fn config_u8(bdf: PciBdf, offset: usize) -> u8 {
+    let word = unsafe { pci::pci().pointer(bdf, offset).virt().read_volatile() };
+    (word >> ((offset & 3) * 8)) as u8
+}
+
+fn config_u32(bdf: PciBdf, offset: usize) -> u32 {
+    unsafe { pci::pci().pointer(bdf, offset).virt().read_volatile() }
+}
+
+impl VirtioPci {
+    /// Assigns the BARs the capabilities live in, enables the device and resets it.
+    ///
+    /// # Safety
+    ///
+    /// `bdf` must be a virtio device nobody else is driving.
+    pub unsafe fn new(bdf: PciBdf) -> Result<Self, VirtioError> {
+        let pci = pci::pci();
+
+        let (_, command) = unsafe { pci.read_cmd_status(bdf) };
+        unsafe {
+            pci.write_cmd_status(
+                bdf,
+                *command
+                    .clone()
+                    .set(CommandRegister::IO_SPACE, false)
+                    .set(CommandRegister::MEMORY_SPACE, false),
+            );
+        }
+
+        // BARs holding at least one capability, each assigned once
+        let mut assigned = [false; 6];
+        let mut caps = [None; 5];
+        let mut notify_multiplier = 0;
+
+        if config_u32(bdf, 0x04) & STATUS_CAPABILITIES == 0 {
+            return Err(VirtioError::MissingCapability(CAP_COMMON_CFG));
+        }
+        let mut cap = config_u8(bdf, 0x34) as usize & !0b11;
+        while cap != 0 {
+            if config_u8(bdf, cap) == PCI_CAP_VENDOR {
+                let cfg_type = config_u8(bdf, cap + 3);
+                let bar = config_u8(bdf, cap + 4);
+                let offset = config_u32(bdf, cap + 8);
+                if (CAP_COMMON_CFG..=CAP_DEVICE_CFG).contains(&cfg_type)
+                    && bar < 6
+                    && caps[cfg_type as usize].is_none()
+                {
+                    if !assigned[bar as usize] {
+                        unsafe { pci.allocate_bar(bdf, bar) };
+                        assigned[bar as usize] = true;
+                    }
+                    caps[cfg_type as usize] = Some((bar, offset));
+                    if cfg_type == CAP_NOTIFY_CFG {
+                        notify_multiplier = config_u32(bdf, cap + 16);
+                    }
+                }
+            }
+            cap = config_u8(bdf, cap + 1) as usize & !0b11;
+        }
+
+        unsafe {
+            pci.write_cmd_status(
+                bdf,
+                *command
+                    .clone()
+                    .set(CommandRegister::MEMORY_SPACE, true)
+                    .set(CommandRegister::BUS_MASTER, true),
+            );
+        }
+
+        let region = |cfg_type: u8| -> Result<*mut u8, VirtioError> {
+            let (bar, offset) = caps[cfg_type as usize].ok_or(VirtioError::MissingCapability(cfg_type))?;
+            let base = unsafe { pci.read_bar(bdf, bar) }.pointer::<u8>(pci).virt();
+            Ok(unsafe { base.add(offset as usize) })
+        };
+
+        let virtio = Self {
+            bdf,
+            common: region(CAP_COMMON_CFG)?,
+            notify: region(CAP_NOTIFY_CFG)?,
+            notify_multiplier,
+            isr: region(CAP_ISR_CFG)?,
+            device: region(CAP_DEVICE_CFG)?,
+        };
+        virtio.reset();
+        Ok(virtio)
+    }
+
+    pub fn bdf(&self) -> PciBdf {
+        self.bdf
+    }
+
+    fn common<T>(&self, offset: usize) -> *mut T {
+        unsafe { self.common.add(offset).cast() }
+    }
+
+    pub fn status(&self) -> u8 {
+        unsafe { self.common::<u8>(COMMON_STATUS).read_volatile() }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        unsafe { self.common::<u8>(COMMON_STATUS).write_volatile(status) }
+    }
+
+    pub fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status);
+    }
+
+    pub fn reset(&self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn device_features(&self) -> u64 {
+        unsafe {
+            self.common::<u32>(COMMON_DFSELECT).write_volatile(0);
+            let lo = self.common::<u32>(COMMON_DF).read_volatile() as u64;
+            self.common::<u32>(COMMON_DFSELECT).write_volatile(1);
+            let hi = self.common::<u32>(COMMON_DF).read_volatile() as u64;
+            lo | (hi << 32)
+        }
+    }
+
+    /// Runs the status handshake up to `FEATURES_OK`, accepting the features of
+    /// `wanted` the device offers. `F_VERSION_1` is always required.
+    pub fn negotiate(&self, wanted: u64) -> Result<u64, VirtioError> {
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let features = self.device_features() & (wanted | F_VERSION_1);
+        if features & F_VERSION_1 == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        unsafe {
+            self.common::<u32>(COMMON_GFSELECT).write_volatile(0);
+            self.common::<u32>(COMMON_GF).write_volatile(features as u32);
+            self.common::<u32>(COMMON_GFSELECT).write_volatile(1);
+            self.common::<u32>(COMMON_GF).write_volatile((features >> 32) as u32);
+        }
+
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.add_status(STATUS_FAILED);
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(features)
+    }
+
+    /// Creates queue `index` with at most `max_size` entries and hands it to the device.
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<VirtQueue, VirtioError> {
+        unsafe {
+            if index >= self.common::<u16>(COMMON_NUMQ).read_volatile() {
+                return Err(VirtioError::NoSuchQueue(index));
+            }
+            self.common::<u16>(COMMON_Q_SELECT).write_volatile(index);
+            if self.common::<u16>(COMMON_Q_ENABLE).read_volatile() != 0 {
+                return Err(VirtioError::QueueUnavailable(index));
+            }
+            let device_max = self.common::<u16>(COMMON_Q_SIZE).read_volatile();
+            if device_max == 0 {
+                return Err(VirtioError::QueueUnavailable(index));
+            }
+
+            // the split ring only needs a power of two size
+            let size = 1 << (max_size.min(device_max).ilog2());
+            let queue = VirtQueue::new(index, size);
+            self.common::<u16>(COMMON_Q_SIZE).write_volatile(size);
+            self.common::<u64>(COMMON_Q_DESCLO).write_volatile(queue.desc_phys());
+            self.common::<u64>(COMMON_Q_AVAILLO).write_volatile(queue.avail_phys());
+            self.common::<u64>(COMMON_Q_USEDLO).write_volatile(queue.used_phys());
+
+            let notify_off = self.common::<u16>(COMMON_Q_NOFF).read_volatile() as usize;
+            let notify = self
+                .notify
+                .add(notify_off * self.notify_multiplier as usize)
+                .cast::<u16>();
+
+            self.common::<u16>(COMMON_Q_ENABLE).write_volatile(1);
+            Ok(queue.with_notify(notify))
+        }
+    }
+
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Reads and acknowledges the interrupt status.
+    pub fn isr(&self) -> u8 {
+        unsafe { self.isr.read_volatile() }
+    }
+
+    /// Device specific configuration at `offset`.
+    pub fn device_config<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.device.add(offset).cast::<T>().read_volatile() }
+    }
+}
+
+/// Finds every supported virtio device on the PCI bus and starts its driver.
+pub fn init() {
+    // modern only and transitional device ids of a block device
+    for device in [0x1042, 0x1001] {
+        if let Some((bdf, _)) = pci::pci().find_device_vendor(VENDOR_ID, device) {
+            match unsafe { blk::VirtioBlk::new(bdf) } {
+                Ok(blk) => crate::dev::block::register(crate::alloc::sync::Arc::new(blk)),
+                Err(err) => println!("virtio-blk {bdf:?}: {err:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use core::sync::atomic::{Ordering, fence};
+
+use crate::mem::pages::{self, PagePtr};
+
+pub const DESC_F_NEXT: u16 = 1;
+pub const DESC_F_WRITE: u16 = 2;
+
+/// Largest queue that still fits in the single page each queue gets.
+pub const MAX_QUEUE_SIZE: u16 = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+// layout inside the queue's page, with room for `MAX_QUEUE_SIZE` entries
+const DESC_OFFSET: usize = 0;
+const AVAIL_OFFSET: usize = 16 * MAX_QUEUE_SIZE as usize;
+const USED_OFFSET: usize = 2048;
+
+/// Buffer handed to the device, by physical address.
+#[derive(Clone, Copy, Debug)]
+pub struct Buffer {
+    pub phys: u64,
+    pub len: u32,
+    /// The device writes into it rather than reading from it.
+    pub device_writes: bool,
+}
+
+/// A split virtqueue: descriptor table, driver ring and device ring in one zeroed page.
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    page: PagePtr,
+    notify: *mut u16,
+    /// Head of the chain of unused descriptors.
+    free_head: u16,
+    num_free: u16,
+    /// Used ring index up to which completions were consumed.
+    last_used: u16,
+}
+
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    /// `size` must be a power of two no larger than [`MAX_QUEUE_SIZE`].
+    pub fn new(index: u16, size: u16) -> Self {
+        assert!(size.is_power_of_two() && size <= MAX_QUEUE_SIZE);
+        let page = unsafe { pages::page_zeroed() };
+        let mut queue = Self {
+            index,
+            size,
+            page,
+            notify: core::ptr::null_mut(),
+            free_head: 0,
+            num_free: size,
+            last_used: 0,
+        };
+        for i in 0..size {
+            queue.desc(i).next = (i + 1) % size;
+        }
+        queue
+    }
+
+    pub(super) fn with_notify(mut self, notify: *mut u16) -> Self {
+        self.notify = notify;
+        self
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.page.virt().cast()
+    }
+
+    pub fn desc_phys(&self) -> u64 {
+        self.page.phys() as u64 + DESC_OFFSET as u64
+    }
+
+    pub fn avail_phys(&self) -> u64 {
+        self.page.phys() as u64 + AVAIL_OFFSET as u64
+    }
+
+    pub fn used_phys(&self) -> u64 {
+        self.page.phys() as u64 + USED_OFFSET as u64
+    }
+
+    fn desc(&mut self, i: u16) -> &mut Descriptor {
+        unsafe { &mut *self.base().add(DESC_OFFSET).cast::<Descriptor>().add(i as usize) }
+    }
+
+    /// avail ring: flags, idx, ring[size]
+    fn avail(&self, i: usize) -> *mut u16 {
+        unsafe { self.base().add(AVAIL_OFFSET).cast::<u16>().add(i) }
+    }
+
+    /// used ring: flags, idx, then ring[size] of `UsedElem`
+    fn used_idx(&self) -> u16 {
+        unsafe { self.base().add(USED_OFFSET).cast::<u16>().add(1).read_volatile() }
+    }
+
+    fn used_elem(&self, i: u16) -> UsedElem {
+        unsafe {
+            self.base()
+                .add(USED_OFFSET + 4)
+                .cast::<UsedElem>()
+                .add((i % self.size) as usize)
+                .read_volatile()
+        }
+    }
+
+    /// Chains `buffers` into descriptors and makes them available to the device,
+    /// returning the head id [`pop_used`](Self::pop_used) reports back.
+    ///
+    /// The device isn't told until [`notify`](Self::notify).
+    pub fn push(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free as usize {
+            return None;
+        }
+
+        // the free list is already linked through `next`, so the chain is the
+        // next `buffers.len()` free descriptors in order
+        let head = self.free_head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let id = self.free_head;
+            let desc = self.desc(id);
+            let next = desc.next;
+            desc.addr = buffer.phys;
+            desc.len = buffer.len;
+            desc.flags = if buffer.device_writes { DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                desc.flags |= DESC_F_NEXT;
+            }
+            self.free_head = next;
+        }
+        self.num_free -= buffers.len() as u16;
+
+        unsafe {
+            let idx = self.avail(1).read_volatile();
+            self.avail(2 + (idx % self.size) as usize).write_volatile(head);
+            // the descriptors and ring entry must be visible before the index moves
+            fence(Ordering::SeqCst);
+            self.avail(1).write_volatile(idx.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    pub fn notify(&self) {
+        unsafe { self.notify.write_volatile(self.index) }
+    }
+
+    /// Takes the next completed chain, returning its head id and how many bytes the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        fence(Ordering::SeqCst);
+        if self.used_idx() == self.last_used {
+            return None;
+        }
+        let elem = self.used_elem(self.last_used);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // return the chain to the free list
+        let head = elem.id as u16;
+        let mut id = head;
+        loop {
+            self.num_free += 1;
+            let Descriptor { flags, next, .. } = *self.desc(id);
+            if flags & DESC_F_NEXT == 0 {
+                let free_head = self.free_head;
+                self.desc(id).next = free_head;
+                break;
+            }
+            id = next;
+        }
+        self.free_head = head;
+        Some((head, elem.len))
+    }
+}
+
+impl Drop for VirtQueue {
+    fn drop(&mut self) {
+        unsafe { pages::free_page(self.page) }
+    }
+}
@@ -0,0 +1,177 @@
+#[cfg(target_pointer_width = "64")]
+pub type ElfAddr = u64;
+#[cfg(target_pointer_width = "32")]
+pub type ElfAddr = u32;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ElfRela {
+    pub r_offset: ElfAddr,
+    pub r_info: ElfAddr,
+    pub r_addend: ElfAddr,
+}
+
+pub const R_RISCV_RELATIVE: ElfAddr = 3;
+
+pub const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+pub const ELFCLASS64: u8 = 2;
+pub const ELFDATA2LSB: u8 = 1;
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+pub const EM_RISCV: u16 = 243;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_PHDR: u32 = 6;
+
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Elf64Ehdr {
+    pub e_ident: [u8; 16],
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u64,
+    pub e_phoff: u64,
+    pub e_shoff: u64,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Elf64Phdr {
+    pub p_type: u32,
+    pub p_flags: u32,
+    pub p_offset: u64,
+    pub p_vaddr: u64,
+    pub p_paddr: u64,
+    pub p_filesz: u64,
+    pub p_memsz: u64,
+    pub p_align: u64,
+}
+
+impl Elf64Phdr {
+    /// Bytes of the segment stored in the file, checked by [`Elf::parse`] for `PT_LOAD` segments.
+    pub fn file_range(&self) -> core::ops::Range<usize> {
+        self.p_offset as usize..(self.p_offset + self.p_filesz) as usize
+    }
+
+    /// Addresses the segment occupies once loaded, checked like [`Self::file_range`].
+    pub fn mem_range(&self) -> core::ops::Range<usize> {
+        self.p_vaddr as usize..(self.p_vaddr + self.p_memsz) as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    InvalidMagic,
+    NotElf64,
+    NotLittleEndian,
+    WrongMachine(u16),
+    NotExecutable(u16),
+    InvalidProgramHeader,
+    SegmentOutOfRange,
+}
+
+/// A statically linked riscv64 executable borrowed from memory.
+#[derive(Clone, Copy, Debug)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    header: Elf64Ehdr,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < core::mem::size_of::<Elf64Ehdr>() {
+            return Err(ElfError::TooShort);
+        }
+        let header = unsafe { data.as_ptr().cast::<Elf64Ehdr>().read_unaligned() };
+
+        if header.e_ident[..4] != ELF_MAGIC {
+            return Err(ElfError::InvalidMagic);
+        }
+        if header.e_ident[4] != ELFCLASS64 {
+            return Err(ElfError::NotElf64);
+        }
+        if header.e_ident[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if header.e_machine != EM_RISCV {
+            return Err(ElfError::WrongMachine(header.e_machine));
+        }
+        if header.e_type != ET_EXEC {
+            return Err(ElfError::NotExecutable(header.e_type));
+        }
+        if header.e_phentsize as usize != core::mem::size_of::<Elf64Phdr>() {
+            return Err(ElfError::InvalidProgramHeader);
+        }
+        let phdrs_end = (header.e_phoff as usize)
+            .checked_add(header.e_phnum as usize * core::mem::size_of::<Elf64Phdr>())
+            .ok_or(ElfError::InvalidProgramHeader)?;
+        if phdrs_end > data.len() {
+            return Err(ElfError::InvalidProgramHeader);
+        }
+
+        let elf = Self { data, header };
+        for phdr in elf.segments() {
+            // the ranges are only computed once these sums are known not to overflow
+            let file_end = phdr.p_offset.checked_add(phdr.p_filesz);
+            let mem_end = phdr.p_vaddr.checked_add(phdr.p_memsz);
+            if phdr.p_filesz > phdr.p_memsz
+                || mem_end.is_none()
+                || file_end.is_none_or(|end| end > data.len() as u64)
+            {
+                return Err(ElfError::SegmentOutOfRange);
+            }
+        }
+        Ok(elf)
+    }
+
+    pub fn header(&self) -> &Elf64Ehdr {
+        &self.header
+    }
+
+    pub fn entry(&self) -> usize {
+        self.header.e_entry as usize
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = Elf64Phdr> + 'a {
+        let data = self.data;
+        let phoff = self.header.e_phoff as usize;
+        (0..self.header.e_phnum as usize).map(move |i| unsafe {
+            data.as_ptr()
+                .add(phoff + i * core::mem::size_of::<Elf64Phdr>())
+                .cast::<Elf64Phdr>()
+                .read_unaligned()
+        })
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Elf64Phdr> + 'a {
+        self.program_headers().filter(|phdr| phdr.p_type == PT_LOAD)
+    }
+
+    /// Where the program headers end up in memory, if a loaded segment covers them.
+    pub fn phdr_addr(&self) -> Option<usize> {
+        if let Some(phdr) = self.program_headers().find(|phdr| phdr.p_type == PT_PHDR) {
+            return Some(phdr.p_vaddr as usize);
+        }
+        let phoff = self.header.e_phoff as usize;
+        self.segments()
+            .find(|phdr| phdr.file_range().contains(&phoff))
+            .map(|phdr| phdr.p_vaddr as usize + phoff - phdr.p_offset as usize)
+    }
+}
@@ -0,0 +1,171 @@
+//! `/dev`: the console, the framebuffer and a null device.
+
+use crate::{
+    alloc::{string::String, sync::Arc, vec::Vec},
+    dev::{tty, vga},
+    fs::{DirEntry, FileSystem, FsError, FsResult, Inode, Metadata, NodeKind},
+};
+
+/// The UART console, reads block for a whole line.
+pub struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::CharDevice,
+            size: 0,
+        }
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        tty::read(buf).map_err(|_| FsError::Interrupted)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> FsResult<usize> {
+        for chunk in buf.utf8_chunks() {
+            crate::print!("{}", chunk.valid());
+            if !chunk.invalid().is_empty() {
+                crate::print!("{}", char::REPLACEMENT_CHARACTER);
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+/// Raw pixels of the display, 4 bytes each in BGRX order.
+pub struct Framebuffer;
+
+impl Framebuffer {
+    fn bytes() -> &'static mut [u8] {
+        let fb = vga::framebuffer();
+        if fb.as_ptr().is_null() {
+            return &mut [];
+        }
+        unsafe { core::slice::from_raw_parts_mut(fb.as_ptr().cast(), fb.len() * 4) }
+    }
+}
+
+impl Inode for Framebuffer {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::File,
+            size: Self::bytes().len() as u64,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let bytes = Self::bytes();
+        let start = (offset as usize).min(bytes.len());
+        let len = buf.len().min(bytes.len() - start);
+        buf[..len].copy_from_slice(&bytes[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        let bytes = Self::bytes();
+        let start = (offset as usize).min(bytes.len());
+        let len = buf.len().min(bytes.len() - start);
+        if len == 0 && !buf.is_empty() {
+            return Err(FsError::NoSpace);
+        }
+        bytes[start..start + len].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Reads as empty, swallows writes.
+pub struct Null;
+
+impl Inode for Null {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::CharDevice,
+            size: 0,
+        }
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> FsResult<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> FsResult<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// A fixed directory of device nodes.
+pub struct DevFs {
+    nodes: Vec<(&'static str, Arc<dyn Inode>)>,
+}
+
+impl DevFs {
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::from([
+                ("console", Arc::new(Console) as Arc<dyn Inode>),
+                ("fb", Arc::new(Framebuffer)),
+                ("null", Arc::new(Null)),
+            ]),
+        }
+    }
+}
+
+impl Default for DevFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct Root(Arc<DevFs>);
+
+impl Inode for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            kind: NodeKind::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        self.0
+            .nodes
+            .iter()
+            .find(|(node, _)| *node == name)
+            .map(|(_, inode)| inode.clone())
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        Ok(self
+            .0
+            .nodes
+            .iter()
+            .map(|(name, inode)| {
+                let metadata = inode.metadata();
+                DirEntry {
+                    name: String::from(*name),
+                    kind: metadata.kind,
+                    size: metadata.size,
+                }
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn remove(&self, _name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root(self: Arc<Self>) -> Arc<dyn Inode> {
+        Arc::new(Root(self))
+    }
+}
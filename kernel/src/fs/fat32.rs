@@ -1 +1,1599 @@
+//! FAT32 on top of a [`BlockDevice`] with 512 byte sectors.
+//!
+//! Directories are read whole into memory, which keeps entry lookup and long
+//! file name reassembly simple at the cost of large directories.
 
+use crate::{
+    alloc::{
+        collections::BTreeMap,
+        string::String,
+        sync::{Arc, Weak},
+        vec,
+        vec::Vec,
+    },
+    dev::block::{BlockDevice, SECTOR_SIZE},
+    fs::{self as vfs, FileSystem, FsError, FsResult, Inode, Metadata, NodeKind},
+    sync::mutex::CriticalSpinLock,
+};
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FREE: u8 = 0xe5;
+const LFN_LAST: u8 = 0x40;
+/// UCS-2 characters held by one long name entry.
+const LFN_CHARS: usize = 13;
+const LFN_MAX: usize = 255;
+
+const FAT_MASK: u32 = 0x0fff_ffff;
+const FAT_FREE: u32 = 0;
+const FAT_BAD: u32 = 0x0fff_fff7;
+const FAT_EOC: u32 = 0x0fff_ffff;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUC_SIG: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIG: u32 = 0xaa55_0000;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// 1980-01-01, there is no clock to stamp entries with yet.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+/// NTRes bits Windows uses for names that are all lower case.
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+
+/// Where a file's short entry lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Location {
+    /// First cluster of the containing directory.
+    dir: u32,
+    /// Index of the short entry among the directory's 32 byte slots.
+    slot: u32,
+    /// Long name slots right in front of it.
+    lfn_slots: u32,
+}
+
+/// A file or directory, as found in its parent.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    short_name: [u8; 11],
+    pub attr: u8,
+    /// First data cluster, 0 while a file is empty.
+    pub cluster: u32,
+    pub size: u32,
+    /// `None` for the root directory, which has no entry.
+    location: Option<Location>,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.location.is_none()
+    }
+
+    fn is_dot(&self) -> bool {
+        self.short_name == *b".          " || self.short_name == *b"..         "
+    }
+}
+
+struct Alloc {
+    free_count: u32,
+    next_free: u32,
+}
+
+pub struct Fat32 {
+    dev: Arc<dyn BlockDevice>,
+    sectors_per_cluster: u32,
+    reserved_sectors: u32,
+    num_fats: u32,
+    fat_size: u32,
+    /// Only this FAT is in use when mirroring is disabled.
+    active_fat: Option<u32>,
+    root_cluster: u32,
+    fsinfo_sector: Option<u32>,
+    first_data_sector: u32,
+    cluster_count: u32,
+    alloc: CriticalSpinLock<Alloc>,
+    /// Serializes every operation that changes the volume.
+    write_lock: CriticalSpinLock<()>,
+    /// Open nodes by the position of their entry, so every user of a file sees the same size.
+    nodes: CriticalSpinLock<BTreeMap<(u32, u32), Weak<Fat32Inode>>>,
+}
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn put16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+impl Fat32 {
+    /// Reads the BPB and FSInfo sector of the volume on `dev`.
+    pub fn mount(dev: Arc<dyn BlockDevice>) -> FsResult<Self> {
+        let mut bpb = [0u8; SECTOR_SIZE];
+        dev.read(0, &mut bpb)?;
+
+        if bpb[510] != 0x55 || bpb[511] != 0xaa {
+            return Err(FsError::BadFormat);
+        }
+        let bytes_per_sector = le16(&bpb, 11) as usize;
+        let sectors_per_cluster = bpb[13] as u32;
+        let reserved_sectors = le16(&bpb, 14) as u32;
+        let num_fats = bpb[16] as u32;
+        let root_entry_count = le16(&bpb, 17);
+        let total_sectors = match le16(&bpb, 19) {
+            0 => le32(&bpb, 32),
+            small => small as u32,
+        };
+        let fat_size_16 = le16(&bpb, 22);
+        let fat_size = le32(&bpb, 36);
+        let ext_flags = le16(&bpb, 40);
+        let root_cluster = le32(&bpb, 44);
+        let fsinfo_sector = le16(&bpb, 48) as u32;
+
+        if bytes_per_sector != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || num_fats == 0
+            || root_entry_count != 0
+            || fat_size_16 != 0
+            || fat_size == 0
+        {
+            return Err(FsError::BadFormat);
+        }
+
+        let first_data_sector = num_fats
+            .checked_mul(fat_size)
+            .and_then(|fats| fats.checked_add(reserved_sectors))
+            .ok_or(FsError::BadFormat)?;
+        if total_sectors <= first_data_sector || total_sectors as u64 > dev.sector_count() {
+            return Err(FsError::BadFormat);
+        }
+        // the FAT must have an entry for every cluster
+        let fat_entries = fat_size
+            .checked_mul(SECTOR_SIZE as u32 / 4)
+            .ok_or(FsError::BadFormat)?;
+        let cluster_count =
+            ((total_sectors - first_data_sector) / sectors_per_cluster).min(fat_entries - 2);
+        if root_cluster < 2 || root_cluster >= cluster_count + 2 {
+            return Err(FsError::BadFormat);
+        }
+        // with mirroring off, the low bits pick the one FAT in use
+        let active_fat = (ext_flags & 0x80 != 0).then_some((ext_flags & 0xf) as u32);
+        if active_fat.is_some_and(|fat| fat >= num_fats) {
+            return Err(FsError::BadFormat);
+        }
+
+        let mut fs = Self {
+            dev,
+            sectors_per_cluster,
+            reserved_sectors,
+            num_fats,
+            fat_size,
+            active_fat,
+            root_cluster,
+            fsinfo_sector: None,
+            first_data_sector,
+            cluster_count,
+            alloc: CriticalSpinLock::new(Alloc {
+                free_count: FSINFO_UNKNOWN,
+                next_free: 2,
+            }),
+            write_lock: CriticalSpinLock::new(()),
+            nodes: CriticalSpinLock::new(BTreeMap::new()),
+        };
+
+        if fsinfo_sector != 0 && fsinfo_sector != 0xffff && fsinfo_sector < reserved_sectors {
+            let mut info = [0u8; SECTOR_SIZE];
+            fs.dev.read(fsinfo_sector as u64, &mut info)?;
+            if le32(&info, 0) == FSINFO_LEAD_SIG
+                && le32(&info, 484) == FSINFO_STRUC_SIG
+                && le32(&info, 508) == FSINFO_TRAIL_SIG
+            {
+                fs.fsinfo_sector = Some(fsinfo_sector);
+                fs.alloc = CriticalSpinLock::new(Alloc {
+                    free_count: le32(&info, 488),
+                    next_free: le32(&info, 492),
+                });
+            }
+        }
+
+        // the FSInfo values are only hints, recount when they can't be right
+        let (free_count, next_free) = {
+            let alloc = fs.alloc.lock();
+            (alloc.free_count, alloc.next_free)
+        };
+        let free_count = if free_count > fs.cluster_count {
+            fs.count_free()?
+        } else {
+            free_count
+        };
+        let next_free = if fs.valid_cluster(next_free) {
+            next_free
+        } else {
+            2
+        };
+        fs.alloc = CriticalSpinLock::new(Alloc {
+            free_count,
+            next_free,
+        });
+
+        Ok(fs)
+    }
+
+    pub fn device(&self) -> &Arc<dyn BlockDevice> {
+        &self.dev
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.dev.read_only()
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    pub fn free_clusters(&self) -> u32 {
+        self.alloc.lock().free_count
+    }
+
+    pub fn total_clusters(&self) -> u32 {
+        self.cluster_count
+    }
+
+    pub fn root_entry(&self) -> DirEntry {
+        DirEntry {
+            name: String::from("/"),
+            short_name: [b' '; 11],
+            attr: ATTR_DIRECTORY,
+            cluster: self.root_cluster,
+            size: 0,
+            location: None,
+        }
+    }
+
+    fn check_writable(&self) -> FsResult<()> {
+        if self.read_only() {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.first_data_sector as u64 + (cluster as u64 - 2) * self.sectors_per_cluster as u64
+    }
+
+    fn read_cluster(&self, cluster: u32, buf: &mut [u8]) -> FsResult<()> {
+        Ok(self.dev.read(self.cluster_sector(cluster), buf)?)
+    }
+
+    fn write_cluster(&self, cluster: u32, buf: &[u8]) -> FsResult<()> {
+        Ok(self.dev.write(self.cluster_sector(cluster), buf)?)
+    }
+
+    // ---------------------------------------------------------------------
+    // FAT
+    // ---------------------------------------------------------------------
+
+    fn fat_position(&self, cluster: u32) -> (u32, usize) {
+        let offset = cluster as usize * 4;
+        (
+            self.reserved_sectors + (offset / SECTOR_SIZE) as u32,
+            offset % SECTOR_SIZE,
+        )
+    }
+
+    fn fat_entry(&self, cluster: u32) -> FsResult<u32> {
+        let (sector, offset) = self.fat_position(cluster);
+        let sector = sector + self.active_fat.unwrap_or(0) * self.fat_size;
+        let mut buf = [0u8; SECTOR_SIZE];
+        self.dev.read(sector as u64, &mut buf)?;
+        Ok(le32(&buf, offset) & FAT_MASK)
+    }
+
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> FsResult<()> {
+        let (sector, offset) = self.fat_position(cluster);
+        let mut buf = [0u8; SECTOR_SIZE];
+        for fat in 0..self.num_fats {
+            if self.active_fat.is_some_and(|active| active != fat) {
+                continue;
+            }
+            let sector = (sector + fat * self.fat_size) as u64;
+            self.dev.read(sector, &mut buf)?;
+            // the top four bits are reserved and must be kept
+            let old = le32(&buf, offset);
+            put32(&mut buf, offset, (old & !FAT_MASK) | (value & FAT_MASK));
+            self.dev.write(sector, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster` in its chain, `None` at the end.
+    fn next_cluster(&self, cluster: u32) -> FsResult<Option<u32>> {
+        match self.fat_entry(cluster)? {
+            next if next > FAT_BAD => Ok(None),
+            next if self.valid_cluster(next) => Ok(Some(next)),
+            _ => Err(FsError::Corrupt),
+        }
+    }
+
+    /// Every cluster of the chain starting at `first`, which may be 0 for an empty file.
+    fn chain(&self, first: u32) -> FsResult<Vec<u32>> {
+        let mut chain = Vec::new();
+        if first == 0 {
+            return Ok(chain);
+        }
+        if !self.valid_cluster(first) {
+            return Err(FsError::Corrupt);
+        }
+        let mut cluster = Some(first);
+        while let Some(current) = cluster {
+            if chain.len() > self.cluster_count as usize {
+                // looped back on itself
+                return Err(FsError::Corrupt);
+            }
+            chain.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(chain)
+    }
+
+    fn count_free(&self) -> FsResult<u32> {
+        let mut free = 0;
+        let mut buf = [0u8; SECTOR_SIZE];
+        let per_sector = (SECTOR_SIZE / 4) as u32;
+        let first_fat = self.reserved_sectors + self.active_fat.unwrap_or(0) * self.fat_size;
+        for cluster in 2..self.cluster_count + 2 {
+            if cluster == 2 || cluster % per_sector == 0 {
+                self.dev
+                    .read((first_fat + cluster / per_sector) as u64, &mut buf)?;
+            }
+            if le32(&buf, (cluster % per_sector) as usize * 4) & FAT_MASK == FAT_FREE {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    /// Takes a free cluster, zeroes it and links it after `prev`.
+    fn alloc_cluster(&self, prev: Option<u32>) -> FsResult<u32> {
+        let mut alloc = self.alloc.lock();
+        if alloc.free_count == 0 {
+            return Err(FsError::NoSpace);
+        }
+        let start = alloc.next_free;
+        let mut cluster = start;
+        loop {
+            if self.fat_entry(cluster)? == FAT_FREE {
+                break;
+            }
+            cluster += 1;
+            if cluster >= self.cluster_count + 2 {
+                cluster = 2;
+            }
+            if cluster == start {
+                alloc.free_count = 0;
+                return Err(FsError::NoSpace);
+            }
+        }
+
+        self.set_fat_entry(cluster, FAT_EOC)?;
+        alloc.free_count = alloc.free_count.saturating_sub(1);
+        alloc.next_free = if cluster + 1 >= self.cluster_count + 2 {
+            2
+        } else {
+            cluster + 1
+        };
+        drop(alloc);
+
+        self.write_cluster(cluster, &vec![0u8; self.cluster_size()])?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster)?;
+        }
+        Ok(cluster)
+    }
+
+    fn free_chain(&self, clusters: &[u32]) -> FsResult<()> {
+        for &cluster in clusters {
+            self.set_fat_entry(cluster, FAT_FREE)?;
+        }
+        let mut alloc = self.alloc.lock();
+        alloc.free_count = (alloc.free_count + clusters.len() as u32).min(self.cluster_count);
+        if let Some(&first) = clusters.iter().min() {
+            alloc.next_free = alloc.next_free.min(first);
+        }
+        Ok(())
+    }
+
+    /// Writes the free cluster count and allocation hint back to the FSInfo sector
+    /// and flushes the device, until then changes may only be in the block cache.
+    pub fn sync(&self) -> FsResult<()> {
+        if self.read_only() {
+            return Ok(());
+        }
+        if let Some(sector) = self.fsinfo_sector {
+            let mut info = [0u8; SECTOR_SIZE];
+            self.dev.read(sector as u64, &mut info)?;
+            let alloc = self.alloc.lock();
+            put32(&mut info, 488, alloc.free_count);
+            put32(&mut info, 492, alloc.next_free);
+            drop(alloc);
+            self.dev.write(sector as u64, &info)?;
+        }
+        Ok(self.dev.flush()?)
+    }
+
+    // ---------------------------------------------------------------------
+    // directories
+    // ---------------------------------------------------------------------
+
+    /// The whole directory starting at `cluster`, with the chain it was read from.
+    fn read_dir_raw(&self, cluster: u32) -> FsResult<(Vec<u32>, Vec<u8>)> {
+        let chain = self.chain(cluster)?;
+        let size = self.cluster_size();
+        let mut data = vec![0u8; chain.len() * size];
+        for (i, &cluster) in chain.iter().enumerate() {
+            self.read_cluster(cluster, &mut data[i * size..(i + 1) * size])?;
+        }
+        Ok((chain, data))
+    }
+
+    /// Overwrites directory slots starting at `slot`, which must already exist.
+    fn write_slots(&self, chain: &[u32], slot: u32, entries: &[[u8; ENTRY_SIZE]]) -> FsResult<()> {
+        let per_cluster = (self.cluster_size() / ENTRY_SIZE) as u32;
+        let per_sector = (SECTOR_SIZE / ENTRY_SIZE) as u32;
+        let mut buf = [0u8; SECTOR_SIZE];
+        let mut loaded = None;
+
+        for (i, entry) in entries.iter().enumerate() {
+            let slot = slot + i as u32;
+            let cluster = *chain
+                .get((slot / per_cluster) as usize)
+                .ok_or(FsError::Corrupt)?;
+            let within = slot % per_cluster;
+            let sector = self.cluster_sector(cluster) + (within / per_sector) as u64;
+
+            if loaded != Some(sector) {
+                if let Some(previous) = loaded {
+                    self.dev.write(previous, &buf)?;
+                }
+                self.dev.read(sector, &mut buf)?;
+                loaded = Some(sector);
+            }
+            let offset = (within % per_sector) as usize * ENTRY_SIZE;
+            buf[offset..offset + ENTRY_SIZE].copy_from_slice(entry);
+        }
+        if let Some(sector) = loaded {
+            self.dev.write(sector, &buf)?;
+        }
+        Ok(())
+    }
+
+    /// Every entry of the directory starting at `cluster`, including `.` and `..`.
+    fn parse_dir(&self, cluster: u32, data: &[u8]) -> Vec<DirEntry> {
+        let mut entries = Vec::new();
+        let mut lfn = [0u16; LFN_MAX + LFN_CHARS];
+        let mut lfn_count = 0;
+        let mut lfn_next = 0;
+        let mut lfn_checksum = 0;
+        let mut lfn_start = 0;
+
+        for (slot, raw) in data.as_chunks::<ENTRY_SIZE>().0.iter().enumerate() {
+            let slot = slot as u32;
+            match raw[0] {
+                ENTRY_END => break,
+                ENTRY_FREE => {
+                    lfn_count = 0;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let attr = raw[11];
+            if attr & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                let ord = raw[0];
+                let index = (ord & 0x1f) as usize;
+                if ord & LFN_LAST != 0 {
+                    lfn_count = index;
+                    lfn_next = index;
+                    lfn_checksum = raw[13];
+                    lfn_start = slot;
+                    lfn.fill(0xffff);
+                }
+                if index == 0
+                    || index != lfn_next
+                    || raw[13] != lfn_checksum
+                    || index * LFN_CHARS > lfn.len()
+                {
+                    lfn_count = 0;
+                    continue;
+                }
+                let chars = &mut lfn[(index - 1) * LFN_CHARS..index * LFN_CHARS];
+                for (i, char) in chars.iter_mut().enumerate() {
+                    let offset = match i {
+                        0..5 => 1 + i * 2,
+                        5..11 => 14 + (i - 5) * 2,
+                        _ => 28 + (i - 11) * 2,
+                    };
+                    *char = le16(raw, offset);
+                }
+                lfn_next -= 1;
+                continue;
+            }
+
+            if attr & ATTR_VOLUME_ID != 0 {
+                lfn_count = 0;
+                continue;
+            }
+
+            let short_name: [u8; 11] = raw[..11].try_into().unwrap();
+            let long = lfn_count != 0 && lfn_next == 0 && checksum(&short_name) == lfn_checksum;
+            let name = if long {
+                let len = lfn[..lfn_count * LFN_CHARS]
+                    .iter()
+                    .position(|&c| c == 0 || c == 0xffff)
+                    .unwrap_or(lfn_count * LFN_CHARS);
+                char::decode_utf16(lfn[..len].iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            } else {
+                display_short_name(&short_name, raw[12])
+            };
+
+            let cluster_hi = le16(raw, 20) as u32;
+            let cluster_lo = le16(raw, 26) as u32;
+            entries.push(DirEntry {
+                name,
+                short_name,
+                attr,
+                cluster: (cluster_hi << 16) | cluster_lo,
+                size: le32(raw, 28),
+                location: Some(Location {
+                    dir: cluster,
+                    slot,
+                    lfn_slots: if long { slot - lfn_start } else { 0 },
+                }),
+            });
+            lfn_count = 0;
+        }
+        entries
+    }
+
+    /// Lists `dir`, without `.` and `..`.
+    pub fn read_dir(&self, dir: &DirEntry) -> FsResult<Vec<DirEntry>> {
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let (_, data) = self.read_dir_raw(dir.cluster)?;
+        let mut entries = self.parse_dir(dir.cluster, &data);
+        entries.retain(|entry| !entry.is_dot());
+        Ok(entries)
+    }
+
+    /// Finds `name` in `dir`, ignoring case like FAT does.
+    pub fn find(&self, dir: &DirEntry, name: &str) -> FsResult<DirEntry> {
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let (_, data) = self.read_dir_raw(dir.cluster)?;
+        self.parse_dir(dir.cluster, &data)
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name))
+            .map(|mut entry| {
+                // `..` of a directory in the root points at cluster 0
+                if entry.is_dot() && entry.cluster == 0 {
+                    entry = self.root_entry();
+                }
+                entry
+            })
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Resolves an absolute or root relative `/` separated path.
+    pub fn lookup(&self, path: &str) -> FsResult<DirEntry> {
+        let mut entry = self.root_entry();
+        for part in path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+        {
+            entry = self.find(&entry, part)?;
+        }
+        Ok(entry)
+    }
+
+    /// Writes the size, first cluster and attributes of `entry` back to its directory.
+    fn store_entry(&self, entry: &DirEntry) -> FsResult<()> {
+        let Some(location) = entry.location else {
+            return Ok(());
+        };
+        let (chain, data) = self.read_dir_raw(location.dir)?;
+        let offset = location.slot as usize * ENTRY_SIZE;
+        let mut raw: [u8; ENTRY_SIZE] = data
+            .get(offset..offset + ENTRY_SIZE)
+            .ok_or(FsError::Corrupt)?
+            .try_into()
+            .unwrap();
+        raw[11] = entry.attr;
+        put16(&mut raw, 20, (entry.cluster >> 16) as u16);
+        put16(&mut raw, 22, 0);
+        put16(&mut raw, 24, DEFAULT_DATE);
+        put16(&mut raw, 26, entry.cluster as u16);
+        put32(&mut raw, 28, if entry.is_dir() { 0 } else { entry.size });
+        self.write_slots(&chain, location.slot, &[raw])
+    }
+
+    /// Adds `name` to `dir` as an empty file or, with `ATTR_DIRECTORY`, a directory holding `.` and `..`.
+    pub fn create(&self, dir: &DirEntry, name: &str, attr: u8) -> FsResult<DirEntry> {
+        self.check_writable()?;
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        validate_name(name)?;
+
+        let _guard = self.write_lock.lock();
+        let (mut chain, data) = self.read_dir_raw(dir.cluster)?;
+        let existing = self.parse_dir(dir.cluster, &data);
+        if existing
+            .iter()
+            .any(|entry| entry.name.eq_ignore_ascii_case(name))
+        {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short_name, ntres, needs_lfn) = short_name_for(name, |candidate| {
+            existing.iter().any(|entry| entry.short_name == *candidate)
+        })
+        .ok_or(FsError::InvalidName)?;
+
+        let mut slots = Vec::new();
+        if needs_lfn {
+            let units: Vec<u16> = name.encode_utf16().collect();
+            let count = units.len().div_ceil(LFN_CHARS);
+            let sum = checksum(&short_name);
+            for index in (1..=count).rev() {
+                let mut raw = [0u8; ENTRY_SIZE];
+                raw[0] = index as u8 | if index == count { LFN_LAST } else { 0 };
+                raw[11] = ATTR_LONG_NAME;
+                raw[13] = sum;
+                for i in 0..LFN_CHARS {
+                    let at = (index - 1) * LFN_CHARS + i;
+                    let unit = match at.cmp(&units.len()) {
+                        core::cmp::Ordering::Less => units[at],
+                        core::cmp::Ordering::Equal => 0,
+                        core::cmp::Ordering::Greater => 0xffff,
+                    };
+                    let offset = match i {
+                        0..5 => 1 + i * 2,
+                        5..11 => 14 + (i - 5) * 2,
+                        _ => 28 + (i - 11) * 2,
+                    };
+                    put16(&mut raw, offset, unit);
+                }
+                slots.push(raw);
+            }
+        }
+
+        let cluster = if attr & ATTR_DIRECTORY != 0 {
+            let cluster = self.alloc_cluster(None)?;
+            let parent = if dir.is_root() { 0 } else { dir.cluster };
+            let mut dots = vec![0u8; self.cluster_size()];
+            dots[..ENTRY_SIZE].copy_from_slice(&short_entry(
+                *b".          ",
+                ATTR_DIRECTORY,
+                0,
+                cluster,
+                0,
+            ));
+            dots[ENTRY_SIZE..2 * ENTRY_SIZE].copy_from_slice(&short_entry(
+                *b"..         ",
+                ATTR_DIRECTORY,
+                0,
+                parent,
+                0,
+            ));
+            self.write_cluster(cluster, &dots)?;
+            cluster
+        } else {
+            0
+        };
+        slots.push(short_entry(short_name, attr, ntres, cluster, 0));
+
+        // find room for every slot in a row, growing the directory if needed
+        let total = (data.len() / ENTRY_SIZE) as u32;
+        let mut run_start = 0;
+        let mut run = 0;
+        let mut found = None;
+        for (slot, raw) in data.as_chunks::<ENTRY_SIZE>().0.iter().enumerate() {
+            if raw[0] == ENTRY_FREE || raw[0] == ENTRY_END {
+                if run == 0 {
+                    run_start = slot as u32;
+                }
+                run += 1;
+                if run == slots.len() {
+                    found = Some(run_start);
+                    break;
+                }
+            } else {
+                run = 0;
+            }
+        }
+        let start = match found {
+            Some(start) => start,
+            None => {
+                let start = if run == 0 { total } else { run_start };
+                let per_cluster = self.cluster_size() / ENTRY_SIZE;
+                let missing = slots.len() - run;
+                for _ in 0..missing.div_ceil(per_cluster) {
+                    let last = *chain.last().ok_or(FsError::Corrupt)?;
+                    chain.push(self.alloc_cluster(Some(last))?);
+                }
+                start
+            }
+        };
+        self.write_slots(&chain, start, &slots)?;
+
+        Ok(DirEntry {
+            name: String::from(name),
+            short_name,
+            attr,
+            cluster,
+            size: 0,
+            location: Some(Location {
+                dir: dir.cluster,
+                slot: start + slots.len() as u32 - 1,
+                lfn_slots: slots.len() as u32 - 1,
+            }),
+        })
+    }
+
+    /// Deletes `entry` and frees its clusters, directories only when empty.
+    pub fn remove(&self, entry: &DirEntry) -> FsResult<()> {
+        self.check_writable()?;
+        let Some(location) = entry.location else {
+            return Err(FsError::InvalidName);
+        };
+        if entry.is_dot() {
+            return Err(FsError::InvalidName);
+        }
+        if entry.is_dir() && !self.read_dir(entry)?.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        let _guard = self.write_lock.lock();
+        let (chain, data) = self.read_dir_raw(location.dir)?;
+        let first = location.slot - location.lfn_slots;
+        let mut slots = Vec::new();
+        for slot in first..=location.slot {
+            let offset = slot as usize * ENTRY_SIZE;
+            let mut raw: [u8; ENTRY_SIZE] = data
+                .get(offset..offset + ENTRY_SIZE)
+                .ok_or(FsError::Corrupt)?
+                .try_into()
+                .unwrap();
+            raw[0] = ENTRY_FREE;
+            slots.push(raw);
+        }
+        self.write_slots(&chain, first, &slots)?;
+        self.free_chain(&self.chain(entry.cluster)?)
+    }
+
+    // ---------------------------------------------------------------------
+    // file data
+    // ---------------------------------------------------------------------
+
+    /// Reads from `offset` into `buf`, returning how many bytes were left in the file to read.
+    pub fn read(&self, file: &DirEntry, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        if file.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        if offset >= file.size as u64 {
+            return Ok(0);
+        }
+        let len = buf.len().min((file.size as u64 - offset) as usize);
+        let size = self.cluster_size();
+        let chain = self.chain(file.cluster)?;
+        let mut cluster_buf = vec![0u8; size];
+
+        let mut done = 0;
+        while done < len {
+            let position = offset as usize + done;
+            let cluster = *chain.get(position / size).ok_or(FsError::Corrupt)?;
+            let within = position % size;
+            let count = (size - within).min(len - done);
+            if within == 0 && count == size {
+                self.read_cluster(cluster, &mut buf[done..done + size])?;
+            } else {
+                self.read_cluster(cluster, &mut cluster_buf)?;
+                buf[done..done + count].copy_from_slice(&cluster_buf[within..within + count]);
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    /// Writes `data` at `offset`, growing the file as needed.
+    pub fn write(&self, file: &mut DirEntry, offset: u64, data: &[u8]) -> FsResult<usize> {
+        self.check_writable()?;
+        if file.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= u32::MAX as u64)
+            .ok_or(FsError::FileTooLarge)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let _guard = self.write_lock.lock();
+        let old_size = file.size as u64;
+        let size = self.cluster_size();
+        let chain = self.grow_chain(file, (end as usize).div_ceil(size))?;
+
+        // bytes between the old end and `offset` must read back as zero
+        if offset > old_size {
+            self.zero_range(&chain, old_size, offset)?;
+        }
+
+        let mut cluster_buf = vec![0u8; size];
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset as usize + done;
+            let cluster = chain[position / size];
+            let within = position % size;
+            let count = (size - within).min(data.len() - done);
+            if within == 0 && count == size {
+                self.write_cluster(cluster, &data[done..done + size])?;
+            } else {
+                self.read_cluster(cluster, &mut cluster_buf)?;
+                cluster_buf[within..within + count].copy_from_slice(&data[done..done + count]);
+                self.write_cluster(cluster, &cluster_buf)?;
+            }
+            done += count;
+        }
+
+        file.size = file.size.max(end as u32);
+        file.attr |= ATTR_ARCHIVE;
+        self.store_entry(file)?;
+        Ok(data.len())
+    }
+
+    /// Grows or shrinks `file` to `size` bytes, new bytes read as zero.
+    pub fn truncate(&self, file: &mut DirEntry, size: u32) -> FsResult<()> {
+        self.check_writable()?;
+        if file.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        let _guard = self.write_lock.lock();
+        let cluster_size = self.cluster_size();
+        let keep = (size as usize).div_ceil(cluster_size);
+
+        if size > file.size {
+            let chain = self.grow_chain(file, keep)?;
+            self.zero_range(&chain, file.size as u64, size as u64)?;
+        } else {
+            let chain = self.chain(file.cluster)?;
+            if keep < chain.len() {
+                if keep == 0 {
+                    file.cluster = 0;
+                } else {
+                    self.set_fat_entry(chain[keep - 1], FAT_EOC)?;
+                }
+                self.free_chain(&chain[keep..])?;
+            }
+        }
+
+        file.size = size;
+        self.store_entry(file)
+    }
+
+    /// Extends the chain of `file` to at least `clusters`, returning the whole chain.
+    fn grow_chain(&self, file: &mut DirEntry, clusters: usize) -> FsResult<Vec<u32>> {
+        let mut chain = self.chain(file.cluster)?;
+        while chain.len() < clusters {
+            let cluster = self.alloc_cluster(chain.last().copied())?;
+            if chain.is_empty() {
+                file.cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+        Ok(chain)
+    }
+
+    fn zero_range(&self, chain: &[u32], start: u64, end: u64) -> FsResult<()> {
+        let size = self.cluster_size();
+        let mut cluster_buf = vec![0u8; size];
+        let mut position = start as usize;
+        while position < end as usize {
+            let cluster = chain[position / size];
+            let within = position % size;
+            let count = (size - within).min(end as usize - position);
+            self.read_cluster(cluster, &mut cluster_buf)?;
+            cluster_buf[within..within + count].fill(0);
+            self.write_cluster(cluster, &cluster_buf)?;
+            position += count;
+        }
+        Ok(())
+    }
+}
+
+/// Checksum of a short name stored in each of its long name entries.
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &c| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c)
+    })
+}
+
+fn display_short_name(short_name: &[u8; 11], ntres: u8) -> String {
+    let mut name = String::new();
+    let base = &short_name[..8];
+    let ext = &short_name[8..];
+    let push = |name: &mut String, c: u8, lower: bool| {
+        name.push(if lower { c.to_ascii_lowercase() } else { c } as char)
+    };
+
+    // 0x05 stands in for a leading 0xe5, which would mark the entry free
+    for (i, &c) in base.iter().enumerate().filter(|(_, c)| **c != b' ') {
+        let c = if i == 0 && c == 0x05 { ENTRY_FREE } else { c };
+        push(&mut name, c, ntres & NTRES_LOWER_BASE != 0);
+    }
+    if ext.iter().any(|&c| c != b' ') {
+        name.push('.');
+        for &c in ext.iter().filter(|c| **c != b' ') {
+            push(&mut name, c, ntres & NTRES_LOWER_EXT != 0);
+        }
+    }
+    name
+}
+
+fn validate_name(name: &str) -> FsResult<()> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > LFN_MAX
+        || name.ends_with(' ')
+        || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+    {
+        return Err(FsError::InvalidName);
+    }
+    Ok(())
+}
+
+fn short_char(c: char) -> Option<u8> {
+    match c {
+        'A'..='Z' | '0'..='9' => Some(c as u8),
+        'a'..='z' => Some(c.to_ascii_uppercase() as u8),
+        '$' | '%' | '\'' | '-' | '_' | '@' | '~' | '`' | '!' | '(' | ')' | '{' | '}' | '^'
+        | '#' | '&' => Some(c as u8),
+        _ => None,
+    }
+}
+
+/// Picks the 8.3 name for `name`, returning it with its NTRes case bits and
+/// whether long name entries are needed to keep the real name.
+fn short_name_for(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> Option<([u8; 11], u8, bool)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(0) | None => (name, ""),
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+    };
+
+    // names that fit 8.3 in a single case are stored as is
+    let fits = |part: &str, max: usize| {
+        !part.is_empty() && part.len() <= max && part.chars().all(|c| short_char(c).is_some())
+    };
+    let single_case = |part: &str| {
+        if part.chars().all(|c| !c.is_ascii_lowercase()) {
+            Some(false)
+        } else if part.chars().all(|c| !c.is_ascii_uppercase()) {
+            Some(true)
+        } else {
+            None
+        }
+    };
+    if fits(base, 8)
+        && (ext.is_empty() || fits(ext, 3))
+        && let (Some(lower_base), Some(lower_ext)) = (single_case(base), single_case(ext))
+    {
+        let mut short = [b' '; 11];
+        for (slot, c) in short.iter_mut().zip(base.chars()) {
+            *slot = short_char(c)?;
+        }
+        for (slot, c) in short[8..].iter_mut().zip(ext.chars()) {
+            *slot = short_char(c)?;
+        }
+        let ntres = if lower_base { NTRES_LOWER_BASE } else { 0 }
+            | if lower_ext { NTRES_LOWER_EXT } else { 0 };
+        if !taken(&short) {
+            return Some((short, ntres, false));
+        }
+    }
+
+    // otherwise a unique "BASIS~N.EXT" alias plus the long name
+    let basis: Vec<u8> = base
+        .chars()
+        .filter(|c| *c != ' ' && *c != '.')
+        .map(|c| short_char(c).unwrap_or(b'_'))
+        .collect();
+    let ext: Vec<u8> = ext
+        .chars()
+        .filter(|c| *c != ' ')
+        .map(|c| short_char(c).unwrap_or(b'_'))
+        .take(3)
+        .collect();
+    for n in 1..1_000_000u32 {
+        let mut digits = [0u8; 7];
+        let mut start = digits.len();
+        let mut rest = n;
+        while rest > 0 {
+            start -= 1;
+            digits[start] = b'0' + (rest % 10) as u8;
+            rest /= 10;
+        }
+        let digits = &digits[start..];
+
+        let mut short = [b' '; 11];
+        let keep = basis.len().min(8 - 1 - digits.len());
+        short[..keep].copy_from_slice(&basis[..keep]);
+        short[keep] = b'~';
+        short[keep + 1..keep + 1 + digits.len()].copy_from_slice(digits);
+        short[8..8 + ext.len()].copy_from_slice(&ext);
+        if !taken(&short) {
+            return Some((short, 0, true));
+        }
+    }
+    None
+}
+
+fn short_entry(name: [u8; 11], attr: u8, ntres: u8, cluster: u32, size: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[..11].copy_from_slice(&name);
+    raw[11] = attr;
+    raw[12] = ntres;
+    put16(&mut raw, 16, DEFAULT_DATE);
+    put16(&mut raw, 18, DEFAULT_DATE);
+    put16(&mut raw, 20, (cluster >> 16) as u16);
+    put16(&mut raw, 24, DEFAULT_DATE);
+    put16(&mut raw, 26, cluster as u16);
+    put32(&mut raw, 28, size);
+    raw
+}
+
+/// A FAT32 file or directory handed out through the VFS.
+pub struct Fat32Inode {
+    fs: Arc<Fat32>,
+    entry: CriticalSpinLock<DirEntry>,
+}
+
+impl Fat32 {
+    /// The node for `entry`, shared with everyone else who has it open.
+    fn node(self: &Arc<Self>, entry: DirEntry) -> Arc<dyn Inode> {
+        let Some(location) = entry.location else {
+            return Arc::new(Fat32Inode {
+                fs: self.clone(),
+                entry: CriticalSpinLock::new(entry),
+            });
+        };
+        let key = (location.dir, location.slot);
+        let mut nodes = self.nodes.lock();
+        if let Some(node) = nodes.get(&key).and_then(Weak::upgrade) {
+            return node;
+        }
+        nodes.retain(|_, node| node.strong_count() > 0);
+        let node = Arc::new(Fat32Inode {
+            fs: self.clone(),
+            entry: CriticalSpinLock::new(entry),
+        });
+        nodes.insert(key, Arc::downgrade(&node));
+        node
+    }
+}
+
+impl Inode for Fat32Inode {
+    fn metadata(&self) -> Metadata {
+        let entry = self.entry.lock();
+        Metadata {
+            kind: if entry.is_dir() {
+                NodeKind::Directory
+            } else {
+                NodeKind::File
+            },
+            size: entry.size as u64,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        self.fs.read(&self.entry.lock(), offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> FsResult<usize> {
+        self.fs.write(&mut self.entry.lock(), offset, buf)
+    }
+
+    fn truncate(&self, size: u64) -> FsResult<()> {
+        let size = u32::try_from(size).map_err(|_| FsError::FileTooLarge)?;
+        self.fs.truncate(&mut self.entry.lock(), size)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let entry = self.fs.find(&self.entry.lock(), name)?;
+        Ok(self.fs.node(entry))
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<vfs::DirEntry>> {
+        let entries = self.fs.read_dir(&self.entry.lock())?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| vfs::DirEntry {
+                kind: if entry.is_dir() {
+                    NodeKind::Directory
+                } else {
+                    NodeKind::File
+                },
+                size: entry.size as u64,
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: NodeKind) -> FsResult<Arc<dyn Inode>> {
+        let attr = match kind {
+            NodeKind::File => ATTR_ARCHIVE,
+            NodeKind::Directory => ATTR_DIRECTORY,
+            NodeKind::CharDevice => return Err(FsError::Unsupported),
+        };
+        let entry = self.fs.create(&self.entry.lock(), name, attr)?;
+        Ok(self.fs.node(entry))
+    }
+
+    fn remove(&self, name: &str) -> FsResult<()> {
+        let entry = self.fs.find(&self.entry.lock(), name)?;
+        let Some(location) = entry.location else {
+            return self.fs.remove(&entry);
+        };
+        // an open node would keep writing to the freed entry and clusters once they are reused,
+        // held until the entry is gone so a lookup can't open it in between
+        let mut nodes = self.fs.nodes.lock();
+        let key = (location.dir, location.slot);
+        if nodes.get(&key).is_some_and(|node| node.strong_count() > 0) {
+            return Err(FsError::Busy);
+        }
+        nodes.remove(&key);
+        self.fs.remove(&entry)
+    }
+}
+
+impl FileSystem for Fat32 {
+    fn name(&self) -> &str {
+        "fat32"
+    }
+
+    fn root(self: Arc<Self>) -> Arc<dyn Inode> {
+        let root = self.root_entry();
+        self.node(root)
+    }
+
+    fn sync(&self) -> FsResult<()> {
+        Fat32::sync(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{alloc::format, dev::block::BlockError};
+
+    const SECTORS: u32 = 4096;
+    const RESERVED: u32 = 32;
+    const FSINFO: u64 = 1;
+
+    struct RamDisk {
+        data: CriticalSpinLock<Vec<u8>>,
+    }
+
+    impl RamDisk {
+        fn new(image: Vec<u8>) -> Arc<Self> {
+            Arc::new(Self {
+                data: CriticalSpinLock::new(image),
+            })
+        }
+
+        fn sector(&self, sector: u64) -> [u8; SECTOR_SIZE] {
+            let mut buf = [0u8; SECTOR_SIZE];
+            self.read(sector, &mut buf).unwrap();
+            buf
+        }
+    }
+
+    impl BlockDevice for RamDisk {
+        fn name(&self) -> &str {
+            "ram"
+        }
+
+        fn sector_count(&self) -> u64 {
+            (self.data.lock().len() / SECTOR_SIZE) as u64
+        }
+
+        fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+            crate::dev::block::check_access(self.sector_count(), sector, buf.len())?;
+            let start = sector as usize * SECTOR_SIZE;
+            buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+            crate::dev::block::check_access(self.sector_count(), sector, buf.len())?;
+            let start = sector as usize * SECTOR_SIZE;
+            self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), BlockError> {
+            Ok(())
+        }
+    }
+
+    fn fat_size(sectors_per_cluster: u32) -> u32 {
+        let clusters = (SECTORS - RESERVED) / sectors_per_cluster;
+        ((clusters + 2) * 4).div_ceil(SECTOR_SIZE as u32)
+    }
+
+    /// An empty volume the way mkfs.fat lays it out: two FATs, FSInfo in
+    /// sector 1 and the root directory in cluster 2.
+    fn format(sectors_per_cluster: u32) -> Vec<u8> {
+        let mut image = vec![0u8; SECTORS as usize * SECTOR_SIZE];
+        let fat_size = fat_size(sectors_per_cluster);
+        let first_data = RESERVED + 2 * fat_size;
+        let clusters = (SECTORS - first_data) / sectors_per_cluster;
+
+        let bpb = &mut image[..SECTOR_SIZE];
+        bpb[..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+        bpb[3..11].copy_from_slice(b"MSWIN4.1");
+        put16(bpb, 11, SECTOR_SIZE as u16);
+        bpb[13] = sectors_per_cluster as u8;
+        put16(bpb, 14, RESERVED as u16);
+        bpb[16] = 2;
+        bpb[21] = 0xf8;
+        put32(bpb, 32, SECTORS);
+        put32(bpb, 36, fat_size);
+        put32(bpb, 44, 2);
+        put16(bpb, 48, FSINFO as u16);
+        put16(bpb, 50, 6);
+        bpb[510] = 0x55;
+        bpb[511] = 0xaa;
+
+        let info = &mut image[FSINFO as usize * SECTOR_SIZE..][..SECTOR_SIZE];
+        put32(info, 0, FSINFO_LEAD_SIG);
+        put32(info, 484, FSINFO_STRUC_SIG);
+        put32(info, 488, clusters - 1);
+        put32(info, 492, 3);
+        put32(info, 508, FSINFO_TRAIL_SIG);
+
+        for fat in 0..2 {
+            let start = (RESERVED + fat * fat_size) as usize * SECTOR_SIZE;
+            let fat = &mut image[start..];
+            put32(fat, 0, 0x0fff_fff8);
+            put32(fat, 4, FAT_EOC);
+            put32(fat, 8, FAT_EOC);
+        }
+        image
+    }
+
+    fn mount(disk: &Arc<RamDisk>) -> Fat32 {
+        Fat32::mount(disk.clone()).unwrap()
+    }
+
+    /// Formats a volume, lets `edit` change its boot sector and mounts it.
+    fn mount_with(edit: impl FnOnce(&mut [u8])) -> FsResult<Fat32> {
+        let mut image = format(1);
+        edit(&mut image[..SECTOR_SIZE]);
+        Fat32::mount(RamDisk::new(image))
+    }
+
+    fn names(fs: &Fat32, dir: &DirEntry) -> Vec<String> {
+        fs.read_dir(dir)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect()
+    }
+
+    fn contents(fs: &Fat32, file: &DirEntry) -> Vec<u8> {
+        let mut buf = vec![0u8; file.size as usize];
+        assert_eq!(fs.read(file, 0, &mut buf).unwrap(), buf.len());
+        buf
+    }
+
+    #[test]
+    fn mount_reads_the_bpb() {
+        for sectors_per_cluster in [1, 4] {
+            let fs = mount(&RamDisk::new(format(sectors_per_cluster)));
+            let first_data = RESERVED + 2 * fat_size(sectors_per_cluster);
+            let clusters = (SECTORS - first_data) / sectors_per_cluster;
+            assert_eq!(
+                fs.cluster_size(),
+                sectors_per_cluster as usize * SECTOR_SIZE
+            );
+            assert_eq!(fs.total_clusters(), clusters);
+            assert_eq!(fs.free_clusters(), clusters - 1);
+            assert_eq!(fs.root_entry().cluster, 2);
+            assert!(fs.read_dir(&fs.root_entry()).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn mount_rejects_what_isnt_fat32() {
+        let cases: [fn(&mut [u8]); 8] = [
+            |bpb| bpb[510] = 0,
+            |bpb| put16(bpb, 11, 4096),
+            |bpb| bpb[13] = 3,
+            |bpb| bpb[16] = 0,
+            // FAT12/16 keep a fixed root directory
+            |bpb| put16(bpb, 17, 512),
+            |bpb| put32(bpb, 32, SECTORS + 1),
+            |bpb| put32(bpb, 44, SECTORS),
+            // mirroring off with only two FATs to pick from
+            |bpb| put16(bpb, 40, 0x80 | 2),
+        ];
+        for edit in cases {
+            assert_eq!(mount_with(edit).err(), Some(FsError::BadFormat));
+        }
+        assert!(mount_with(|bpb| put16(bpb, 40, 0x80 | 1)).is_ok());
+    }
+
+    #[test]
+    fn mount_recounts_free_clusters_when_fsinfo_is_wrong() {
+        let mut image = format(1);
+        put32(&mut image[FSINFO as usize * SECTOR_SIZE..], 488, u32::MAX);
+        put32(&mut image[FSINFO as usize * SECTOR_SIZE..], 492, u32::MAX);
+        let fs = mount(&RamDisk::new(image));
+        assert_eq!(fs.free_clusters(), fs.total_clusters() - 1);
+        assert_eq!(fs.alloc.lock().next_free, 2);
+    }
+
+    #[test]
+    fn short_names_are_kept_without_long_entries() {
+        let disk = RamDisk::new(format(1));
+        let fs = mount(&disk);
+        let root = fs.root_entry();
+
+        let upper = fs.create(&root, "README.TXT", 0).unwrap();
+        let lower = fs.create(&root, "notes.md", 0).unwrap();
+        assert_eq!(upper.location.unwrap().lfn_slots, 0);
+        assert_eq!(lower.location.unwrap().lfn_slots, 0);
+        assert_eq!(lower.short_name, *b"NOTES   MD ");
+
+        let fs = mount(&disk);
+        assert_eq!(names(&fs, &root), ["README.TXT", "notes.md"]);
+        assert_eq!(fs.find(&root, "readme.txt").unwrap().name, "README.TXT");
+        assert_eq!(
+            fs.create(&root, "Notes.MD", 0).err(),
+            Some(FsError::AlreadyExists)
+        );
+    }
+
+    #[test]
+    fn long_names_round_trip() {
+        let disk = RamDisk::new(format(1));
+        let fs = mount(&disk);
+        let root = fs.root_entry();
+
+        let names_in = [
+            "Mixed.txt",
+            "a rather long file name.text",
+            "long name 2.text",
+            "ünïcode ✓",
+        ];
+        for name in names_in {
+            fs.create(&root, name, 0).unwrap();
+        }
+        let entries = mount(&disk).read_dir(&root).unwrap();
+        let listed: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(listed, names_in);
+
+        // 28 characters need three long entries, the aliases stay unique
+        assert_eq!(entries[1].location.unwrap().lfn_slots, 3);
+        assert_eq!(entries[1].short_name, *b"ARATHE~1TEX");
+        assert_eq!(entries[2].short_name, *b"LONGNA~1TEX");
+        assert_eq!(
+            fs.create(&root, "A Rather Long File Name.TEXT", 0).err(),
+            Some(FsError::AlreadyExists)
+        );
+        let alias = fs
+            .create(&root, "a rather long file name.text2", 0)
+            .unwrap();
+        assert_eq!(alias.short_name, *b"ARATHE~2TEX");
+
+        assert_eq!(fs.create(&root, "a:b", 0).err(), Some(FsError::InvalidName));
+        let too_long = "x".repeat(LFN_MAX + 1);
+        assert_eq!(
+            fs.create(&root, &too_long, 0).err(),
+            Some(FsError::InvalidName)
+        );
+    }
+
+    #[test]
+    fn long_names_with_a_stale_checksum_fall_back_to_the_short_name() {
+        let disk = RamDisk::new(format(1));
+        let fs = mount(&disk);
+        let root = fs.root_entry();
+        let entry = fs.create(&root, "a long name", 0).unwrap();
+
+        let (chain, data) = fs.read_dir_raw(root.cluster).unwrap();
+        let mut raw: [u8; ENTRY_SIZE] = data[..ENTRY_SIZE].try_into().unwrap();
+        raw[13] ^= 1;
+        fs.write_slots(&chain, 0, &[raw]).unwrap();
+        assert_eq!(
+            names(&fs, &root),
+            [display_short_name(&entry.short_name, 0)]
+        );
+    }
+
+    #[test]
+    fn directories_grow_past_their_first_cluster() {
+        let fs = mount(&RamDisk::new(format(1)));
+        let root = fs.root_entry();
+        let dir = fs.create(&root, "dir", ATTR_DIRECTORY).unwrap();
+
+        // 16 slots per cluster, `.` and `..` included
+        let created: Vec<String> = (0..40).map(|i| format!("file number {i}")).collect();
+        for name in &created {
+            fs.create(&dir, name, 0).unwrap();
+        }
+        assert_eq!(names(&fs, &dir), created);
+        assert!(fs.chain(dir.cluster).unwrap().len() > 1);
+        assert_eq!(
+            fs.lookup("/dir/file number 39").unwrap().name,
+            "file number 39"
+        );
+        assert_eq!(fs.find(&dir, "..").unwrap().cluster, root.cluster);
+    }
+
+    #[test]
+    fn writes_extend_and_truncate_files() {
+        let disk = RamDisk::new(format(1));
+        let fs = mount(&disk);
+        let free = fs.free_clusters();
+        let mut file = fs.create(&fs.root_entry(), "data", 0).unwrap();
+        assert_eq!(file.cluster, 0);
+
+        let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        assert_eq!(fs.write(&mut file, 0, &data).unwrap(), data.len());
+        assert_eq!(fs.free_clusters(), free - 3);
+        assert_eq!(contents(&fs, &file), data);
+
+        // a write past the end leaves a hole that reads back as zero
+        fs.write(&mut file, 2000, b"tail").unwrap();
+        let stored = mount(&disk).lookup("/data").unwrap();
+        assert_eq!(stored.size, 2004);
+        let read = contents(&fs, &stored);
+        assert_eq!(read[..1500], data);
+        assert!(read[1500..2000].iter().all(|&b| b == 0));
+        assert_eq!(&read[2000..], b"tail");
+        assert_eq!(fs.free_clusters(), free - 4);
+
+        fs.truncate(&mut file, 100).unwrap();
+        assert_eq!(fs.free_clusters(), free - 1);
+        assert_eq!(fs.chain(file.cluster).unwrap().len(), 1);
+        fs.truncate(&mut file, 700).unwrap();
+        let read = contents(&fs, &file);
+        assert_eq!(read[..100], data[..100]);
+        assert!(read[100..].iter().all(|&b| b == 0));
+
+        fs.truncate(&mut file, 0).unwrap();
+        assert_eq!(file.cluster, 0);
+        assert_eq!(fs.free_clusters(), free);
+        assert_eq!(mount(&disk).lookup("/data").unwrap().size, 0);
+
+        assert_eq!(
+            fs.write(&mut file, u32::MAX as u64, b"x").err(),
+            Some(FsError::FileTooLarge)
+        );
+        assert_eq!(
+            fs.write(&mut file, u64::MAX, b"x").err(),
+            Some(FsError::FileTooLarge)
+        );
+    }
+
+    #[test]
+    fn remove_frees_entries_and_clusters() {
+        let disk = RamDisk::new(format(1));
+        let fs = mount(&disk);
+        let root = fs.root_entry();
+        let free = fs.free_clusters();
+
+        let dir = fs.create(&root, "some directory", ATTR_DIRECTORY).unwrap();
+        let mut file = fs.create(&dir, "file", 0).unwrap();
+        fs.write(&mut file, 0, &[1; 1024]).unwrap();
+        assert_eq!(fs.remove(&dir).err(), Some(FsError::DirectoryNotEmpty));
+
+        fs.remove(&file).unwrap();
+        fs.remove(&dir).unwrap();
+        assert_eq!(fs.free_clusters(), free);
+        assert!(names(&fs, &root).is_empty());
+        assert_eq!(fs.lookup("/some directory").err(), Some(FsError::NotFound));
+        assert_eq!(fs.remove(&root).err(), Some(FsError::InvalidName));
+
+        // the freed slots and clusters are handed out again
+        let again = fs.create(&root, "some directory", ATTR_DIRECTORY).unwrap();
+        assert_eq!(again.location, dir.location);
+        assert_eq!(again.cluster, dir.cluster);
+    }
+
+    #[test]
+    fn open_files_cant_be_removed() {
+        let fs: Arc<Fat32> = Arc::new(mount(&RamDisk::new(format(1))));
+        let root = fs.clone().root();
+        let file = root.create("open", NodeKind::File).unwrap();
+        file.write_at(0, b"data").unwrap();
+        assert_eq!(root.remove("open").err(), Some(FsError::Busy));
+        assert_eq!(root.lookup("open").unwrap().metadata().size, 4);
+
+        drop(file);
+        root.remove("open").unwrap();
+        assert!(root.lookup("open").is_err());
+    }
+
+    #[test]
+    fn sync_updates_fsinfo() {
+        let disk = RamDisk::new(format(1));
+        let fs = mount(&disk);
+        let mut file = fs.create(&fs.root_entry(), "file", 0).unwrap();
+        fs.write(&mut file, 0, &[7; 4096]).unwrap();
+        let free = fs.free_clusters();
+
+        // nothing but sync writes FSInfo
+        assert_ne!(le32(&disk.sector(FSINFO), 488), free);
+        fs.sync().unwrap();
+        let info = disk.sector(FSINFO);
+        assert_eq!(le32(&info, 488), free);
+        assert_eq!(le32(&info, 492), fs.alloc.lock().next_free);
+        assert_eq!(le32(&info, 508), FSINFO_TRAIL_SIG);
+        assert_eq!(mount(&disk).free_clusters(), free);
+    }
+
+    #[test]
+    fn allocation_follows_the_active_fat() {
+        let mut image = format(1);
+        put16(&mut image, 40, 0x80 | 1);
+        let disk = RamDisk::new(image);
+        let fs = mount(&disk);
+        let mut file = fs.create(&fs.root_entry(), "file", 0).unwrap();
+        fs.write(&mut file, 0, b"x").unwrap();
+
+        let (sector, offset) = fs.fat_position(file.cluster);
+        let fat_size = fat_size(1) as u64;
+        assert_eq!(le32(&disk.sector(sector as u64), offset), FAT_FREE);
+        assert_eq!(
+            le32(&disk.sector(sector as u64 + fat_size), offset) & FAT_MASK,
+            FAT_EOC
+        );
+        assert_eq!(
+            contents(&mount(&disk), &mount(&disk).lookup("/file").unwrap()),
+            b"x"
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Open files and the per-task descriptor tables that refer to them.
+
+use crate::{
+    alloc::{sync::Arc, vec::Vec},
+    fs::{FsError, FsResult, Inode, NodeKind},
+    sync::mutex::CriticalSpinLock,
+};
+
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_ACCMODE: usize = 3;
+pub const O_CREAT: usize = 0x40;
+pub const O_TRUNC: usize = 0x200;
+pub const O_APPEND: usize = 0x400;
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// Most descriptors a task can have open at once.
+pub const MAX_FDS: usize = 64;
+
+/// An inode opened with some access mode and a position, shared by every
+/// descriptor duplicated from the one that opened it.
+pub struct OpenFile {
+    inode: Arc<dyn Inode>,
+    flags: usize,
+    offset: CriticalSpinLock<u64>,
+}
+
+impl OpenFile {
+    pub fn new(inode: Arc<dyn Inode>, flags: usize) -> Arc<Self> {
+        Arc::new(Self {
+            inode,
+            flags,
+            offset: CriticalSpinLock::new(0),
+        })
+    }
+
+    /// Opens `path` the way `open(2)` would with `flags`.
+    pub fn open(path: &str, flags: usize) -> FsResult<Arc<Self>> {
+        let inode = match super::resolve(path) {
+            Ok(inode) => inode,
+            Err(FsError::NotFound) if flags & O_CREAT != 0 => {
+                let (parent, name) = super::mount::resolve_parent(path)?;
+                parent.create(&name, NodeKind::File)?
+            }
+            Err(err) => return Err(err),
+        };
+        if inode.is_dir() && flags & O_ACCMODE != O_RDONLY {
+            return Err(FsError::IsADirectory);
+        }
+        if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY {
+            inode.truncate(0)?;
+        }
+        Ok(Self::new(inode, flags))
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+
+    /// Reads at the current position and moves past what was read.
+    ///
+    /// The position isn't locked across the read, which may block on the console.
+    pub fn read(&self, buf: &mut [u8]) -> FsResult<usize> {
+        if !self.readable() {
+            return Err(FsError::AccessDenied);
+        }
+        let offset = *self.offset.lock();
+        let len = self.inode.read_at(offset, buf)?;
+        *self.offset.lock() = offset + len as u64;
+        Ok(len)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> FsResult<usize> {
+        if !self.writable() {
+            return Err(FsError::AccessDenied);
+        }
+        let offset = if self.flags & O_APPEND != 0 {
+            self.inode.metadata().size
+        } else {
+            *self.offset.lock()
+        };
+        let len = self.inode.write_at(offset, buf)?;
+        *self.offset.lock() = offset + len as u64;
+        Ok(len)
+    }
+
+    /// Moves the position like `lseek(2)`, returning the new one.
+    pub fn seek(&self, offset: i64, whence: usize) -> FsResult<u64> {
+        let metadata = self.inode.metadata();
+        if metadata.kind == NodeKind::CharDevice {
+            return Err(FsError::Unsupported);
+        }
+        let mut position = self.offset.lock();
+        let base = match whence {
+            SEEK_SET => 0,
+            SEEK_CUR => *position,
+            SEEK_END => metadata.size,
+            _ => return Err(FsError::InvalidArgument),
+        };
+        *position = base
+            .checked_add_signed(offset)
+            .ok_or(FsError::InvalidArgument)?;
+        Ok(*position)
+    }
+}
+
+/// A task's descriptors, indices into which are handed to user space.
+pub struct FdTable {
+    files: CriticalSpinLock<Vec<Option<Arc<OpenFile>>>>,
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        Self {
+            files: CriticalSpinLock::new(Vec::new()),
+        }
+    }
+
+    /// A table with `/dev/console` open as stdin, stdout and stderr.
+    pub fn with_console() -> Self {
+        let table = Self::new();
+        if let Ok(console) = OpenFile::open("/dev/console", O_RDWR) {
+            for _ in 0..3 {
+                _ = table.insert(console.clone());
+            }
+        }
+        table
+    }
+
+    /// Installs `file` at the lowest free descriptor.
+    pub fn insert(&self, file: Arc<OpenFile>) -> Option<usize> {
+        let mut files = self.files.lock();
+        match files.iter().position(Option::is_none) {
+            Some(fd) => {
+                files[fd] = Some(file);
+                Some(fd)
+            }
+            None if files.len() < MAX_FDS => {
+                files.push(Some(file));
+                Some(files.len() - 1)
+            }
+            None => None,
+        }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<Arc<OpenFile>> {
+        self.files.lock().get(fd).cloned().flatten()
+    }
+
+    pub fn close(&self, fd: usize) -> Option<Arc<OpenFile>> {
+        self.files.lock().get_mut(fd).and_then(Option::take)
+    }
+
+    /// A copy for a forked task, sharing every open file and its position.
+    pub fn fork(&self) -> Self {
+        Self {
+            files: CriticalSpinLock::new(self.files.lock().clone()),
+        }
+    }
+}
+
+impl Default for FdTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for FdTable {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FdTable").finish_non_exhaustive()
+    }
+}
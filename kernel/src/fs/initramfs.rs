@@ -0,0 +1,235 @@
+//! Read-only filesystem over a cpio archive in the `newc` format, the initial
+//! ramdisk the bootloader leaves in memory.
+//!
+//! File contents are borrowed straight from the archive, which stays reserved forever.
+
+use crate::{
+    alloc::{collections::BTreeMap, string::String, sync::Arc, vec::Vec},
+    fs::{DirEntry, FileSystem, FsError, FsResult, Inode, Metadata, NodeKind},
+};
+
+const MAGIC: &[u8; 6] = b"070701";
+/// Same layout, with the `check` field holding a checksum we don't verify.
+const MAGIC_CRC: &[u8; 6] = b"070702";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &[u8] = b"TRAILER!!!";
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// Header fields, in order, each eight hex digits after the magic.
+const FIELD_MODE: usize = 1;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_NAMESIZE: usize = 11;
+
+pub enum Node {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, Arc<Node>>),
+}
+
+/// A directory still being filled in while the archive is parsed.
+#[derive(Default)]
+struct Builder {
+    children: BTreeMap<String, Entry>,
+}
+
+enum Entry {
+    File(&'static [u8]),
+    Directory(Builder),
+}
+
+impl Builder {
+    /// The directory at `path`, creating it and its parents when the archive
+    /// lists files before, or without, their directories.
+    fn directory(&mut self, path: &[&str]) -> Option<&mut Builder> {
+        let mut dir = self;
+        for &part in path {
+            let entry = dir
+                .children
+                .entry(String::from(part))
+                .or_insert_with(|| Entry::Directory(Builder::default()));
+            dir = match entry {
+                Entry::Directory(dir) => dir,
+                Entry::File(_) => return None,
+            };
+        }
+        Some(dir)
+    }
+
+    fn build(self) -> Arc<Node> {
+        Arc::new(Node::Directory(
+            self.children
+                .into_iter()
+                .map(|(name, entry)| {
+                    let node = match entry {
+                        Entry::File(data) => Arc::new(Node::File(data)),
+                        Entry::Directory(dir) => dir.build(),
+                    };
+                    (name, node)
+                })
+                .collect(),
+        ))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpioError {
+    BadMagic,
+    Truncated,
+    BadHeader,
+}
+
+fn field(header: &[u8], index: usize) -> Result<u32, CpioError> {
+    let start = MAGIC.len() + index * 8;
+    let digits =
+        core::str::from_utf8(&header[start..start + 8]).map_err(|_| CpioError::BadHeader)?;
+    u32::from_str_radix(digits, 16).map_err(|_| CpioError::BadHeader)
+}
+
+/// Builds the directory tree of `archive`. Entries other than files and
+/// directories, like symlinks and device nodes, are skipped.
+pub fn parse(archive: &'static [u8]) -> Result<Arc<Node>, CpioError> {
+    let mut root = Builder::default();
+    let mut offset = 0;
+
+    loop {
+        let header = archive
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(CpioError::Truncated)?;
+        if &header[..6] != MAGIC && &header[..6] != MAGIC_CRC {
+            return Err(CpioError::BadMagic);
+        }
+        let mode = field(header, FIELD_MODE)?;
+        let file_size = field(header, FIELD_FILESIZE)? as usize;
+        let name_size = field(header, FIELD_NAMESIZE)? as usize;
+
+        // the name includes its NUL, and the data starts 4 byte aligned
+        let name_start = offset + HEADER_SIZE;
+        let name = archive
+            .get(name_start..name_start + name_size)
+            .ok_or(CpioError::Truncated)?;
+        let name = name.strip_suffix(&[0]).ok_or(CpioError::BadHeader)?;
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = archive
+            .get(data_start..data_start + file_size)
+            .ok_or(CpioError::Truncated)?;
+        offset = (data_start + file_size).next_multiple_of(4);
+
+        if name == TRAILER {
+            break;
+        }
+        let Ok(name) = core::str::from_utf8(name) else {
+            continue;
+        };
+        let mut parts: Vec<&str> = super::mount::components(name);
+        let Some(last) = parts.pop() else {
+            // the archive's own "."
+            continue;
+        };
+        let Some(dir) = root.directory(&parts) else {
+            continue;
+        };
+        match mode & S_IFMT {
+            S_IFDIR => {
+                dir.directory(&[last]);
+            }
+            S_IFREG => {
+                dir.children.insert(String::from(last), Entry::File(data));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(root.build())
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Node::File(data) => Metadata {
+                kind: NodeKind::File,
+                size: data.len() as u64,
+            },
+            Node::Directory(_) => Metadata {
+                kind: NodeKind::Directory,
+                size: 0,
+            },
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> FsResult<usize> {
+        let Node::File(data) = self else {
+            return Err(FsError::IsADirectory);
+        };
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> FsResult<usize> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> FsResult<Arc<dyn Inode>> {
+        let Node::Directory(children) = self else {
+            return Err(FsError::NotADirectory);
+        };
+        children
+            .get(name)
+            .map(|node| node.clone() as Arc<dyn Inode>)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        let Node::Directory(children) = self else {
+            return Err(FsError::NotADirectory);
+        };
+        Ok(children
+            .iter()
+            .map(|(name, node)| {
+                let metadata = node.metadata();
+                DirEntry {
+                    name: name.clone(),
+                    kind: metadata.kind,
+                    size: metadata.size,
+                }
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn remove(&self, _name: &str) -> FsResult<()> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+pub struct Initramfs {
+    root: Arc<Node>,
+}
+
+impl Initramfs {
+    pub fn new(archive: &'static [u8]) -> Result<Self, CpioError> {
+        Ok(Self {
+            root: parse(archive)?,
+        })
+    }
+}
+
+impl FileSystem for Initramfs {
+    fn name(&self) -> &str {
+        "initramfs"
+    }
+
+    fn root(self: Arc<Self>) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
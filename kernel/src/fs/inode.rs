@@ -0,0 +1,117 @@
+//! The [`Inode`] and [`FileSystem`] traits filesystems implement and the types
+//! they pass around, with nothing tying them to the rest of the kernel.
+
+use crate::{
+    alloc::{string::String, sync::Arc, vec::Vec},
+    dev::block::BlockError,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError {
+    Io(BlockError),
+    /// The volume isn't in a format the filesystem understands.
+    BadFormat,
+    /// On-disk structures point somewhere impossible.
+    Corrupt,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    InvalidName,
+    NoSpace,
+    FileTooLarge,
+    ReadOnly,
+    /// The node doesn't support the operation, like seeking a console.
+    Unsupported,
+    InvalidArgument,
+    /// The file wasn't opened for this kind of access.
+    AccessDenied,
+    /// Something is mounted there already, a mount is still in use, or a file being removed is still open.
+    Busy,
+    /// A blocking read was cut short by ^C.
+    Interrupted,
+}
+
+impl From<BlockError> for FsError {
+    fn from(value: BlockError) -> Self {
+        FsError::Io(value)
+    }
+}
+
+pub type FsResult<T> = Result<T, FsError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+    /// Stream device without a size, offsets are ignored.
+    CharDevice,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Metadata {
+    pub kind: NodeKind,
+    pub size: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: NodeKind,
+    pub size: u64,
+}
+
+/// A file, directory or device inside a mounted filesystem.
+///
+/// Everything but [`metadata`](Self::metadata) defaults to failing, so nodes
+/// only implement what makes sense for them.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Reads at `offset`, returning 0 at the end of the file.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> FsResult<usize> {
+        Err(FsError::Unsupported)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> FsResult<usize> {
+        Err(FsError::Unsupported)
+    }
+
+    fn truncate(&self, _size: u64) -> FsResult<()> {
+        Err(FsError::Unsupported)
+    }
+
+    /// The child called `name` of a directory.
+    fn lookup(&self, _name: &str) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Every child of a directory, without `.` and `..`.
+    fn read_dir(&self) -> FsResult<Vec<DirEntry>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn create(&self, _name: &str, _kind: NodeKind) -> FsResult<Arc<dyn Inode>> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn remove(&self, _name: &str) -> FsResult<()> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.metadata().kind == NodeKind::Directory
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn root(self: Arc<Self>) -> Arc<dyn Inode>;
+
+    /// Writes back whatever the filesystem still holds in memory.
+    fn sync(&self) -> FsResult<()> {
+        Ok(())
+    }
+}
@@ -1 +1,61 @@
+//! Virtual filesystem: the [`Inode`] and [`FileSystem`] traits filesystems
+//! implement, the mount table paths are resolved through and the open file
+//! objects tasks reach by descriptor.
+
+use crate::{
+    alloc::{string::String, sync::Arc},
+    dev::block_cache::CachedDevice,
+    dtb::Dtb,
+    mem::Pointer,
+};
+
+pub mod devfs;
 pub mod fat32;
+pub mod file;
+pub mod initramfs;
+mod inode;
+pub mod mount;
+
+pub use file::{FdTable, OpenFile};
+pub use inode::*;
+pub use mount::{mount, resolve, unmount};
+
+/// Mounts `/dev`, the initial ramdisk as `/` if the bootloader passed one, and
+/// the first block device holding a FAT32 volume as `/`, or `/mnt` below a ramdisk.
+pub fn init(dtb: &Dtb) {
+    if mount("/dev", Arc::new(devfs::DevFs::new())).is_err() {
+        crate::println!("Could not mount /dev");
+    }
+
+    let mut disk_at = "/";
+    if let Some(initrd) = crate::mem::initrd_region(dtb) {
+        let archive = unsafe {
+            let start = Pointer::from_phys(initrd.start as *mut u8).virt();
+            core::slice::from_raw_parts(start, initrd.len())
+        };
+        match initramfs::Initramfs::new(archive) {
+            Ok(fs) => {
+                if mount("/", Arc::new(fs)).is_ok() {
+                    crate::println!("Mounted initramfs of {} bytes on /", initrd.len());
+                    disk_at = "/mnt";
+                }
+            }
+            Err(err) => crate::println!("Could not read initramfs: {err:?}"),
+        }
+    }
+
+    for device in crate::dev::block::devices() {
+        let name = String::from(device.name());
+        match fat32::Fat32::mount(Arc::new(CachedDevice::new(device))) {
+            Ok(fs) => {
+                match mount(disk_at, Arc::new(fs)) {
+                    Ok(()) => crate::println!("Mounted {name} on {disk_at} as FAT32"),
+                    Err(err) => crate::println!("Could not mount {name} on {disk_at}: {err:?}"),
+                }
+                break;
+            }
+            Err(FsError::BadFormat) => {}
+            Err(err) => crate::println!("Could not read {name}: {err:?}"),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! The mount table and path resolution through it.
+
+use crate::{
+    alloc::{string::String, sync::Arc, vec::Vec},
+    fs::{FileSystem, FsError, FsResult, Inode},
+    sync::mutex::CriticalSpinLock,
+};
+
+struct Mount {
+    /// Normalized components of where it's mounted, empty for `/`.
+    at: Vec<String>,
+    fs: Arc<dyn FileSystem>,
+    root: Arc<dyn Inode>,
+}
+
+static MOUNTS: CriticalSpinLock<Vec<Mount>> = CriticalSpinLock::new(Vec::new());
+
+/// Components of `path` with `.` and `..` applied, relative paths start at `/`.
+pub fn components(path: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts
+}
+
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> FsResult<()> {
+    let at: Vec<String> = components(path).into_iter().map(String::from).collect();
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.at == at) {
+        return Err(FsError::Busy);
+    }
+    let root = fs.clone().root();
+    mounts.push(Mount { at, fs, root });
+    Ok(())
+}
+
+/// Syncs and detaches whatever is mounted at `path`.
+pub fn unmount(path: &str) -> FsResult<()> {
+    let at = components(path);
+    let mount = {
+        let mut mounts = MOUNTS.lock();
+        let index = mounts
+            .iter()
+            .position(|mount| mount.at.iter().eq(at.iter()))
+            .ok_or(FsError::NotFound)?;
+        mounts.remove(index)
+    };
+    mount.fs.sync()
+}
+
+/// Calls `f` with the path and filesystem of every mount.
+pub fn for_each_mount(mut f: impl FnMut(&str, &dyn FileSystem)) {
+    let mounts: Vec<(String, Arc<dyn FileSystem>)> = MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| {
+            let mut path = String::new();
+            for part in &mount.at {
+                path.push('/');
+                path.push_str(part);
+            }
+            if path.is_empty() {
+                path.push('/');
+            }
+            (path, mount.fs.clone())
+        })
+        .collect();
+    for (path, fs) in mounts {
+        f(&path, &*fs);
+    }
+}
+
+/// Syncs every mounted filesystem.
+pub fn sync_all() -> FsResult<()> {
+    let mounts: Vec<Arc<dyn FileSystem>> =
+        MOUNTS.lock().iter().map(|mount| mount.fs.clone()).collect();
+    mounts.iter().try_for_each(|fs| fs.sync())
+}
+
+/// The node at `path`, walking from the deepest mount containing it.
+pub fn resolve(path: &str) -> FsResult<Arc<dyn Inode>> {
+    let parts = components(path);
+    let (mut node, depth) = {
+        let mounts = MOUNTS.lock();
+        let mount = mounts
+            .iter()
+            .filter(|mount| {
+                mount.at.len() <= parts.len() && mount.at.iter().zip(&parts).all(|(a, b)| a == b)
+            })
+            .max_by_key(|mount| mount.at.len())
+            .ok_or(FsError::NotFound)?;
+        (mount.root.clone(), mount.at.len())
+    };
+    for part in &parts[depth..] {
+        node = node.lookup(part)?;
+    }
+    Ok(node)
+}
+
+/// The directory `path` would live in and its last component.
+pub fn resolve_parent(path: &str) -> FsResult<(Arc<dyn Inode>, String)> {
+    let mut parts = components(path);
+    let name = parts.pop().ok_or(FsError::InvalidName)?;
+    let mut parent = String::new();
+    for part in parts {
+        parent.push('/');
+        parent.push_str(part);
+    }
+    Ok((resolve(&parent)?, String::from(name)))
+}
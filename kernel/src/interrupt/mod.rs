@@ -1,5 +1,184 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    arch::smp::MAX_HARTS,
+    dtb::{ByteStream, Dtb, DtbNodes, DtbProperties},
+    interrupt::plic::{Plic, PlicDev},
+    mem::Pointer,
+    println,
+    sync::mutex::CriticalSpinLock,
+};
+
 pub mod plic;
 
-unsafe trait InterruptHandler{
-    fn handle(&self);
-}
\ No newline at end of file
+/// Runs when its interrupt line fires, on whichever hart claimed it and with interrupts disabled.
+pub trait InterruptHandler: Sync {
+    fn handle(&self, irq: u32);
+}
+
+impl<F: Fn(u32) + Sync> InterruptHandler for F {
+    fn handle(&self, irq: u32) {
+        self(irq)
+    }
+}
+
+/// Interrupt sources the PLIC spec allows, source 0 means none.
+const MAX_IRQ: usize = 1024;
+const NO_CONTEXT: usize = usize::MAX;
+
+/// `interrupts-extended` cause of a supervisor external interrupt.
+const IRQ_S_EXT: u32 = 9;
+
+static PLIC: CriticalSpinLock<Option<PlicDev>> = CriticalSpinLock::new(None);
+
+static HANDLERS: CriticalSpinLock<[Option<&'static dyn InterruptHandler>; MAX_IRQ]> =
+    CriticalSpinLock::new([None; MAX_IRQ]);
+
+/// PLIC context taking supervisor external interrupts for each hart.
+static S_CONTEXT: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(NO_CONTEXT) }; MAX_HARTS];
+
+/// Routes `irq` to `handler` and enables it on every hart.
+///
+/// Fails if the line doesn't exist or already has a handler.
+pub fn register(irq: u32, handler: &'static dyn InterruptHandler) -> Result<(), ()> {
+    let mut plic = PLIC.lock();
+    let Some(plic) = plic.as_mut() else {
+        return Err(());
+    };
+    if irq == 0 || irq > plic.max_int() {
+        return Err(());
+    }
+
+    let mut handlers = HANDLERS.lock();
+    let slot = &mut handlers[irq as usize];
+    if slot.is_some() {
+        return Err(());
+    }
+    *slot = Some(handler);
+
+    unsafe {
+        plic.set_priority(irq, 1);
+        for ctx in contexts() {
+            plic.enable(ctx, irq);
+        }
+    }
+    Ok(())
+}
+
+/// Disables `irq` and drops its handler.
+pub fn unregister(irq: u32) {
+    let mut plic = PLIC.lock();
+    let Some(plic) = plic.as_mut() else {
+        return;
+    };
+    if irq == 0 || irq > plic.max_int() {
+        return;
+    }
+    unsafe {
+        for ctx in contexts() {
+            plic.disable(ctx, irq);
+        }
+        plic.set_priority(irq, 0);
+    }
+    HANDLERS.lock()[irq as usize] = None;
+}
+
+fn contexts() -> impl Iterator<Item = usize> {
+    S_CONTEXT
+        .iter()
+        .map(|ctx| ctx.load(Ordering::Relaxed))
+        .filter(|ctx| *ctx != NO_CONTEXT)
+}
+
+/// Claims and dispatches every pending external interrupt of this hart.
+pub fn handle_external() {
+    let ctx = S_CONTEXT[crate::arch::current_hart()].load(Ordering::Relaxed);
+    if ctx == NO_CONTEXT {
+        return;
+    }
+    loop {
+        let irq = match PLIC.lock().as_ref() {
+            Some(plic) => unsafe { plic.claim(ctx) },
+            None => return,
+        };
+        if irq == 0 {
+            return;
+        }
+
+        // copied out so a handler may register or unregister lines itself
+        let handler = HANDLERS.lock()[irq as usize];
+        match handler {
+            Some(handler) => handler.handle(irq),
+            None => println!("Unhandled external interrupt {irq}"),
+        }
+
+        if let Some(plic) = PLIC.lock().as_ref() {
+            unsafe { plic.complete(ctx, irq) }
+        }
+    }
+}
+
+/// Finds the PLIC in the DTB and assigns each hart its supervisor context.
+pub fn init(dtb: &Dtb) {
+    let Some(node) = dtb.nodes().compatible(b"riscv,plic0").next() else {
+        println!("No PLIC found, external interrupts disabled");
+        return;
+    };
+
+    let [start, _size] = node.properties().expect_value(b"reg", |stream| {
+        stream.usize_cells_arr(dtb.root().addr_size_cells())
+    });
+    let max_int = node
+        .properties()
+        .expect_value(b"riscv,ndev", ByteStream::u32)
+        .min(MAX_IRQ as u32 - 1);
+    let mut plic = unsafe { PlicDev::new(Pointer::from_phys(start as *mut Plic).virt(), max_int) };
+
+    // each (phandle, cause) pair is one context, pointing at the interrupt controller of a hart
+    let mut extended = node.properties().expect(b"interrupts-extended");
+    let mut ctx = 0;
+    while let (Some(phandle), Some(cause)) = (extended.u32(), extended.u32()) {
+        if cause == IRQ_S_EXT
+            && let Some(hart) = hart_of_intc(dtb, phandle)
+            && hart < MAX_HARTS
+        {
+            S_CONTEXT[hart].store(ctx, Ordering::Relaxed);
+            unsafe {
+                plic.clear_context(ctx);
+                plic.set_threshold(ctx, 0);
+            }
+        }
+        ctx += 1;
+    }
+
+    for irq in 1..=max_int {
+        unsafe { plic.set_priority(irq, 0) };
+    }
+
+    *PLIC.lock() = Some(plic);
+
+    unsafe {
+        riscv::register::sie::set_sext();
+    }
+
+    println!(
+        "Initialized PLIC at 0x{start:x}, {max_int} sources, {} harts",
+        contexts().count()
+    );
+}
+
+/// Hart id of the cpu whose interrupt controller has `phandle`.
+fn hart_of_intc(dtb: &Dtb, phandle: u32) -> Option<usize> {
+    let cpus = dtb.root().childern().nammed(b"cpus").next()?;
+    for cpu in cpus.childern() {
+        let owns_intc = cpu.childern().compatible(b"riscv,cpu-intc").any(|intc| {
+            intc.properties()
+                .find_value(b"phandle", ByteStream::u32)
+                .is_some_and(|value| value == phandle)
+        });
+        if owns_intc {
+            return cpu.properties().find_value(b"reg", ByteStream::u32).map(|hart| hart as usize);
+        }
+    }
+    None
+}
@@ -69,6 +69,16 @@ pub struct PlicDev {
     base: *mut Plic,
 }
 
+unsafe impl Send for PlicDev {}
+
+// register blocks of the PLIC spec indexed by context, unlike the fixed four hart layout of `Plic`
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
 #[allow(unsafe_op_in_unsafe_fn)]
 #[allow(clippy::missing_safety_doc)]
 impl PlicDev {
@@ -76,6 +86,54 @@ impl PlicDev {
         Self { max_int, base }
     }
 
+    pub fn max_int(&self) -> u32 {
+        self.max_int
+    }
+
+    fn enable_word(&self, ctx: usize, int: u32) -> *mut u32 {
+        (self.base as usize + ENABLE_BASE + ctx * ENABLE_STRIDE + (int as usize / 32) * 4) as *mut u32
+    }
+
+    fn context_reg(&self, ctx: usize, reg: usize) -> *mut u32 {
+        (self.base as usize + CONTEXT_BASE + ctx * CONTEXT_STRIDE + reg) as *mut u32
+    }
+
+    pub unsafe fn enable(&mut self, ctx: usize, int: u32) {
+        let val = self.enable_word(ctx, int);
+        val.write_volatile(val.read_volatile() | (1 << (int % 32)));
+    }
+
+    pub unsafe fn disable(&mut self, ctx: usize, int: u32) {
+        let val = self.enable_word(ctx, int);
+        val.write_volatile(val.read_volatile() & !(1 << (int % 32)));
+    }
+
+    pub unsafe fn set_threshold(&mut self, ctx: usize, threshold: u32) {
+        self.context_reg(ctx, CONTEXT_THRESHOLD).write_volatile(threshold);
+    }
+
+    /// Highest priority pending interrupt of `ctx`, 0 when there is none.
+    pub unsafe fn claim(&self, ctx: usize) -> u32 {
+        self.context_reg(ctx, CONTEXT_CLAIM).read_volatile()
+    }
+
+    pub unsafe fn complete(&self, ctx: usize, int: u32) {
+        self.context_reg(ctx, CONTEXT_CLAIM).write_volatile(int);
+    }
+
+    /// Disables every interrupt of `ctx` and drains anything it already claimed.
+    pub unsafe fn clear_context(&mut self, ctx: usize) {
+        for i in 1..=self.max_int {
+            self.disable(ctx, i);
+        }
+        loop {
+            match self.claim(ctx) {
+                0 => break,
+                v => self.complete(ctx, v),
+            }
+        }
+    }
+
     pub unsafe fn clear(&mut self) {
         self.mclear();
         self.sclear();
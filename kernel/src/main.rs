@@ -5,11 +5,13 @@ pub mod alloc;
 pub mod arch;
 pub mod dev;
 pub mod dtb;
+pub mod elf;
 pub mod fs;
 pub mod interrupt;
 pub mod mem;
 pub mod panic;
 pub mod sbi;
+pub mod shell;
 pub mod std;
 pub mod sync;
 pub mod task;
@@ -19,7 +21,12 @@ pub mod syscall;
 
 use dev::*;
 
-use crate::{dtb::Dtb, std::stdio};
+use crate::{
+    dtb::Dtb,
+    fs::FsError,
+    std::stdio,
+    task::process::{LoadError, Process},
+};
 
 #[unsafe(no_mangle)]
 #[inline(never)]
@@ -71,20 +78,30 @@ pub unsafe extern "C" fn init_task(_hart_id: usize, dtb_ptr: *const u8) -> ! {
 
     let dtb = unsafe { Dtb::from_ptr(dtb_ptr).unwrap() };
     println!("{dtb}");
-    
+
+    sbi::init();
+
+    interrupt::init(&dtb);
+
     pci::init(&dtb);
 
     uart::init(&dtb);
 
-    // timer::clint::init(&dtb);
+    timer::clint::init(&dtb);
+
+    arch::smp::init(&dtb);
 
     dev::test_pci::test_pci();
 
+    block::init(&dtb);
+
+    fs::init(&dtb);
+
     vga::init(1920, 1080);
     display::update_buffer(vga::framebuffer());
 
     stdio::set_sout(|str| {
-        uart::uart().write_str(str);
+        tty::write_str(str);
         display::print(str.as_bytes());
     });
 
@@ -96,5 +113,17 @@ pub unsafe extern "C" fn init_task(_hart_id: usize, dtb_ptr: *const u8) -> ! {
 
     syscon::init(&dtb);
 
+    task::spawn_named("shell", shell::main, dtb_ptr as usize);
+
+    // the first user program, when the initramfs brings one
+    match Process::load_file("/init", &[b"/init"], &[]) {
+        Ok(init) => {
+            let code = init.spawn("init").join();
+            println!("/init exited with {code}");
+        }
+        Err(LoadError::Fs(FsError::NotFound)) => {}
+        Err(err) => println!("Could not start /init: {err:?}"),
+    }
+
     arch::halt()
 }
\ No newline at end of file
@@ -1,9 +1,21 @@
 pub mod pages;
+pub mod space;
 
-use crate::dtb::{ByteStream, Dtb, DtbNodes, DtbProperties};
+use crate::{
+    arch::page::PageTableRoot,
+    dtb::{ByteStream, Dtb, DtbNodes, DtbProperties},
+    sync::mutex::CriticalSpinLock,
+};
 
 pub const PHYS_ADDR_OFFSET: usize = 0xFFFFFFC000000000;
 
+/// Virtual region holding guarded kernel task stacks, see `task::stack`.
+pub const KSTACK_REGION: usize = 0xFFFFFFE000000000;
+pub const KSTACK_REGION_SIZE: usize = 1 << 30;
+
+/// The kernel's own address space, installed by `setup_vm`.
+pub static KERNEL_MAP: CriticalSpinLock<Option<PageTableRoot>> = CriticalSpinLock::new(None);
+
 pub struct Pointer<T>(*mut T);
 
 impl<T> Ord for Pointer<T> {
@@ -163,6 +175,20 @@ pub fn reserved_regions(dtb: &Dtb) -> impl Iterator<Item = Range> {
         }))
 }
 
+/// Physical range of the initial ramdisk the bootloader noted in `/chosen`.
+pub fn initrd_region(dtb: &Dtb) -> Option<Range> {
+    // one or two cells, depending on who wrote the tree
+    fn value(stream: &mut ByteStream) -> Option<usize> {
+        let len = stream.len() as u32;
+        stream.usize_bytes(len)
+    }
+
+    let chosen = dtb.root().childern().nammed(b"chosen").next()?;
+    let start = chosen.properties().find_value(b"linux,initrd-start", value)?;
+    let end = chosen.properties().find_value(b"linux,initrd-end", value)?;
+    (start < end).then_some(start..end)
+}
+
 pub fn physical_region(dtb: &Dtb) -> Range {
     let node = dtb
         .nodes()
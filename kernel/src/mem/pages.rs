@@ -11,6 +11,65 @@ pub static BUDDY: CriticalSpinLock<Buddy> = CriticalSpinLock::new(Buddy::new());
 
 pub type PagePtr = Pointer<Page>;
 
+/// Extra owners of every physical page beyond the first, so a count of zero
+/// means the page has a single owner. Only pages shared by fork ever get more.
+struct PageRefs {
+    base: usize,
+    counts: &'static mut [u32],
+}
+
+static PAGE_REFS: CriticalSpinLock<PageRefs> = CriticalSpinLock::new(PageRefs {
+    base: 0,
+    counts: &mut [],
+});
+
+impl PageRefs {
+    fn count(&mut self, page: PagePtr) -> &mut u32 {
+        let index = (page.phys() as usize - self.base) >> 12;
+        self.counts
+            .get_mut(index)
+            .expect("page outside of physical memory")
+    }
+}
+
+/// Adds an owner to `page`, it is only freed once every owner called [`put_page`].
+pub fn get_page(page: PagePtr) {
+    *PAGE_REFS.lock().count(page) += 1;
+}
+
+/// Drops an owner of `page`, freeing it if that was the last one.
+///
+/// # Safety
+/// The caller must own a reference to `page` and not touch it afterwards.
+pub unsafe fn put_page(page: PagePtr) {
+    let last = {
+        let mut refs = PAGE_REFS.lock();
+        let count = refs.count(page);
+        let last = *count == 0;
+        *count = count.saturating_sub(1);
+        last
+    };
+    if last {
+        unsafe { free_page(page) };
+    }
+}
+
+/// Whether more than one owner currently holds `page`.
+pub fn page_shared(page: PagePtr) -> bool {
+    *PAGE_REFS.lock().count(page) > 0
+}
+
+/// Sets up the reference counts covering `mem`, must run once the buddy allocator has memory.
+fn init_page_refs(mem: core::ops::Range<usize>) {
+    let pages = (mem.end - mem.start) >> 12;
+    let table_pages = (pages * core::mem::size_of::<u32>()).div_ceil(1 << 12);
+    let table = unsafe { pages_zeroed(table_pages) };
+
+    let mut refs = PAGE_REFS.lock();
+    refs.base = mem.start;
+    refs.counts = unsafe { core::slice::from_raw_parts_mut(table.virt().cast::<u32>(), pages) };
+}
+
 /// # Safety
 /// .
 pub unsafe fn page_zeroed() -> PagePtr {
@@ -89,9 +148,12 @@ pub unsafe fn discover(dtb: &crate::dtb::Dtb, vma: usize, pma: usize) {
     let kernel_range_phys_end = kernel_layout_virt.total.end - vma + pma;
     let kernel_range_phys = kernel_range_phys_start..kernel_range_phys_end;
 
+    let initrd_range_phys = crate::mem::initrd_region(dtb);
+
     let reserved = |page: core::ops::Range<usize>| {
         crate::mem::reserved_regions(dtb)
             .chain([kernel_range_phys.clone(), dtb_range_phys.clone()])
+            .chain(initrd_range_phys.clone())
             .any(|reserved| (page.start < reserved.end) & (reserved.start < page.end))
     };
 
@@ -99,6 +161,7 @@ pub unsafe fn discover(dtb: &crate::dtb::Dtb, vma: usize, pma: usize) {
 
     println!("kernel region phys: {kernel_range_phys:#x?}");
     println!("dtb    region phys: {dtb_range_phys:#x?}");
+    println!("initrd region phys: {initrd_range_phys:#x?}");
     println!("memory region phys: {mem:#x?}");
 
     // for page in (mem.start..mem.end).step_by(page_size){
@@ -147,5 +210,7 @@ pub unsafe fn discover(dtb: &crate::dtb::Dtb, vma: usize, pma: usize) {
 
     BUDDY.lock().print();
 
+    init_page_refs(mem.start..mem.end);
+
     println!("Memory discovery complete");
 }
@@ -0,0 +1,559 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    alloc::{collections::BTreeMap, vec::Vec},
+    arch::{
+        page::{self, Page, PageTable, PageTableEntry, PageTableRoot},
+        tlb,
+    },
+    mem::{KERNEL_MAP, Pointer, pages},
+    sync::mutex::CriticalSpinLock,
+};
+
+const PAGE_SIZE: usize = core::mem::size_of::<Page>();
+
+/// First address past user space. Kept at the size of the Sv39 lower half so the
+/// user layout doesn't depend on the paging mode picked at boot.
+pub const USER_END: usize = 1 << 38;
+
+/// Mappings placed by the kernel, like `mmap` without an address, are handed out upwards from here.
+pub const MMAP_BASE: usize = 0x10_0000_0000;
+
+/// RSW value marking a user page that is shared read-only and gets copied on the first store.
+pub const RSW_COW: u64 = 0b01;
+
+/// Root entries from here on map the kernel and are shared by every address space.
+const KERNEL_ROOT_ENTRIES: core::ops::Range<usize> = 256..512;
+
+struct Asids {
+    /// Number of implemented ASID bits, `None` until probed.
+    bits: Option<u32>,
+    next: u16,
+    free: Vec<u16>,
+}
+
+static ASIDS: CriticalSpinLock<Asids> = CriticalSpinLock::new(Asids {
+    bits: None,
+    next: 1,
+    free: Vec::new(),
+});
+
+/// Finds how many ASID bits the hart implements by writing all ones and reading back.
+fn probe_asid_bits() -> u32 {
+    use riscv::register::satp;
+    let old = satp::read();
+    unsafe {
+        satp::set(old.mode(), 0xFFFF, old.ppn());
+        let bits = (satp::read().asid() as u16).count_ones();
+        satp::set(old.mode(), old.asid(), old.ppn());
+        bits
+    }
+}
+
+/// ASID 0 belongs to the kernel and is also handed out once the ASID space is
+/// exhausted, such spaces get a full TLB flush on every switch.
+fn alloc_asid() -> u16 {
+    let mut asids = ASIDS.lock();
+    let bits = *asids.bits.get_or_insert_with(probe_asid_bits);
+
+    let asid = if let Some(asid) = asids.free.pop() {
+        asid
+    } else if (asids.next as u32) < (1 << bits) {
+        asids.next += 1;
+        asids.next - 1
+    } else {
+        0
+    };
+    drop(asids);
+
+    // a reused ASID may still have stale translations cached
+    tlb::shootdown(0..USER_END, asid);
+    asid
+}
+
+fn free_asid(asid: u16) {
+    if asid != 0 {
+        ASIDS.lock().free.push(asid);
+    }
+}
+
+fn table_supplier() -> Pointer<PageTable> {
+    unsafe { pages::page_zeroed().cast() }
+}
+
+/// The kind of access that caused a page fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A region of user memory the program may touch, with the permissions its pages get.
+#[derive(Clone, Copy, Debug)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub entry: PageTableEntry,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.entry.readable(),
+            Access::Write => self.entry.writable(),
+            Access::Execute => self.entry.executable(),
+        }
+    }
+}
+
+/// A user address space: the lower half is private, the upper half mirrors the kernel map.
+pub struct AddressSpace {
+    asid: u16,
+    root: Pointer<PageTable>,
+    map: CriticalSpinLock<PageTableRoot>,
+    /// Every valid user region keyed by its start, pages inside them are mapped on first touch.
+    vmas: CriticalSpinLock<BTreeMap<usize, Vma>>,
+    mmap_next: AtomicUsize,
+}
+
+unsafe impl Send for AddressSpace {}
+unsafe impl Sync for AddressSpace {}
+
+impl core::fmt::Debug for AddressSpace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AddressSpace")
+            .field("asid", &self.asid)
+            .field("root", &self.root)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AddressSpace {
+    pub fn new() -> Self {
+        let root = table_supplier();
+
+        {
+            let kernel = KERNEL_MAP.lock();
+            let kernel = kernel.as_ref().expect("kernel map not initialized").root();
+            unsafe {
+                (&mut (*root.virt()).entries)[KERNEL_ROOT_ENTRIES]
+                    .copy_from_slice(&(&(*kernel.virt()).entries)[KERNEL_ROOT_ENTRIES]);
+            }
+        }
+
+        Self {
+            asid: alloc_asid(),
+            root,
+            map: CriticalSpinLock::new(unsafe { PageTableRoot::from(root) }),
+            vmas: CriticalSpinLock::new(BTreeMap::new()),
+            mmap_next: AtomicUsize::new(MMAP_BASE),
+        }
+    }
+
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    pub fn root(&self) -> Pointer<PageTable> {
+        self.root
+    }
+
+    pub fn map(&self) -> &CriticalSpinLock<PageTableRoot> {
+        &self.map
+    }
+
+    /// Picks an unused page aligned range of `size` bytes for a mapping without a fixed address.
+    pub fn reserve(&self, size: usize) -> Option<usize> {
+        let size = size.checked_next_multiple_of(PAGE_SIZE)?;
+        // only moves on when the whole range fits, so a failed reservation uses up nothing
+        self.mmap_next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |base| {
+                base.checked_add(size).filter(|&end| end <= USER_END)
+            })
+            .ok()
+    }
+
+    /// The region containing `addr`, if any.
+    pub fn vma(&self, addr: usize) -> Option<Vma> {
+        self.vmas
+            .lock()
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| *vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Records `virt..virt + size` as valid user memory without backing it,
+    /// zeroed pages are mapped in as they are first touched.
+    pub fn map_lazy(&self, virt: usize, size: usize, entry: PageTableEntry) -> Result<(), ()> {
+        if !virt.is_multiple_of(PAGE_SIZE) || size == 0 {
+            return Err(());
+        }
+        let end = size
+            .checked_next_multiple_of(PAGE_SIZE)
+            .and_then(|size| virt.checked_add(size))
+            .filter(|&end| end <= USER_END)
+            .ok_or(())?;
+
+        let mut vmas = self.vmas.lock();
+        let overlaps = vmas
+            .range(..end)
+            .next_back()
+            .is_some_and(|(_, vma)| vma.end > virt);
+        if overlaps {
+            return Err(());
+        }
+        vmas.insert(
+            virt,
+            Vma {
+                start: virt,
+                end,
+                entry: entry.set_user(true) | PageTableEntry::DIRTY_ACCESSED,
+            },
+        );
+        Ok(())
+    }
+
+    /// Resolves a fault at `addr`, either by mapping a zeroed page or by breaking
+    /// copy-on-write sharing, if a region allows the access. `Err` means the access
+    /// was a genuine violation.
+    pub fn handle_fault(&self, addr: usize, access: Access) -> Result<(), ()> {
+        let vma = self.vma(addr).ok_or(())?;
+        if !vma.allows(access) {
+            return Err(());
+        }
+
+        let virt = addr & !(PAGE_SIZE - 1);
+        let mut map = self.map.lock();
+
+        if let Some(entry) = map.leaf_mut(virt) {
+            // already present, so only a store to a COW page is something we can fix
+            if access != Access::Write || entry.rsw() != RSW_COW {
+                return Err(());
+            }
+            let old: Pointer<Page> = Pointer::from_phys((entry.ppn() << 12) as *mut Page);
+            if pages::page_shared(old) {
+                let new = unsafe { pages::page_zeroed() };
+                unsafe {
+                    new.virt().copy_from_nonoverlapping(old.virt(), 1);
+                    pages::put_page(old);
+                }
+                *entry = entry.set_ppn(new.phys() as u64 >> 12);
+            }
+            *entry = entry.set_writable(true).set_rsw(0);
+        } else {
+            let page = unsafe { pages::page_zeroed() };
+            if let Err(err) =
+                map.map_phys_page(virt, page.phys() as usize, vma.entry, table_supplier)
+            {
+                unsafe { pages::free_page(page) };
+                return Err(err);
+            }
+        }
+        drop(map);
+
+        tlb::flush_page(virt, self.asid);
+        Ok(())
+    }
+
+    /// Faults in every page of `start..start + len` for `access` up front, so the
+    /// kernel can touch them during a syscall, possibly holding locks, without
+    /// taking a page fault. `Err` if any of it isn't user memory allowing `access`.
+    pub fn fault_in(&self, start: usize, len: usize, access: Access) -> Result<(), ()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = start
+            .checked_add(len)
+            .filter(|&end| end <= USER_END)
+            .ok_or(())?;
+        for virt in (start & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+            let ready = self.map.lock().leaf_mut(virt).is_some_and(|entry| match access {
+                Access::Read => entry.readable(),
+                Access::Write => entry.writable(),
+                Access::Execute => entry.executable(),
+            });
+            if !ready {
+                self.handle_fault(virt, access)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Duplicates this address space for `fork()`. Writable pages become read-only
+    /// and marked [`RSW_COW`] in both spaces, so neither sees the other's stores.
+    pub fn fork(&self) -> AddressSpace {
+        let child = AddressSpace::new();
+        *child.vmas.lock() = self.vmas.lock().clone();
+        child
+            .mmap_next
+            .store(self.mmap_next.load(Ordering::Relaxed), Ordering::Relaxed);
+
+        {
+            let parent_map = self.map.lock();
+            let mut child_map = child.map.lock();
+            unsafe {
+                for_each_user_leaf(parent_map.root(), page::levels() - 1, 0, &mut |virt, entry: &mut PageTableEntry| {
+                    if entry.writable() {
+                        *entry = entry.set_writable(false).set_rsw(RSW_COW);
+                    }
+                    pages::get_page(Pointer::from_phys((entry.ppn() << 12) as *mut Page));
+                    child_map
+                        .map_phys_page(virt, (entry.ppn() << 12) as usize, *entry, table_supplier)
+                        .expect("fresh address space already mapped");
+                });
+            }
+        }
+
+        // the parent may have the now read-only pages cached as writable
+        tlb::shootdown(0..USER_END, self.asid);
+        child
+    }
+
+    /// Removes every region and mapping in `virt..virt + size`, releasing the pages
+    /// once no hart can still reach them through a stale translation.
+    pub fn unmap(&self, virt: usize, size: usize) -> Result<(), ()> {
+        let range = page_range(virt, size)?;
+        carve_vmas(&mut self.vmas.lock(), range.clone());
+
+        let mut released = Vec::new();
+        let result = self.map.lock().unmap_region(range.start, range.len(), |entry, _| {
+            released.push(Pointer::from_phys((entry.ppn() << 12) as *mut Page));
+        });
+
+        tlb::shootdown(range, self.asid);
+        for page in released {
+            unsafe { pages::put_page(page) };
+        }
+        result
+    }
+
+    /// Changes the permissions of every region and mapped page in `virt..virt + size` to `entry`.
+    pub fn protect(&self, virt: usize, size: usize, entry: PageTableEntry) -> Result<(), ()> {
+        let range = page_range(virt, size)?;
+        let entry = entry.set_user(true) | PageTableEntry::DIRTY_ACCESSED;
+        if !entry.is_leaf() {
+            return Err(());
+        }
+
+        {
+            let mut vmas = self.vmas.lock();
+            for mut vma in carve_vmas(&mut vmas, range.clone()) {
+                vma.entry = entry;
+                vmas.insert(vma.start, vma);
+            }
+        }
+
+        let mut map = self.map.lock();
+        // COW pages stay read-only, the fault handler grants write once they are copied
+        let result = map.change_perms(range.start, range.len(), entry);
+        if entry.writable() {
+            for addr in range.clone().step_by(PAGE_SIZE) {
+                if let Some(pte) = map.leaf_mut(addr)
+                    && pte.rsw() == RSW_COW
+                {
+                    *pte = pte.set_writable(false);
+                }
+            }
+        }
+        drop(map);
+
+        tlb::shootdown(range, self.asid);
+        result
+    }
+
+    /// Backs `virt..virt + size` with freshly zeroed pages, accessible from U-mode
+    /// with the permissions in `entry`.
+    pub fn map_anonymous(&self, virt: usize, size: usize, entry: PageTableEntry) -> Result<(), ()> {
+        self.map_data(virt, &[], size, entry)
+    }
+
+    /// Like [`map_anonymous`](Self::map_anonymous) but the region starts out holding `data`.
+    pub fn map_data(
+        &self,
+        virt: usize,
+        data: &[u8],
+        size: usize,
+        entry: PageTableEntry,
+    ) -> Result<(), ()> {
+        if data.len() > size {
+            return Err(());
+        }
+        self.map_with(virt, size, entry, |offset, page| {
+            let chunk = data.get(offset..).unwrap_or(&[]);
+            let chunk = &chunk[..chunk.len().min(PAGE_SIZE)];
+            page[..chunk.len()].copy_from_slice(chunk);
+        })
+    }
+
+    /// Maps zeroed pages over `virt..virt + size` and lets `fill` initialize each
+    /// one, it is passed the page's offset into the region.
+    pub fn map_with(
+        &self,
+        virt: usize,
+        size: usize,
+        entry: PageTableEntry,
+        mut fill: impl FnMut(usize, &mut [u8]),
+    ) -> Result<(), ()> {
+        self.map_lazy(virt, size, entry)?;
+
+        let entry = entry.set_user(true) | PageTableEntry::DIRTY_ACCESSED;
+        let mut map = self.map.lock();
+
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let page = unsafe { pages::page_zeroed() };
+            fill(offset, unsafe {
+                core::slice::from_raw_parts_mut(page.virt().cast::<u8>(), PAGE_SIZE)
+            });
+
+            if let Err(err) =
+                map.map_phys_page(virt + offset, page.phys() as usize, entry, table_supplier)
+            {
+                unsafe { pages::free_page(page) };
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Switches the current hart to this address space.
+    pub fn activate(&self) {
+        activate(self.root, self.asid);
+    }
+}
+
+fn page_range(virt: usize, size: usize) -> Result<Range<usize>, ()> {
+    let end = size
+        .checked_next_multiple_of(PAGE_SIZE)
+        .and_then(|size| virt.checked_add(size))
+        .ok_or(())?;
+    if !virt.is_multiple_of(PAGE_SIZE) || end > USER_END {
+        return Err(());
+    }
+    Ok(virt..end)
+}
+
+/// Cuts `range` out of every region overlapping it, returning the pieces that were inside.
+fn carve_vmas(vmas: &mut BTreeMap<usize, Vma>, range: Range<usize>) -> Vec<Vma> {
+    let overlapping: Vec<Vma> = vmas
+        .range(..range.end)
+        .map(|(_, vma)| *vma)
+        .filter(|vma| vma.end > range.start)
+        .collect();
+
+    let mut inside = Vec::new();
+    for vma in overlapping {
+        vmas.remove(&vma.start);
+        if vma.start < range.start {
+            vmas.insert(vma.start, Vma { end: range.start, ..vma });
+        }
+        if vma.end > range.end {
+            vmas.insert(range.end, Vma { start: range.end, ..vma });
+        }
+        inside.push(Vma {
+            start: vma.start.max(range.start),
+            end: vma.end.min(range.end),
+            ..vma
+        });
+    }
+    inside
+}
+
+/// Switches back to the kernel's own address space.
+pub fn activate_kernel() {
+    let root = KERNEL_MAP
+        .lock()
+        .as_ref()
+        .expect("kernel map not initialized")
+        .root();
+    activate(root, 0);
+}
+
+fn activate(root: Pointer<PageTable>, asid: u16) {
+    unsafe {
+        riscv::register::satp::set(
+            page::satp_mode(),
+            asid as usize,
+            root.phys() as usize >> 12,
+        );
+    }
+    if asid == 0 {
+        tlb::flush_all();
+    }
+}
+
+/// Calls `f` with the address and entry of every user page mapped below `table`,
+/// which covers addresses from `base` at the given level.
+unsafe fn for_each_user_leaf(
+    table: Pointer<PageTable>,
+    level: usize,
+    base: usize,
+    f: &mut dyn FnMut(usize, &mut PageTableEntry),
+) {
+    let entries = unsafe { &mut (*table.virt()).entries };
+    let count = if level == page::levels() - 1 {
+        KERNEL_ROOT_ENTRIES.start
+    } else {
+        entries.len()
+    };
+    for (i, entry) in entries[..count].iter_mut().enumerate() {
+        if !entry.valid() {
+            continue;
+        }
+        let virt = base + (i << (12 + 9 * level));
+        if !entry.is_leaf() {
+            let next = Pointer::from_phys((entry.ppn() << 12) as *mut PageTable);
+            unsafe { for_each_user_leaf(next, level - 1, virt, f) };
+        } else if entry.user() {
+            f(virt, entry);
+        }
+    }
+}
+
+/// Frees every page table below `table` along with the user pages they map.
+unsafe fn free_user_table(table: Pointer<PageTable>, level: usize) {
+    for entry in unsafe { &(*table.virt()).entries } {
+        if !entry.valid() {
+            continue;
+        }
+        let next = Pointer::from_phys((entry.ppn() << 12) as *mut Page);
+        if !entry.is_leaf() {
+            unsafe { free_user_table(next.cast(), level - 1) };
+        } else if level == 0 && entry.user() {
+            // may still be shared copy-on-write with another space
+            unsafe { pages::put_page(next) };
+        }
+    }
+    unsafe { pages::free_page(table.cast()) };
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // no hart may still walk the tables once they are freed
+        tlb::shootdown(0..USER_END, self.asid);
+
+        let root = unsafe { &*self.root.virt() };
+        for entry in &root.entries[..KERNEL_ROOT_ENTRIES.start] {
+            if entry.valid() && !entry.is_leaf() {
+                unsafe {
+                    free_user_table(
+                        Pointer::from_phys((entry.ppn() << 12) as *mut PageTable),
+                        page::levels() - 2,
+                    );
+                }
+            }
+        }
+        unsafe {
+            pages::free_page(self.root.cast());
+        }
+        free_asid(self.asid);
+    }
+}
@@ -0,0 +1,71 @@
+use core::ffi::CStr;
+
+/// Entry of `.ksymtab`, written by `xbuild` sorted by address.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+struct KSym {
+    addr: u64,
+    name_off: u32,
+    size: u32,
+}
+
+unsafe extern "C" {
+    #[link_name = "_ksymtab_start"]
+    static ksymtab_start: KSym;
+    #[link_name = "_ksymtab_end"]
+    static ksymtab_end: KSym;
+
+    #[link_name = "_kstrtab_start"]
+    static kstrtab_start: u8;
+    #[link_name = "_kstrtab_end"]
+    static kstrtab_end: u8;
+
+    #[link_name = "_kernel_start"]
+    static kernel_start: u8;
+}
+
+/// Address the kernel is linked at, see `KERNEL_LINK_ADDR` in `link.map`.
+const KERNEL_LINK_ADDR: usize = 0usize.wrapping_sub(2 * 1024 * 1024 * 1024);
+
+fn symbols() -> &'static [KSym] {
+    let start = &raw const ksymtab_start;
+    let end = &raw const ksymtab_end;
+    let len = (end as usize - start as usize) / core::mem::size_of::<KSym>();
+    unsafe { core::slice::from_raw_parts(start, len) }
+}
+
+fn strings() -> &'static [u8] {
+    let start = &raw const kstrtab_start;
+    let end = &raw const kstrtab_end;
+    unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) }
+}
+
+/// Difference between where the kernel runs and where it was linked, which is
+/// only non zero before paging is set up.
+fn slide() -> usize {
+    (&raw const kernel_start as usize).wrapping_sub(KERNEL_LINK_ADDR)
+}
+
+/// Whether the symbol table was linked in, it is empty unless `xbuild` relinked the kernel.
+pub fn available() -> bool {
+    !symbols().is_empty()
+}
+
+/// Finds the function containing `addr`, returning its demangled name and the offset into it.
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    let addr = addr.wrapping_sub(slide()) as u64;
+    let symbols = symbols();
+
+    let index = symbols.partition_point(|sym| sym.addr <= addr).checked_sub(1)?;
+    let sym = symbols[index];
+    if sym.size != 0 && addr >= sym.addr + sym.size as u64 {
+        return None;
+    }
+
+    let name = strings().get(sym.name_off as usize..)?;
+    let name = CStr::from_bytes_until_nul(name).ok()?;
+    Some((
+        name.to_str().unwrap_or("<invalid name>"),
+        (addr - sym.addr) as usize,
+    ))
+}
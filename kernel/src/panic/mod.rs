@@ -1,6 +1,11 @@
 use core::{fmt::Write, panic::PanicInfo, sync::atomic::AtomicBool};
 
-use crate::{arch, print};
+use crate::{arch, print, println};
+
+pub mod ksyms;
+
+/// Frames printed at most, in case a corrupted stack links back on itself.
+const MAX_BACKTRACE: usize = 32;
 
 static PANICKED: AtomicBool = AtomicBool::new(false);
 
@@ -31,6 +36,8 @@ pub fn print_u32_dec(out: &mut crate::std::stdio::Sout, mut n: u32) {
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // whatever was queued before the panic explains it, get it out first
+    crate::dev::tty::flush();
     crate::std::stdio::set_sout(|str| {
         arch::entry::early_print(str);
     });
@@ -40,5 +47,53 @@ fn panic(info: &PanicInfo) -> ! {
     let mut out = crate::std::stdio::sout();
     _ = out.write_str("\nKERNEL PANIC\n");
     print!("{info}");
+    println!();
+    print_backtrace();
     arch::halt()
 }
+
+/// Prints `addr  name+offset` for a code address.
+///
+/// Return addresses are looked up one byte earlier, since the call can be the
+/// last instruction of a function that never returns.
+fn print_symbol(addr: usize, return_address: bool) {
+    let lookup = if return_address { addr.wrapping_sub(1) } else { addr };
+    match ksyms::lookup(lookup) {
+        Some((name, offset)) => {
+            let offset = offset + (addr - lookup);
+            println!("  0x{addr:016x}  {name}+0x{offset:x}")
+        }
+        None => println!("  0x{addr:016x}  <unknown>"),
+    }
+}
+
+/// Prints the call stack leading up to the caller.
+#[inline(never)]
+pub fn print_backtrace() {
+    print_header();
+    unsafe { print_return_addresses(arch::trace::fp()) }
+}
+
+/// Prints the call stack of an interrupted context, starting at its `pc` and walking up from `fp`.
+///
+/// # Safety
+///
+/// `fp` has to be a frame pointer on a mapped stack.
+pub unsafe fn print_backtrace_at(pc: usize, fp: usize) {
+    print_header();
+    print_symbol(pc, false);
+    unsafe { print_return_addresses(fp) }
+}
+
+fn print_header() {
+    if !ksyms::available() {
+        println!("no kernel symbols, build with xbuild to get them");
+    }
+    println!("backtrace:");
+}
+
+unsafe fn print_return_addresses(fp: usize) {
+    for addr in unsafe { arch::trace::return_addresses(fp) }.take(MAX_BACKTRACE) {
+        print_symbol(addr, true);
+    }
+}
@@ -1,3 +1,71 @@
+//! Calls into the SBI firmware below us, either OpenSBI or our own in `arch::riscv64::mtrap`.
+//!
+//! [`init`] probes which extensions the firmware has, other subsystems check
+//! [`has`] before relying on one and fall back to driving the hardware directly.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{alloc::vec::Vec, println};
+
+pub const SBI_SUCCESS: isize = 0;
+pub const SBI_ERR_FAILED: isize = -1;
+pub const SBI_ERR_NOT_SUPPORTED: isize = -2;
+pub const SBI_ERR_INVALID_PARAM: isize = -3;
+pub const SBI_ERR_DENIED: isize = -4;
+pub const SBI_ERR_INVALID_ADDRESS: isize = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: isize = -6;
+pub const SBI_ERR_ALREADY_STARTED: isize = -7;
+pub const SBI_ERR_ALREADY_STOPPED: isize = -8;
+pub const SBI_ERR_NO_SHMEM: isize = -9;
+
+/// `set_timer` of the legacy extensions, from before SBI 0.2 grew the TIME extension.
+pub const SBI_EXT_LEGACY_SET_TIMER: usize = 0x00;
+
+pub const SBI_EXT_BASE: usize = 0x10;
+pub const SBI_FID_GET_SPEC_VERSION: usize = 0;
+pub const SBI_FID_GET_IMPL_ID: usize = 1;
+pub const SBI_FID_GET_IMPL_VERSION: usize = 2;
+pub const SBI_FID_PROBE_EXTENSION: usize = 3;
+pub const SBI_FID_GET_MVENDORID: usize = 4;
+pub const SBI_FID_GET_MARCHID: usize = 5;
+pub const SBI_FID_GET_MIMPID: usize = 6;
+
+pub const SBI_EXT_RFENCE: usize = 0x52464E43; // "RFNC"
+pub const SBI_FID_REMOTE_FENCE_I: usize = 0;
+pub const SBI_FID_REMOTE_SFENCE_VMA: usize = 1;
+pub const SBI_FID_REMOTE_SFENCE_VMA_ASID: usize = 2;
+
+pub const SBI_EXT_SRST: usize = 0x53525354; // "SRST"
+pub const SBI_FID_SYSTEM_RESET: usize = 0;
+pub const SBI_RESET_TYPE_SHUTDOWN: usize = 0;
+pub const SBI_RESET_TYPE_COLD_REBOOT: usize = 1;
+pub const SBI_RESET_TYPE_WARM_REBOOT: usize = 2;
+pub const SBI_RESET_REASON_NONE: usize = 0;
+pub const SBI_RESET_REASON_SYSTEM_FAILURE: usize = 1;
+
+pub const SBI_EXT_DBCN: usize = 0x4442434E; // "DBCN"
+pub const SBI_FID_CONSOLE_WRITE: usize = 0;
+pub const SBI_FID_CONSOLE_READ: usize = 1;
+pub const SBI_FID_CONSOLE_WRITE_BYTE: usize = 2;
+
+pub const SBI_EXT_PMU: usize = 0x504D55; // "PMU"
+pub const SBI_FID_PMU_NUM_COUNTERS: usize = 0;
+pub const SBI_FID_PMU_COUNTER_GET_INFO: usize = 1;
+pub const SBI_FID_PMU_COUNTER_CONFIG_MATCHING: usize = 2;
+pub const SBI_FID_PMU_COUNTER_START: usize = 3;
+pub const SBI_FID_PMU_COUNTER_STOP: usize = 4;
+pub const SBI_FID_PMU_COUNTER_FW_READ: usize = 5;
+
+pub const SBI_PMU_CFG_FLAG_SKIP_MATCH: usize = 1 << 0;
+pub const SBI_PMU_CFG_FLAG_CLEAR_VALUE: usize = 1 << 1;
+pub const SBI_PMU_CFG_FLAG_AUTO_START: usize = 1 << 2;
+pub const SBI_PMU_START_FLAG_SET_INIT_VALUE: usize = 1 << 0;
+pub const SBI_PMU_STOP_FLAG_RESET: usize = 1 << 0;
+
+/// Implementation ids of the base extension worth telling apart.
+pub const SBI_IMPL_BBL: usize = 0;
+pub const SBI_IMPL_OPENSBI: usize = 1;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct SbiRet {
@@ -5,6 +73,49 @@ pub struct SbiRet {
     pub value: isize,
 }
 
+impl SbiRet {
+    pub fn into_result(self) -> SbiResult<usize> {
+        match self.error {
+            SBI_SUCCESS => Ok(self.value as usize),
+            error => Err(SbiError::from_code(error)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    NoShmem,
+    /// An error code newer than this kernel.
+    Unknown(isize),
+}
+
+impl SbiError {
+    pub fn from_code(code: isize) -> Self {
+        match code {
+            SBI_ERR_FAILED => Self::Failed,
+            SBI_ERR_NOT_SUPPORTED => Self::NotSupported,
+            SBI_ERR_INVALID_PARAM => Self::InvalidParam,
+            SBI_ERR_DENIED => Self::Denied,
+            SBI_ERR_INVALID_ADDRESS => Self::InvalidAddress,
+            SBI_ERR_ALREADY_AVAILABLE => Self::AlreadyAvailable,
+            SBI_ERR_ALREADY_STARTED => Self::AlreadyStarted,
+            SBI_ERR_ALREADY_STOPPED => Self::AlreadyStopped,
+            SBI_ERR_NO_SHMEM => Self::NoShmem,
+            code => Self::Unknown(code),
+        }
+    }
+}
+
+pub type SbiResult<T> = Result<T, SbiError>;
+
 #[inline(always)]
 #[allow(unsafe_op_in_unsafe_fn)]
 #[allow(clippy::too_many_arguments)]
@@ -39,21 +150,486 @@ unsafe fn sbi_ecall(
     SbiRet { error, value }
 }
 
-const SBI_EXT_TIME: usize = 0x54494D45; // "TIME"
-const SBI_FID_SET_TIMER: usize = 0;
+/// An ecall with up to three arguments, which is all most functions take.
+fn call(ext: usize, fid: usize, args: [usize; 3]) -> SbiResult<usize> {
+    let [arg0, arg1, arg2] = args;
+    unsafe { sbi_ecall(ext, fid, arg0, arg1, arg2, 0, 0, 0) }.into_result()
+}
+
+// -------------------------
+// Extension probing
+// -------------------------
+
+/// The extensions beyond base that [`init`] looks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Extension {
+    Time,
+    Ipi,
+    Rfence,
+    Hsm,
+    Srst,
+    Dbcn,
+    Pmu,
+}
+
+impl Extension {
+    pub const ALL: [Extension; 7] = [
+        Self::Time,
+        Self::Ipi,
+        Self::Rfence,
+        Self::Hsm,
+        Self::Srst,
+        Self::Dbcn,
+        Self::Pmu,
+    ];
+
+    pub const fn id(self) -> usize {
+        match self {
+            Self::Time => SBI_EXT_TIME,
+            Self::Ipi => SBI_EXT_IPI,
+            Self::Rfence => SBI_EXT_RFENCE,
+            Self::Hsm => SBI_EXT_HSM,
+            Self::Srst => SBI_EXT_SRST,
+            Self::Dbcn => SBI_EXT_DBCN,
+            Self::Pmu => SBI_EXT_PMU,
+        }
+    }
+
+    const fn bit(self) -> usize {
+        1 << self as usize
+    }
+}
+
+/// Extensions found by [`init`], one [`Extension::bit`] each.
+static EXTENSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the firmware provides `ext`. Always false before [`init`].
+pub fn has(ext: Extension) -> bool {
+    EXTENSIONS.load(Ordering::Relaxed) & ext.bit() != 0
+}
+
+/// Asks the firmware what it implements.
+pub fn init() {
+    let (major, minor) = sbi_spec_version();
+    let name = match sbi_impl_id() {
+        SBI_IMPL_BBL => "BBL",
+        SBI_IMPL_OPENSBI => "OpenSBI",
+        _ if crate::arch::entry::builtin_firmware() => "builtin",
+        _ => "unknown",
+    };
+    println!(
+        "SBI {major}.{minor}, {name} firmware version {:#x}",
+        sbi_impl_version()
+    );
+
+    let mut found = 0;
+    for ext in Extension::ALL {
+        if sbi_probe_extension(ext.id()) {
+            found |= ext.bit();
+        }
+    }
+    EXTENSIONS.store(found, Ordering::Relaxed);
 
+    let found: Vec<_> = Extension::ALL.into_iter().filter(|ext| has(*ext)).collect();
+    println!("SBI extensions: {found:?}");
+}
+
+// -------------------------
+// Base
+// -------------------------
+
+/// The SBI specification version as `(major, minor)`.
+pub fn sbi_spec_version() -> (usize, usize) {
+    // the base extension can't fail
+    let version = call(SBI_EXT_BASE, SBI_FID_GET_SPEC_VERSION, [0; 3]).unwrap_or(0);
+    ((version >> 24) & 0x7f, version & 0xff_ffff)
+}
+
+pub fn sbi_impl_id() -> usize {
+    call(SBI_EXT_BASE, SBI_FID_GET_IMPL_ID, [0; 3]).unwrap_or(usize::MAX)
+}
+
+pub fn sbi_impl_version() -> usize {
+    call(SBI_EXT_BASE, SBI_FID_GET_IMPL_VERSION, [0; 3]).unwrap_or(0)
+}
+
+pub fn sbi_probe_extension(ext: usize) -> bool {
+    call(SBI_EXT_BASE, SBI_FID_PROBE_EXTENSION, [ext, 0, 0]).is_ok_and(|found| found != 0)
+}
+
+pub fn sbi_mvendorid() -> usize {
+    call(SBI_EXT_BASE, SBI_FID_GET_MVENDORID, [0; 3]).unwrap_or(0)
+}
+
+pub fn sbi_marchid() -> usize {
+    call(SBI_EXT_BASE, SBI_FID_GET_MARCHID, [0; 3]).unwrap_or(0)
+}
+
+pub fn sbi_mimpid() -> usize {
+    call(SBI_EXT_BASE, SBI_FID_GET_MIMPID, [0; 3]).unwrap_or(0)
+}
+
+// -------------------------
+// TIME
+// -------------------------
+
+pub const SBI_EXT_TIME: usize = 0x54494D45; // "TIME"
+pub const SBI_FID_SET_TIMER: usize = 0;
+
+/// Raises the next S-mode timer interrupt once `time` reaches `deadline`,
+/// through the legacy call on firmware without the TIME extension.
 pub fn sbi_set_timer(deadline: u64) {
+    let ret = if has(Extension::Time) {
+        call(SBI_EXT_TIME, SBI_FID_SET_TIMER, [deadline as usize, 0, 0])
+    } else {
+        // legacy calls return nothing but the error code
+        call(SBI_EXT_LEGACY_SET_TIMER, 0, [deadline as usize, 0, 0]).map(|_| 0)
+    };
+    debug_assert!(ret.is_ok());
+}
+
+// -------------------------
+// IPI
+// -------------------------
+
+pub const SBI_EXT_IPI: usize = 0x735049; // "sPI"
+pub const SBI_FID_SEND_IPI: usize = 0;
+
+/// Raises a supervisor software interrupt on every hart in `hart_mask`, offset by `hart_mask_base`.
+pub fn sbi_send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiResult<()> {
+    call(SBI_EXT_IPI, SBI_FID_SEND_IPI, [hart_mask, hart_mask_base, 0]).map(drop)
+}
+
+// -------------------------
+// RFENCE
+// -------------------------
+
+/// Runs `fence.i` on every hart in the mask.
+pub fn sbi_remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> SbiResult<()> {
+    call(
+        SBI_EXT_RFENCE,
+        SBI_FID_REMOTE_FENCE_I,
+        [hart_mask, hart_mask_base, 0],
+    )
+    .map(drop)
+}
+
+/// Runs `sfence.vma` for `size` bytes from `start` on every hart in the mask,
+/// a `size` of `usize::MAX` flushes everything.
+pub fn sbi_remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+) -> SbiResult<()> {
     unsafe {
-        let ret = sbi_ecall(
-            SBI_EXT_TIME,
-            SBI_FID_SET_TIMER,
-            deadline as usize,
+        sbi_ecall(
+            SBI_EXT_RFENCE,
+            SBI_FID_REMOTE_SFENCE_VMA,
+            hart_mask,
+            hart_mask_base,
+            start,
+            size,
             0,
             0,
+        )
+    }
+    .into_result()
+    .map(drop)
+}
+
+/// Like [`sbi_remote_sfence_vma`], limited to the address space `asid`.
+pub fn sbi_remote_sfence_vma_asid(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+    asid: usize,
+) -> SbiResult<()> {
+    unsafe {
+        sbi_ecall(
+            SBI_EXT_RFENCE,
+            SBI_FID_REMOTE_SFENCE_VMA_ASID,
+            hart_mask,
+            hart_mask_base,
+            start,
+            size,
+            asid,
             0,
+        )
+    }
+    .into_result()
+    .map(drop)
+}
+
+// -------------------------
+// HSM
+// -------------------------
+
+pub const SBI_EXT_HSM: usize = 0x48534D; // "HSM"
+pub const SBI_FID_HART_START: usize = 0;
+pub const SBI_FID_HART_STOP: usize = 1;
+pub const SBI_FID_HART_GET_STATUS: usize = 2;
+pub const SBI_FID_HART_SUSPEND: usize = 3;
+
+pub const SBI_HSM_STATE_STARTED: usize = 0;
+pub const SBI_HSM_STATE_STOPPED: usize = 1;
+pub const SBI_HSM_STATE_START_PENDING: usize = 2;
+pub const SBI_HSM_STATE_STOP_PENDING: usize = 3;
+pub const SBI_HSM_STATE_SUSPENDED: usize = 4;
+pub const SBI_HSM_STATE_SUSPEND_PENDING: usize = 5;
+pub const SBI_HSM_STATE_RESUME_PENDING: usize = 6;
+
+pub const SBI_HSM_SUSPEND_RETENTIVE: usize = 0;
+pub const SBI_HSM_SUSPEND_NON_RETENTIVE: usize = 0x8000_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HartState {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+}
+
+/// Starts the stopped hart `hart_id` in S-mode at the physical address `start_addr`,
+/// with paging off, `a0` set to its hart id and `a1` to `opaque`.
+pub fn sbi_hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> SbiResult<()> {
+    call(
+        SBI_EXT_HSM,
+        SBI_FID_HART_START,
+        [hart_id, start_addr, opaque],
+    )
+    .map(drop)
+}
+
+/// Stops the calling hart, only returning if the firmware refused.
+pub fn sbi_hart_stop() -> SbiError {
+    match call(SBI_EXT_HSM, SBI_FID_HART_STOP, [0; 3]) {
+        Ok(_) => SbiError::Failed,
+        Err(err) => err,
+    }
+}
+
+pub fn sbi_hart_status(hart_id: usize) -> SbiResult<HartState> {
+    match call(SBI_EXT_HSM, SBI_FID_HART_GET_STATUS, [hart_id, 0, 0])? {
+        SBI_HSM_STATE_STARTED => Ok(HartState::Started),
+        SBI_HSM_STATE_STOPPED => Ok(HartState::Stopped),
+        SBI_HSM_STATE_START_PENDING => Ok(HartState::StartPending),
+        SBI_HSM_STATE_STOP_PENDING => Ok(HartState::StopPending),
+        SBI_HSM_STATE_SUSPENDED => Ok(HartState::Suspended),
+        SBI_HSM_STATE_SUSPEND_PENDING => Ok(HartState::SuspendPending),
+        SBI_HSM_STATE_RESUME_PENDING => Ok(HartState::ResumePending),
+        _ => Err(SbiError::Failed),
+    }
+}
+
+/// Waits in a low power state until an interrupt arrives and returns.
+pub fn sbi_hart_suspend_retentive() -> SbiResult<()> {
+    call(
+        SBI_EXT_HSM,
+        SBI_FID_HART_SUSPEND,
+        [SBI_HSM_SUSPEND_RETENTIVE, 0, 0],
+    )
+    .map(drop)
+}
+
+/// Waits in a low power state losing all registers, then restarts at the
+/// physical address `resume_addr` the way [`sbi_hart_start`] would.
+/// Only returns if the firmware refused.
+pub fn sbi_hart_suspend_non_retentive(resume_addr: usize, opaque: usize) -> SbiError {
+    match call(
+        SBI_EXT_HSM,
+        SBI_FID_HART_SUSPEND,
+        [SBI_HSM_SUSPEND_NON_RETENTIVE, resume_addr, opaque],
+    ) {
+        Ok(_) => SbiError::Failed,
+        Err(err) => err,
+    }
+}
+
+// -------------------------
+// SRST
+// -------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetType {
+    Shutdown = SBI_RESET_TYPE_SHUTDOWN as isize,
+    ColdReboot = SBI_RESET_TYPE_COLD_REBOOT as isize,
+    WarmReboot = SBI_RESET_TYPE_WARM_REBOOT as isize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetReason {
+    None = SBI_RESET_REASON_NONE as isize,
+    SystemFailure = SBI_RESET_REASON_SYSTEM_FAILURE as isize,
+}
+
+/// Asks the firmware to shut down or reset the machine, only returning when it can't.
+pub fn sbi_system_reset(reset_type: ResetType, reason: ResetReason) -> SbiError {
+    match call(
+        SBI_EXT_SRST,
+        SBI_FID_SYSTEM_RESET,
+        [reset_type as usize, reason as usize, 0],
+    ) {
+        Ok(_) => SbiError::Failed,
+        Err(err) => err,
+    }
+}
+
+// -------------------------
+// DBCN
+// -------------------------
+
+/// Physical address of the kernel memory at `virt`, which DBCN wants.
+fn phys_addr(virt: usize) -> SbiResult<usize> {
+    let map = crate::mem::KERNEL_MAP.lock();
+    map.as_ref()
+        .and_then(|map| map.translate(virt))
+        .map(|(phys, _)| phys)
+        .ok_or(SbiError::InvalidAddress)
+}
+
+/// How many of the `len` bytes at `addr` lie in the same page, and so are contiguous physically.
+fn page_chunk(addr: usize, len: usize) -> usize {
+    const PAGE_SIZE: usize = 1 << 12;
+    (PAGE_SIZE - addr % PAGE_SIZE).min(len)
+}
+
+/// Writes `bytes` to the firmware's console, returning how many it took.
+pub fn sbi_debug_console_write(bytes: &[u8]) -> SbiResult<usize> {
+    let mut written = 0;
+    while written < bytes.len() {
+        let addr = bytes[written..].as_ptr() as usize;
+        let len = page_chunk(addr, bytes.len() - written);
+        let done = call(
+            SBI_EXT_DBCN,
+            SBI_FID_CONSOLE_WRITE,
+            [len, phys_addr(addr)?, 0],
+        )?;
+        written += done;
+        if done < len {
+            break;
+        }
+    }
+    Ok(written)
+}
+
+/// Reads whatever the firmware's console has ready into `buf`, without waiting.
+pub fn sbi_debug_console_read(buf: &mut [u8]) -> SbiResult<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        let addr = buf[read..].as_mut_ptr() as usize;
+        let len = page_chunk(addr, buf.len() - read);
+        let done = call(
+            SBI_EXT_DBCN,
+            SBI_FID_CONSOLE_READ,
+            [len, phys_addr(addr)?, 0],
+        )?;
+        read += done;
+        if done < len {
+            break;
+        }
+    }
+    Ok(read)
+}
+
+pub fn sbi_debug_console_write_byte(byte: u8) -> SbiResult<()> {
+    call(
+        SBI_EXT_DBCN,
+        SBI_FID_CONSOLE_WRITE_BYTE,
+        [byte as usize, 0, 0],
+    )
+    .map(drop)
+}
+
+// -------------------------
+// PMU
+// -------------------------
+
+/// What [`sbi_pmu_counter_info`] reports about one counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CounterInfo {
+    /// Backed by the CSR `csr`, of which `width + 1` bits count.
+    Hardware { csr: u16, width: u8 },
+    /// Counts firmware events, read with [`sbi_pmu_counter_fw_read`].
+    Firmware,
+}
+
+pub fn sbi_pmu_num_counters() -> SbiResult<usize> {
+    call(SBI_EXT_PMU, SBI_FID_PMU_NUM_COUNTERS, [0; 3])
+}
+
+pub fn sbi_pmu_counter_info(counter: usize) -> SbiResult<CounterInfo> {
+    let info = call(SBI_EXT_PMU, SBI_FID_PMU_COUNTER_GET_INFO, [counter, 0, 0])?;
+    if info >> (usize::BITS - 1) != 0 {
+        Ok(CounterInfo::Firmware)
+    } else {
+        Ok(CounterInfo::Hardware {
+            csr: (info & 0xfff) as u16,
+            width: ((info >> 12) & 0x3f) as u8,
+        })
+    }
+}
+
+/// Finds a counter among `counter_base` and `counter_mask` able to count
+/// `event_idx` and configures it, `flags` being `SBI_PMU_CFG_FLAG_*`.
+pub fn sbi_pmu_counter_config_matching(
+    counter_base: usize,
+    counter_mask: usize,
+    flags: usize,
+    event_idx: usize,
+    event_data: u64,
+) -> SbiResult<usize> {
+    unsafe {
+        sbi_ecall(
+            SBI_EXT_PMU,
+            SBI_FID_PMU_COUNTER_CONFIG_MATCHING,
+            counter_base,
+            counter_mask,
+            flags,
+            event_idx,
+            event_data as usize,
+            0,
+        )
+    }
+    .into_result()
+}
+
+/// Starts the counters in the mask, from `initial_value` with `SBI_PMU_START_FLAG_SET_INIT_VALUE`.
+pub fn sbi_pmu_counter_start(
+    counter_base: usize,
+    counter_mask: usize,
+    flags: usize,
+    initial_value: u64,
+) -> SbiResult<()> {
+    unsafe {
+        sbi_ecall(
+            SBI_EXT_PMU,
+            SBI_FID_PMU_COUNTER_START,
+            counter_base,
+            counter_mask,
+            flags,
+            initial_value as usize,
             0,
             0,
-        );
-        debug_assert!(ret.error == 0);
+        )
     }
+    .into_result()
+    .map(drop)
+}
+
+pub fn sbi_pmu_counter_stop(counter_base: usize, counter_mask: usize, flags: usize) -> SbiResult<()> {
+    call(
+        SBI_EXT_PMU,
+        SBI_FID_PMU_COUNTER_STOP,
+        [counter_base, counter_mask, flags],
+    )
+    .map(drop)
+}
+
+pub fn sbi_pmu_counter_fw_read(counter: usize) -> SbiResult<usize> {
+    call(SBI_EXT_PMU, SBI_FID_PMU_COUNTER_FW_READ, [counter, 0, 0])
 }
@@ -0,0 +1,322 @@
+//! Debug monitor on the console, for poking at the kernel without rebuilding `init_task`.
+
+use crate::{
+    arch::page::PageTable,
+    dev::{pci, syscon, tty},
+    dtb::Dtb,
+    fs::{self, FsError, NodeKind, OpenFile, file},
+    mem::Pointer,
+    print, println,
+    std::stdio,
+};
+
+const LINE_MAX: usize = 256;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&Dtb, &mut core::str::SplitWhitespace) -> Result<(), &'static str>,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "",
+        help: "list commands",
+        run: help,
+    },
+    Command {
+        name: "dtb",
+        usage: "",
+        help: "dump the device tree",
+        run: dtb,
+    },
+    Command {
+        name: "pci",
+        usage: "",
+        help: "list PCI devices",
+        run: pci,
+    },
+    Command {
+        name: "blk",
+        usage: "",
+        help: "list block devices and block cache statistics",
+        run: blk,
+    },
+    Command {
+        name: "mounts",
+        usage: "",
+        help: "list mounted filesystems",
+        run: mounts,
+    },
+    Command {
+        name: "sync",
+        usage: "",
+        help: "write cached changes of every filesystem to disk",
+        run: sync,
+    },
+    Command {
+        name: "ls",
+        usage: "[path]",
+        help: "list a directory",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        usage: "<path>",
+        help: "print a file",
+        run: cat,
+    },
+    Command {
+        name: "write",
+        usage: "<path> [text]",
+        help: "replace a file's contents with text",
+        run: write,
+    },
+    Command {
+        name: "mkdir",
+        usage: "<path>",
+        help: "create a directory",
+        run: mkdir,
+    },
+    Command {
+        name: "rm",
+        usage: "<path>",
+        help: "remove a file or empty directory",
+        run: rm,
+    },
+    Command {
+        name: "mem",
+        usage: "[track on|off|dump]",
+        help: "heap statistics and allocation tracking",
+        run: mem,
+    },
+    Command {
+        name: "pt",
+        usage: "[root]",
+        help: "print the page table at physical address root, the kernel's by default",
+        run: pt,
+    },
+    Command {
+        name: "hexdump",
+        usage: "<addr> <len>",
+        help: "dump memory at a virtual address",
+        run: hexdump,
+    },
+    Command {
+        name: "poweroff",
+        usage: "",
+        help: "power the machine off",
+        run: poweroff,
+    },
+    Command {
+        name: "reboot",
+        usage: "",
+        help: "reset the machine",
+        run: reboot,
+    },
+];
+
+/// Shell task entry, `dtb_ptr` is the boot device tree.
+pub fn main(dtb_ptr: usize) -> usize {
+    let dtb = unsafe { Dtb::from_ptr(dtb_ptr as *const u8).expect("shell needs the device tree") };
+    println!("Kernel debug shell, type `help` for commands");
+
+    let mut line = [0u8; LINE_MAX];
+    loop {
+        print!("> ");
+        let Ok(len) = tty::read(&mut line) else {
+            continue;
+        };
+        let Ok(line) = core::str::from_utf8(&line[..len]) else {
+            println!("invalid utf8");
+            continue;
+        };
+
+        let mut args = line.split_whitespace();
+        let Some(name) = args.next() else {
+            continue;
+        };
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => {
+                if let Err(err) = (command.run)(&dtb, &mut args) {
+                    println!("{err}, usage: {} {}", command.name, command.usage);
+                }
+            }
+            None => println!("unknown command `{name}`"),
+        }
+    }
+}
+
+/// Parses a number, hex with a `0x` prefix and decimal otherwise.
+fn number(arg: Option<&str>) -> Result<usize, &'static str> {
+    let arg = arg.ok_or("missing argument")?;
+    match arg.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }
+    .map_err(|_| "invalid number")
+}
+
+fn help(_: &Dtb, _: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    for command in COMMANDS {
+        println!("{:<10} {:<20} {}", command.name, command.usage, command.help);
+    }
+    Ok(())
+}
+
+fn dtb(dtb: &Dtb, _: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    crate::dtb::dump(stdio::sout(), dtb).map_err(|_| "write failed")
+}
+
+fn pci(_: &Dtb, _: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    pci::pci().enumerate_devices();
+    Ok(())
+}
+
+fn blk(_: &Dtb, _: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    for device in crate::dev::block::devices() {
+        println!(
+            "{:<24} {:>12} sectors{}",
+            device.name(),
+            device.sector_count(),
+            if device.read_only() { " ro" } else { "" }
+        );
+    }
+    let stats = crate::dev::block_cache::stats();
+    println!(
+        "cache: {} blocks, {} dirty, {} hits, {} misses, {} writebacks",
+        stats.blocks, stats.dirty, stats.hits, stats.misses, stats.writebacks
+    );
+    Ok(())
+}
+
+fn fs_error(err: FsError) -> &'static str {
+    match err {
+        FsError::NotFound => "not found",
+        FsError::NotADirectory => "not a directory",
+        FsError::IsADirectory => "is a directory",
+        FsError::AlreadyExists => "already exists",
+        FsError::DirectoryNotEmpty => "directory not empty",
+        FsError::ReadOnly => "read only filesystem",
+        FsError::NoSpace => "no space left",
+        FsError::Interrupted => "interrupted",
+        _ => "filesystem error",
+    }
+}
+
+fn mounts(_: &Dtb, _: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    fs::mount::for_each_mount(|path, fs| println!("{:<10} {}", path, fs.name()));
+    Ok(())
+}
+
+fn sync(_: &Dtb, _: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    fs::mount::sync_all().map_err(fs_error)
+}
+
+fn ls(_: &Dtb, args: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    let dir = fs::resolve(args.next().unwrap_or("/")).map_err(fs_error)?;
+    for entry in dir.read_dir().map_err(fs_error)? {
+        let kind = match entry.kind {
+            NodeKind::File => "file",
+            NodeKind::Directory => "dir",
+            NodeKind::CharDevice => "char",
+        };
+        println!("{kind:<5} {:>10} {}", entry.size, entry.name);
+    }
+    Ok(())
+}
+
+fn cat(_: &Dtb, args: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    let path = args.next().ok_or("missing path")?;
+    let file = OpenFile::open(path, file::O_RDONLY).map_err(fs_error)?;
+    let mut buf = [0u8; 512];
+    loop {
+        let len = file.read(&mut buf).map_err(fs_error)?;
+        if len == 0 {
+            break;
+        }
+        for chunk in buf[..len].utf8_chunks() {
+            print!("{}", chunk.valid());
+        }
+    }
+    println!();
+    Ok(())
+}
+
+fn write(_: &Dtb, args: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    let path = args.next().ok_or("missing path")?;
+    let file =
+        OpenFile::open(path, file::O_WRONLY | file::O_CREAT | file::O_TRUNC).map_err(fs_error)?;
+    for (i, word) in args.enumerate() {
+        if i != 0 {
+            file.write(b" ").map_err(fs_error)?;
+        }
+        file.write(word.as_bytes()).map_err(fs_error)?;
+    }
+    Ok(())
+}
+
+fn mkdir(_: &Dtb, args: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    let (parent, name) =
+        fs::mount::resolve_parent(args.next().ok_or("missing path")?).map_err(fs_error)?;
+    parent
+        .create(&name, NodeKind::Directory)
+        .map_err(fs_error)?;
+    Ok(())
+}
+
+fn rm(_: &Dtb, args: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    let (parent, name) =
+        fs::mount::resolve_parent(args.next().ok_or("missing path")?).map_err(fs_error)?;
+    parent.remove(&name).map_err(fs_error)
+}
+
+fn mem(_: &Dtb, args: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    use crate::alloc::track;
+
+    match (args.next(), args.next()) {
+        (None, _) => crate::alloc::dump_stats(),
+        (Some("track"), Some("on")) => track::set_enabled(true),
+        (Some("track"), Some("off")) => track::set_enabled(false),
+        (Some("track"), Some("dump")) => track::dump_outstanding(),
+        _ => return Err("unknown argument"),
+    }
+    Ok(())
+}
+
+fn pt(_: &Dtb, args: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    let root = match args.next() {
+        Some(root) => Pointer::from_phys(number(Some(root))? as *mut PageTable),
+        None => crate::mem::KERNEL_MAP
+            .lock()
+            .as_ref()
+            .ok_or("kernel map not initialized")?
+            .root(),
+    };
+    if !root.phys().is_aligned() {
+        return Err("root must be page aligned");
+    }
+    unsafe { PageTable::disp_table(root, stdio::sout()) }.map_err(|_| "write failed")
+}
+
+fn hexdump(_: &Dtb, args: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    let addr = number(args.next())?;
+    let len = number(args.next())?;
+    if addr == 0 {
+        return Err("refusing to read address 0");
+    }
+    unsafe { crate::util::hexdump::hexdump_u8(addr as *const u8, len) };
+    Ok(())
+}
+
+fn poweroff(_: &Dtb, _: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    fs::mount::sync_all().map_err(fs_error)?;
+    syscon::poweroff()
+}
+
+fn reboot(_: &Dtb, _: &mut core::str::SplitWhitespace) -> Result<(), &'static str> {
+    fs::mount::sync_all().map_err(fs_error)?;
+    syscon::reboot()
+}
@@ -21,6 +21,10 @@ impl RawSpinLock {
         }
     }
 
+    pub fn try_lock(&self) -> bool {
+        !self.lock.swap(true, Ordering::Acquire)
+    }
+
     pub fn lock_with(&self, before: impl Fn(), fail: impl Fn()) {
         while {
             before();
@@ -46,6 +50,36 @@ impl Default for RawSpinLock {
 
 pub use critical::*;
 
+/// The interrupt masking of [`CriticalSpinLock`]. Hosts running the kernel's
+/// tests have no interrupts to mask, only the lock itself matters there.
+mod irq {
+    #[cfg(target_arch = "riscv64")]
+    pub fn enabled() -> bool {
+        riscv::register::sstatus::read().sie()
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    pub unsafe fn disable() {
+        unsafe { riscv::register::sstatus::clear_sie() }
+    }
+
+    #[cfg(target_arch = "riscv64")]
+    pub unsafe fn enable() {
+        unsafe { riscv::register::sstatus::set_sie() }
+    }
+
+    #[cfg(not(target_arch = "riscv64"))]
+    pub fn enabled() -> bool {
+        false
+    }
+
+    #[cfg(not(target_arch = "riscv64"))]
+    pub unsafe fn disable() {}
+
+    #[cfg(not(target_arch = "riscv64"))]
+    pub unsafe fn enable() {}
+}
+
 mod critical {
     use super::*;
 
@@ -76,7 +110,7 @@ mod critical {
             unsafe {
                 self.lock.lock.unlock();
                 if self.ie {
-                    riscv::register::sstatus::set_spie();
+                    irq::enable();
                 }
             }
         }
@@ -96,18 +130,51 @@ mod critical {
         }
     }
 
+    impl<T> CriticalSpinLock<T> {
+        /// Locks the entry of `locks` that `index` picks with interrupts already
+        /// disabled, for per-hart data where `index` reads the hart we run on
+        /// and must not see it change before the lock is held.
+        pub fn lock_indexed(
+            locks: &[Self],
+            index: impl Fn() -> usize,
+        ) -> (usize, CriticalSpinLockGuard<'_, T>) {
+            let ie = irq::enabled();
+            loop {
+                unsafe {
+                    irq::disable();
+                }
+                let i = index();
+                if locks[i].lock.try_lock() {
+                    return (
+                        i,
+                        CriticalSpinLockGuard {
+                            lock: &locks[i],
+                            ie,
+                        },
+                    );
+                }
+                if ie {
+                    unsafe {
+                        irq::enable();
+                    }
+                }
+                core::hint::spin_loop();
+            }
+        }
+    }
+
     impl<T: ?Sized> CriticalSpinLock<T> {
         #[track_caller]
         pub fn lock(&self) -> CriticalSpinLockGuard<'_, T> {
-            let ie = riscv::register::sstatus::read().sie();
+            let ie = irq::enabled();
 
             let before = || unsafe {
-                riscv::register::sstatus::clear_sie();
+                irq::disable();
             };
             let fail = || {
                 if ie {
                     unsafe {
-                        riscv::register::sstatus::set_sie();
+                        irq::enable();
                     }
                 }
             };
@@ -115,5 +182,24 @@ mod critical {
 
             CriticalSpinLockGuard { lock: self, ie }
         }
+
+        /// Takes the lock only if nobody holds it, for paths like the panic
+        /// handler which must not wait on whoever they interrupted.
+        pub fn try_lock(&self) -> Option<CriticalSpinLockGuard<'_, T>> {
+            let ie = irq::enabled();
+            unsafe {
+                irq::disable();
+            }
+            if self.lock.try_lock() {
+                Some(CriticalSpinLockGuard { lock: self, ie })
+            } else {
+                if ie {
+                    unsafe {
+                        irq::enable();
+                    }
+                }
+                None
+            }
+        }
     }
 }
@@ -1,6 +1,310 @@
+//! System call ABI
+//!
+//! A user program traps in with `ecall`, passing the call number in `a7` and up
+//! to seven arguments in `a0`-`a6`. Results come back in `a0` and `a1`, every
+//! other register is preserved. A failed call returns `-errno` in `a0`, so
+//! values in `-4095..0` are errors and everything else is a success.
+//!
+//! | nr | name       | arguments                  | returns              |
+//! |----|------------|----------------------------|----------------------|
+//! | 0  | `read`     | fd, buf, len               | bytes read           |
+//! | 1  | `write`    | fd, buf, len               | bytes written        |
+//! | 2  | `exit`     | code                       | does not return      |
+//! | 3  | `yield`    |                            | 0                    |
+//! | 4  | `getpid`   |                            | task id              |
+//! | 5  | `sleep`    | nanoseconds                | 0                    |
+//! | 6  | `mmap`     | addr, len, prot            | address of mapping   |
+//! | 7  | `fork`     |                            | child id, 0 in child |
+//! | 8  | `munmap`   | addr, len                  | 0                    |
+//! | 9  | `mprotect` | addr, len, prot            | 0                    |
+//! | 10 | `open`     | path, path len, flags      | file descriptor      |
+//! | 11 | `close`    | fd                         | 0                    |
+//! | 12 | `lseek`    | fd, offset, whence         | new offset           |
 
+use crate::{
+    alloc::sync::Arc,
+    arch::{Frame, page::PageTableEntry},
+    fs::{FsError, OpenFile},
+    mem::space::{Access, USER_END},
+    task::sched,
+};
 
-pub unsafe fn syscall(args: [usize; 7]) -> [usize; 2]{
-    
-    [0;2]
-}
\ No newline at end of file
+pub mod nr {
+    pub const READ: usize = 0;
+    pub const WRITE: usize = 1;
+    pub const EXIT: usize = 2;
+    pub const YIELD: usize = 3;
+    pub const GETPID: usize = 4;
+    pub const SLEEP: usize = 5;
+    pub const MMAP: usize = 6;
+    pub const FORK: usize = 7;
+    pub const MUNMAP: usize = 8;
+    pub const MPROTECT: usize = 9;
+    pub const OPEN: usize = 10;
+    pub const CLOSE: usize = 11;
+    pub const LSEEK: usize = 12;
+}
+
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+pub const STDIN: usize = 0;
+pub const STDOUT: usize = 1;
+pub const STDERR: usize = 2;
+
+/// Longest path `open` accepts.
+pub const PATH_MAX: usize = 4096;
+
+/// Most bytes a single `read` or `write` moves, the whole buffer is faulted in first.
+pub const IO_MAX: usize = 1 << 20;
+
+/// Error codes, numbered like their Linux counterparts.
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Errno {
+    EPERM = 1,
+    ENOENT = 2,
+    ESRCH = 3,
+    EINTR = 4,
+    EIO = 5,
+    EBADF = 9,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    EFBIG = 27,
+    ENOSPC = 28,
+    ESPIPE = 29,
+    EROFS = 30,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+}
+
+impl From<FsError> for Errno {
+    fn from(value: FsError) -> Self {
+        match value {
+            FsError::Io(_) | FsError::BadFormat | FsError::Corrupt => Errno::EIO,
+            FsError::NotFound => Errno::ENOENT,
+            FsError::NotADirectory => Errno::ENOTDIR,
+            FsError::IsADirectory => Errno::EISDIR,
+            FsError::AlreadyExists => Errno::EEXIST,
+            FsError::DirectoryNotEmpty => Errno::ENOTEMPTY,
+            FsError::InvalidName | FsError::InvalidArgument => Errno::EINVAL,
+            FsError::NoSpace => Errno::ENOSPC,
+            FsError::FileTooLarge => Errno::EFBIG,
+            FsError::ReadOnly => Errno::EROFS,
+            FsError::Unsupported => Errno::ESPIPE,
+            FsError::AccessDenied => Errno::EBADF,
+            FsError::Busy => Errno::EBUSY,
+            FsError::Interrupted => Errno::EINTR,
+        }
+    }
+}
+
+pub type SyscallResult = Result<usize, Errno>;
+
+/// Handlers get the caller's trap frame, with `pc` already past the `ecall`.
+type Handler = fn(&Frame, &[usize; 7]) -> SyscallResult;
+
+const TABLE: [Option<Handler>; 13] = {
+    let mut table: [Option<Handler>; 13] = [None; 13];
+    table[nr::READ] = Some(sys_read);
+    table[nr::WRITE] = Some(sys_write);
+    table[nr::EXIT] = Some(sys_exit);
+    table[nr::YIELD] = Some(sys_yield);
+    table[nr::GETPID] = Some(sys_getpid);
+    table[nr::SLEEP] = Some(sys_sleep);
+    table[nr::MMAP] = Some(sys_mmap);
+    table[nr::FORK] = Some(sys_fork);
+    table[nr::MUNMAP] = Some(sys_munmap);
+    table[nr::MPROTECT] = Some(sys_mprotect);
+    table[nr::OPEN] = Some(sys_open);
+    table[nr::CLOSE] = Some(sys_close);
+    table[nr::LSEEK] = Some(sys_lseek);
+    table
+};
+
+/// Runs system call `nr`, returning the values for `a0` and `a1`.
+///
+/// # Safety
+///
+/// Must be called from the trap handler of the calling task, with its address space active.
+pub unsafe fn syscall(frame: &Frame, nr: usize, args: [usize; 7]) -> [usize; 2] {
+    let result = match TABLE.get(nr) {
+        Some(Some(handler)) => handler(frame, &args),
+        _ => Err(Errno::ENOSYS),
+    };
+    match result {
+        Ok(value) => [value, 0],
+        Err(errno) => [(errno as usize).wrapping_neg(), 0],
+    }
+}
+
+/// Checks that `ptr..ptr + len` lies entirely in user space.
+fn user_range(ptr: usize, len: usize) -> Result<(), Errno> {
+    match ptr.checked_add(len) {
+        Some(end) if end <= USER_END => Ok(()),
+        _ => Err(Errno::EFAULT),
+    }
+}
+
+/// Maps in all of `ptr..ptr + len` ahead of time. A fault while a handler holds a
+/// lock could not kill the task without leaving the lock taken, so handlers only
+/// ever touch user memory checked here.
+fn fault_in(ptr: usize, len: usize, access: Access) -> Result<(), Errno> {
+    user_range(ptr, len)?;
+    let space = sched::current_space().ok_or(Errno::EFAULT)?;
+    space.fault_in(ptr, len, access).map_err(|_| Errno::EFAULT)
+}
+
+fn user_slice<'a>(ptr: usize, len: usize) -> Result<&'a [u8], Errno> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    fault_in(ptr, len, Access::Read)?;
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len) })
+}
+
+fn user_slice_mut<'a>(ptr: usize, len: usize) -> Result<&'a mut [u8], Errno> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    fault_in(ptr, len, Access::Write)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len) })
+}
+
+/// The open file behind `fd` in the calling task.
+fn file(fd: usize) -> Result<Arc<OpenFile>, Errno> {
+    sched::current_files()
+        .and_then(|files| files.get(fd))
+        .ok_or(Errno::EBADF)
+}
+
+/// Reads from the file position of `fd`, a console read blocks until a whole line was typed.
+fn sys_read(_: &Frame, args: &[usize; 7]) -> SyscallResult {
+    let [fd, buf, len, ..] = *args;
+    let file = file(fd)?;
+    let buf = user_slice_mut(buf, len.min(IO_MAX))?;
+    Ok(file.read(buf)?)
+}
+
+fn sys_write(_: &Frame, args: &[usize; 7]) -> SyscallResult {
+    let [fd, buf, len, ..] = *args;
+    let file = file(fd)?;
+    let buf = user_slice(buf, len.min(IO_MAX))?;
+    Ok(file.write(buf)?)
+}
+
+/// Opens the absolute path `path..path + len` with the `O_*` flags of [`crate::fs::file`].
+fn sys_open(_: &Frame, args: &[usize; 7]) -> SyscallResult {
+    let [path, len, flags, ..] = *args;
+    if len > PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    let path = core::str::from_utf8(user_slice(path, len)?).map_err(|_| Errno::EINVAL)?;
+    let files = sched::current_files().ok_or(Errno::EPERM)?;
+    let file = OpenFile::open(path, flags)?;
+    files.insert(file).ok_or(Errno::EMFILE)
+}
+
+fn sys_close(_: &Frame, args: &[usize; 7]) -> SyscallResult {
+    let files = sched::current_files().ok_or(Errno::EBADF)?;
+    files.close(args[0]).ok_or(Errno::EBADF)?;
+    Ok(0)
+}
+
+fn sys_lseek(_: &Frame, args: &[usize; 7]) -> SyscallResult {
+    let [fd, offset, whence, ..] = *args;
+    let position = file(fd)?.seek(offset as i64, whence)?;
+    Ok(position as usize)
+}
+
+fn sys_exit(_: &Frame, args: &[usize; 7]) -> SyscallResult {
+    sched::exit(args[0])
+}
+
+fn sys_yield(_: &Frame, _: &[usize; 7]) -> SyscallResult {
+    sched::yield_now();
+    Ok(0)
+}
+
+fn sys_getpid(_: &Frame, _: &[usize; 7]) -> SyscallResult {
+    sched::current_id().map(|id| id.0).ok_or(Errno::ESRCH)
+}
+
+fn sys_sleep(_: &Frame, args: &[usize; 7]) -> SyscallResult {
+    crate::timer::sleep(crate::timer::Duration::from_nanos(args[0] as u64));
+    Ok(0)
+}
+
+/// Reserves zeroed anonymous memory, at `addr` if it is non zero, otherwise wherever there is room.
+/// Pages are only allocated once touched.
+fn sys_mmap(_: &Frame, args: &[usize; 7]) -> SyscallResult {
+    let [addr, len, prot, ..] = *args;
+    if len == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let len = len
+        .checked_next_multiple_of(core::mem::size_of::<crate::arch::page::Page>())
+        .ok_or(Errno::ENOMEM)?;
+    let space = sched::current_space().ok_or(Errno::EPERM)?;
+
+    let addr = if addr == 0 {
+        space.reserve(len).ok_or(Errno::ENOMEM)?
+    } else if addr.is_multiple_of(core::mem::size_of::<crate::arch::page::Page>()) {
+        user_range(addr, len).map_err(|_| Errno::ENOMEM)?;
+        addr
+    } else {
+        return Err(Errno::EINVAL);
+    };
+
+    space
+        .map_lazy(addr, len, prot_entry(prot)?)
+        .map_err(|_| Errno::ENOMEM)?;
+    Ok(addr)
+}
+
+/// Page permissions for `prot`. An entry without any of R/W/X would point at a
+/// page table, so `PROT_NONE` is refused.
+fn prot_entry(prot: usize) -> Result<PageTableEntry, Errno> {
+    if prot == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(Errno::EINVAL);
+    }
+    Ok(PageTableEntry::new()
+        .set_valid(true)
+        .set_readable(prot & (PROT_READ | PROT_WRITE) != 0)
+        .set_writable(prot & PROT_WRITE != 0)
+        .set_executable(prot & PROT_EXEC != 0))
+}
+
+fn sys_munmap(_: &Frame, args: &[usize; 7]) -> SyscallResult {
+    let [addr, len, ..] = *args;
+    let space = sched::current_space().ok_or(Errno::EPERM)?;
+    space.unmap(addr, len).map_err(|_| Errno::EINVAL)?;
+    Ok(0)
+}
+
+fn sys_mprotect(_: &Frame, args: &[usize; 7]) -> SyscallResult {
+    let [addr, len, prot, ..] = *args;
+    let entry = prot_entry(prot)?;
+    let space = sched::current_space().ok_or(Errno::EPERM)?;
+    space.protect(addr, len, entry).map_err(|_| Errno::EINVAL)?;
+    Ok(0)
+}
+
+fn sys_fork(frame: &Frame, _: &[usize; 7]) -> SyscallResult {
+    let space = sched::current_space().ok_or(Errno::EPERM)?;
+    let name = sched::current_name().unwrap_or("fork");
+    let files = sched::current_files().ok_or(Errno::EPERM)?;
+    let child = crate::task::Task::fork(name, &space, &files, frame);
+    let id = child.id;
+    sched::add(child);
+    Ok(id.0)
+}
@@ -1,15 +1,265 @@
-use crate::arch;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+use crate::{
+    alloc::{boxed::Box, sync::Arc},
+    arch::{self, Frame},
+    fs::FdTable,
+    mem::space::AddressSpace,
+};
 
+pub mod process;
+pub mod sched;
+pub mod stack;
+
+pub use stack::{KSTACK_PAGES, KernelStack};
+
+/// Exit code of a task killed for an invalid memory access, `128 + SIGSEGV` like a shell reports it.
+pub const EXIT_SEGFAULT: usize = 139;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(pub usize);
+
+impl TaskId {
+    /// Reserved for the per-hart idle task, which never sits in the run queue.
+    pub const IDLE: TaskId = TaskId(0);
+
+    fn next() -> Self {
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
 
 #[derive(Debug)]
 pub struct Task{
+    pub id: TaskId,
+    pub name: &'static str,
+    pub state: TaskState,
+    pub exit_code: usize,
+    pub join: Option<Arc<JoinState>>,
+    /// Open file descriptors, shared with nobody unless forked before `exec`.
+    pub files: Arc<FdTable>,
     pub ctx: Context,
 }
 
+unsafe impl Send for Task {}
+
 #[derive(Debug)]
 pub struct Context{
     pub arch: arch::Context,
-    pub kstack: *mut u8,
-    pub mmap: (),
-}
\ No newline at end of file
+    /// `None` for the init task, which keeps running on the linker provided boot stack.
+    pub kstack: Option<KernelStack>,
+    /// Address space of a user task, kernel tasks run in the kernel map.
+    pub mmap: Option<Arc<AddressSpace>>,
+}
+
+impl Task {
+    /// Wraps the context currently running on the linker provided boot stack.
+    ///
+    /// Its frame is filled in the first time it gets switched out.
+    pub fn boot(name: &'static str) -> Box<Task> {
+        Box::new(Task {
+            id: TaskId::next(),
+            name,
+            state: TaskState::Running,
+            exit_code: 0,
+            join: None,
+            files: Arc::new(FdTable::new()),
+            ctx: Context {
+                arch: arch::Context::new(),
+                kstack: None,
+                mmap: None,
+            },
+        })
+    }
+
+    /// Wraps the context a secondary hart boots on, which stays around as its idle task.
+    pub(crate) fn idle(kstack: KernelStack) -> Box<Task> {
+        Box::new(Task {
+            id: TaskId::IDLE,
+            name: "idle",
+            state: TaskState::Running,
+            exit_code: 0,
+            join: None,
+            files: Arc::new(FdTable::new()),
+            ctx: Context {
+                arch: arch::Context::new(),
+                kstack: Some(kstack),
+                mmap: None,
+            },
+        })
+    }
+
+    /// Creates a ready to run kernel task which calls `entry(arg)` on a fresh guarded stack.
+    ///
+    /// Returning from `entry` exits the task with the returned value.
+    pub fn kernel(name: &'static str, entry: fn(usize) -> usize, arg: usize) -> Box<Task> {
+        Self::kernel_with_id(TaskId::next(), name, entry, arg)
+    }
+
+    pub(crate) fn kernel_with_id(
+        id: TaskId,
+        name: &'static str,
+        entry: fn(usize) -> usize,
+        arg: usize,
+    ) -> Box<Task> {
+        let kstack = KernelStack::alloc();
+        let top = kstack.top();
+
+        let mut frame = Frame::kernel(task_entry as *const () as usize, top);
+        frame.regs[Frame::A0] = entry as usize;
+        frame.regs[Frame::A1] = arg;
+
+        Self::with_frame(id, name, kstack, None, Arc::new(FdTable::new()), frame)
+    }
+
+    /// Creates a ready to run task which enters U-mode at `entry` inside `space`,
+    /// with its user stack pointer set to `sp` and the console as descriptors 0 to 2.
+    pub fn user(name: &'static str, space: Arc<AddressSpace>, entry: usize, sp: usize) -> Box<Task> {
+        Self::with_frame(
+            TaskId::next(),
+            name,
+            KernelStack::alloc(),
+            Some(space),
+            Arc::new(FdTable::with_console()),
+            Frame::user(entry, sp),
+        )
+    }
+
+    /// A copy of the calling user task with its memory shared copy-on-write and
+    /// its open files shared, resuming from `frame` with `a0` set to 0 like a returning `fork()`.
+    pub fn fork(
+        name: &'static str,
+        space: &AddressSpace,
+        files: &FdTable,
+        frame: &Frame,
+    ) -> Box<Task> {
+        let mut frame = *frame;
+        frame.regs[Frame::A0] = 0;
+        frame.regs[Frame::A1] = 0;
+        Self::with_frame(
+            TaskId::next(),
+            name,
+            KernelStack::alloc(),
+            Some(Arc::new(space.fork())),
+            Arc::new(files.fork()),
+            frame,
+        )
+    }
+
+    fn with_frame(
+        id: TaskId,
+        name: &'static str,
+        kstack: KernelStack,
+        mmap: Option<Arc<AddressSpace>>,
+        files: Arc<FdTable>,
+        frame: Frame,
+    ) -> Box<Task> {
+        // the first frame lives just below the stack top and is consumed by `strap_return`
+        let frame_ptr =
+            (kstack.top() - core::mem::size_of::<Frame>().next_multiple_of(16)) as *mut Frame;
+        unsafe {
+            frame_ptr.write(frame);
+        }
+
+        Box::new(Task {
+            id,
+            name,
+            state: TaskState::Ready,
+            exit_code: 0,
+            join: None,
+            files,
+            ctx: Context {
+                arch: arch::Context { frame: frame_ptr },
+                kstack: Some(kstack),
+                mmap,
+            },
+        })
+    }
+}
+
+/// First code run by every kernel task, `entry` is the task's `fn(usize) -> usize`.
+extern "C" fn task_entry(entry: usize, arg: usize) -> ! {
+    let entry: fn(usize) -> usize = unsafe { core::mem::transmute(entry) };
+    sched::exit(entry(arg))
+}
+
+/// Shared between an exiting task and its [`JoinHandle`].
+#[derive(Debug, Default)]
+pub struct JoinState {
+    done: AtomicBool,
+    value: AtomicUsize,
+    /// Id of the task waiting in [`JoinHandle::join`], zero when nobody is.
+    waiter: AtomicUsize,
+}
+
+impl JoinState {
+    fn finish(&self, value: usize) {
+        self.value.store(value, Ordering::Relaxed);
+        self.done.store(true, Ordering::Release);
+        match self.waiter.load(Ordering::Acquire) {
+            0 => {}
+            waiter => sched::wake(TaskId(waiter)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct JoinHandle {
+    id: TaskId,
+    state: Arc<JoinState>,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.done.load(Ordering::Acquire)
+    }
+
+    /// Blocks until the task exits, returning its exit value.
+    pub fn join(self) -> usize {
+        let me = sched::current_id().expect("join called outside of a task");
+        loop {
+            if self.is_finished() {
+                return self.state.value.load(Ordering::Relaxed);
+            }
+            self.state.waiter.store(me.0, Ordering::Release);
+            // blocked before the last check, so an exit from here on finds us to wake
+            sched::mark_blocked();
+            if self.is_finished() {
+                sched::wake(me);
+                return self.state.value.load(Ordering::Relaxed);
+            }
+            sched::yield_now();
+        }
+    }
+}
+
+/// Starts a kernel thread running `entry(arg)`.
+pub fn spawn(entry: fn(usize) -> usize, arg: usize) -> JoinHandle {
+    spawn_named("kthread", entry, arg)
+}
+
+pub fn spawn_named(name: &'static str, entry: fn(usize) -> usize, arg: usize) -> JoinHandle {
+    spawn_task(Task::kernel(name, entry, arg))
+}
+
+/// Queues an already built task, returning a handle to wait for its exit.
+pub fn spawn_task(mut task: Box<Task>) -> JoinHandle {
+    let state = Arc::new(JoinState::default());
+    task.join = Some(state.clone());
+    let id = task.id;
+    sched::add(task);
+    JoinHandle { id, state }
+}
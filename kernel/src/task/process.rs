@@ -0,0 +1,259 @@
+use crate::{
+    alloc::{sync::Arc, vec, vec::Vec},
+    arch::page::{Page, PageTableEntry},
+    elf::{Elf, Elf64Phdr, ElfError, PF_R, PF_W, PF_X},
+    fs::{self, FsError, NodeKind},
+    mem::space::{AddressSpace, USER_END},
+    task::{JoinHandle, Task},
+};
+
+const PAGE_SIZE: usize = core::mem::size_of::<Page>();
+
+/// Pages of the initial stack mapped up front, the rest of [`USER_STACK_SIZE`] is faulted in on demand.
+pub const USER_STACK_PAGES: usize = 8;
+
+/// The most the user stack can grow to.
+pub const USER_STACK_SIZE: usize = 8 << 20;
+
+/// The initial user stack grows down from here, one unmapped page below the end of user space.
+pub const USER_STACK_TOP: usize = USER_END - PAGE_SIZE;
+
+pub const AT_NULL: usize = 0;
+pub const AT_PHDR: usize = 3;
+pub const AT_PHENT: usize = 4;
+pub const AT_PHNUM: usize = 5;
+pub const AT_PAGESZ: usize = 6;
+pub const AT_ENTRY: usize = 9;
+pub const AT_RANDOM: usize = 25;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    Elf(ElfError),
+    /// The executable could not be read.
+    Fs(FsError),
+    /// A segment or the stack overlapped another mapping or left user space.
+    Map,
+    /// `argv`, `envp` and the auxiliary vector do not fit on the initial stack.
+    StackOverflow,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(value: ElfError) -> Self {
+        Self::Elf(value)
+    }
+}
+
+impl From<FsError> for LoadError {
+    fn from(value: FsError) -> Self {
+        Self::Fs(value)
+    }
+}
+
+/// A user program being assembled: its address space, entry point and initial stack.
+pub struct Process {
+    space: Arc<AddressSpace>,
+    entry: usize,
+    sp: usize,
+}
+
+impl Process {
+    /// A fresh, empty address space. The initial stack is mapped by
+    /// [`setup_stack`](Self::setup_stack).
+    pub fn new() -> Self {
+        Self {
+            space: Arc::new(AddressSpace::new()),
+            entry: 0,
+            sp: USER_STACK_TOP,
+        }
+    }
+
+    /// Loads a statically linked ELF executable and prepares its stack with
+    /// `argv`, `envp` and the auxiliary vector.
+    pub fn load_elf(data: &[u8], argv: &[&[u8]], envp: &[&[u8]]) -> Result<Self, LoadError> {
+        let elf = Elf::parse(data)?;
+        let mut process = Self::new();
+
+        for phdr in elf.segments() {
+            process.load_segment(&elf, &phdr)?;
+        }
+        process.entry = elf.entry();
+
+        let phnum = elf.program_headers().count();
+        let auxv = [
+            (AT_PHDR, elf.phdr_addr().unwrap_or(0)),
+            (AT_PHENT, core::mem::size_of::<Elf64Phdr>()),
+            (AT_PHNUM, phnum),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, elf.entry()),
+        ];
+        process.setup_stack(argv, envp, &auxv)?;
+        Ok(process)
+    }
+
+    /// Reads the ELF executable at `path` and loads it like [`load_elf`](Self::load_elf).
+    pub fn load_file(path: &str, argv: &[&[u8]], envp: &[&[u8]]) -> Result<Self, LoadError> {
+        let node = fs::resolve(path)?;
+        let metadata = node.metadata();
+        match metadata.kind {
+            NodeKind::File => {}
+            NodeKind::Directory => return Err(FsError::IsADirectory.into()),
+            NodeKind::CharDevice => return Err(FsError::Unsupported.into()),
+        }
+        let mut data = vec![0; metadata.size as usize];
+        let mut done = 0;
+        while done < data.len() {
+            match node.read_at(done as u64, &mut data[done..])? {
+                0 => return Err(FsError::Corrupt.into()),
+                len => done += len,
+            }
+        }
+        Self::load_elf(&data, argv, envp)
+    }
+
+    /// Maps one `PT_LOAD` segment, the part past `p_filesz` is left zeroed as bss.
+    fn load_segment(&mut self, elf: &Elf, phdr: &Elf64Phdr) -> Result<(), LoadError> {
+        let mem = phdr.mem_range();
+        if mem.is_empty() {
+            return Ok(());
+        }
+        // parse only rules out wrapping, the segment still has to fit in user space
+        let end = mem
+            .end
+            .checked_next_multiple_of(PAGE_SIZE)
+            .filter(|&end| end <= USER_END)
+            .ok_or(LoadError::Map)?;
+        let start = mem.start & !(PAGE_SIZE - 1);
+
+        let file = &elf.data()[phdr.file_range()];
+        let file_start = mem.start;
+
+        // writable implies readable, W without R is reserved
+        let entry = PageTableEntry::new()
+            .set_valid(true)
+            .set_readable(phdr.p_flags & (PF_R | PF_W) != 0)
+            .set_writable(phdr.p_flags & PF_W != 0)
+            .set_executable(phdr.p_flags & PF_X != 0);
+
+        self.space
+            .map_with(start, end - start, entry, |offset, page| {
+                let page_addr = start + offset;
+                let from = page_addr.max(file_start);
+                let to = (page_addr + PAGE_SIZE).min(file_start + file.len());
+                if from < to {
+                    page[from - page_addr..to - page_addr]
+                        .copy_from_slice(&file[from - file_start..to - file_start]);
+                }
+            })
+            .map_err(|_| LoadError::Map)
+    }
+
+    /// Maps the initial stack below [`USER_STACK_TOP`] laid out the way the SysV
+    /// ABI expects at process entry: `argc`, `argv`, `envp` and the auxiliary
+    /// vector, followed by the strings they point to.
+    pub fn setup_stack(
+        &mut self,
+        argv: &[&[u8]],
+        envp: &[&[u8]],
+        auxv: &[(usize, usize)],
+    ) -> Result<(), LoadError> {
+        let stack_size = USER_STACK_PAGES * PAGE_SIZE;
+
+        // the strings area sits right below the top, AT_RANDOM bytes first
+        let mut strings = Vec::new();
+        let random = crate::timer::now().wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        strings.extend_from_slice(&random.to_le_bytes());
+        strings.extend_from_slice(&random.rotate_left(32).to_le_bytes());
+
+        let mut push_str = |s: &[u8]| {
+            let offset = strings.len();
+            strings.extend_from_slice(s);
+            strings.push(0);
+            offset
+        };
+        let argv_offsets: Vec<usize> = argv.iter().map(|&s| push_str(s)).collect();
+        let envp_offsets: Vec<usize> = envp.iter().map(|&s| push_str(s)).collect();
+
+        let strings_base = USER_STACK_TOP - strings.len().next_multiple_of(16);
+
+        let mut words = Vec::new();
+        words.push(argv.len());
+        words.extend(argv_offsets.iter().map(|offset| strings_base + offset));
+        words.push(0);
+        words.extend(envp_offsets.iter().map(|offset| strings_base + offset));
+        words.push(0);
+        for &(key, value) in auxv {
+            words.push(key);
+            words.push(value);
+        }
+        words.extend([AT_RANDOM, strings_base, AT_NULL, 0]);
+
+        let sp = (strings_base - words.len() * core::mem::size_of::<usize>()) & !15;
+        let stack_base = USER_STACK_TOP - stack_size;
+        if sp < stack_base {
+            return Err(LoadError::StackOverflow);
+        }
+
+        self.space
+            .map_with(stack_base, stack_size, PageTableEntry::COM_RW, |offset, page| {
+                for (i, byte) in page.iter_mut().enumerate() {
+                    let addr = stack_base + offset + i;
+                    if addr >= strings_base {
+                        *byte = strings.get(addr - strings_base).copied().unwrap_or(0);
+                    } else if addr >= sp {
+                        let word = (addr - sp) / core::mem::size_of::<usize>();
+                        let shift = (addr - sp) % core::mem::size_of::<usize>() * 8;
+                        *byte = words.get(word).map_or(0, |w| (w >> shift) as u8);
+                    }
+                }
+            })
+            .map_err(|_| LoadError::Map)?;
+        self.space
+            .map_lazy(
+                USER_STACK_TOP - USER_STACK_SIZE,
+                USER_STACK_SIZE - stack_size,
+                PageTableEntry::COM_RW,
+            )
+            .map_err(|_| LoadError::Map)?;
+
+        self.sp = sp;
+        Ok(())
+    }
+
+    pub fn space(&self) -> &Arc<AddressSpace> {
+        &self.space
+    }
+
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    pub fn set_entry(&mut self, entry: usize) {
+        self.entry = entry;
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    pub fn set_sp(&mut self, sp: usize) {
+        self.sp = sp;
+    }
+
+    /// Maps a flat executable image at `base` and starts execution at its first byte,
+    /// the caller still has to [`setup_stack`](Self::setup_stack).
+    pub fn load_flat(&mut self, base: usize, image: &[u8]) -> Result<(), ()> {
+        self.space.map_data(
+            base,
+            image,
+            image.len().next_multiple_of(PAGE_SIZE),
+            PageTableEntry::COM_EXEC,
+        )?;
+        self.entry = base;
+        Ok(())
+    }
+
+    /// Starts running the process in U-mode.
+    pub fn spawn(self, name: &'static str) -> JoinHandle {
+        crate::task::spawn_task(Task::user(name, self.space, self.entry, self.sp))
+    }
+}
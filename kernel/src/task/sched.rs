@@ -0,0 +1,266 @@
+use core::arch::asm;
+
+use crate::{
+    alloc::{boxed::Box, collections::{BTreeMap, VecDeque}, sync::Arc, vec::Vec},
+    arch::{self, Frame, smp::MAX_HARTS},
+    fs::FdTable,
+    mem::space::AddressSpace,
+    sync::mutex::CriticalSpinLock,
+    task::{Task, TaskId, TaskState},
+};
+
+/// Timer interrupts per second, each one ends the running task's time slice.
+pub const QUANTUM_HZ: u64 = 100;
+
+/// The part of the scheduler private to one hart.
+struct Cpu {
+    current: Option<Box<Task>>,
+    idle: Option<Box<Task>>,
+    /// Task just switched away from, filed away by [`finish_switch`] once this
+    /// hart left its stack so no other hart picks it up while it is still in use.
+    prev: Option<Box<Task>>,
+}
+
+impl Cpu {
+    const fn new() -> Self {
+        Self {
+            current: None,
+            idle: None,
+            prev: None,
+        }
+    }
+}
+
+/// One run queue shared by every hart, each hart runs its own current and idle task.
+pub struct Scheduler {
+    cpus: [Cpu; MAX_HARTS],
+    run_queue: VecDeque<Box<Task>>,
+    blocked: BTreeMap<TaskId, Box<Task>>,
+    /// Exited tasks, dropped once we are no longer running on their stacks.
+    reaped: Vec<Box<Task>>,
+}
+
+impl Scheduler {
+    pub const fn new() -> Self {
+        Self {
+            cpus: [const { Cpu::new() }; MAX_HARTS],
+            run_queue: VecDeque::new(),
+            blocked: BTreeMap::new(),
+            reaped: Vec::new(),
+        }
+    }
+
+    /// State of the calling hart, the lock keeps us from migrating while it is used.
+    fn cpu(&self) -> &Cpu {
+        &self.cpus[arch::current_hart()]
+    }
+
+    fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpus[arch::current_hart()]
+    }
+
+    /// The task with `id` if it is running or just being switched out on some hart.
+    fn on_cpu(&mut self, id: TaskId) -> Option<&mut Box<Task>> {
+        self.cpus
+            .iter_mut()
+            .flat_map(|cpu| [cpu.current.as_mut(), cpu.prev.as_mut()])
+            .flatten()
+            .find(|task| task.id == id)
+    }
+}
+
+static SCHED: CriticalSpinLock<Scheduler> = CriticalSpinLock::new(Scheduler::new());
+
+fn idle(_: usize) -> usize {
+    arch::halt()
+}
+
+/// Installs `boot` as the running task of the boot hart. Must be called once
+/// before the first timer or software interrupt is taken.
+pub fn init(boot: Box<Task>) {
+    let idle = Task::kernel_with_id(TaskId::IDLE, "idle", idle, 0);
+    let mut sched = SCHED.lock();
+    let cpu = sched.cpu_mut();
+    cpu.idle = Some(idle);
+    cpu.current = Some(boot);
+}
+
+/// Installs `idle` as the running task of a secondary hart, which then picks
+/// up work from the shared run queue on its next interrupt.
+pub fn init_hart(idle: Box<Task>) {
+    SCHED.lock().cpu_mut().current = Some(idle);
+}
+
+/// Makes `task` runnable, it will be picked up after everything already queued.
+pub fn add(mut task: Box<Task>) {
+    task.state = TaskState::Ready;
+    SCHED.lock().run_queue.push_back(task);
+}
+
+pub fn current_id() -> Option<TaskId> {
+    SCHED.lock().cpu().current.as_ref().map(|task| task.id)
+}
+
+pub fn current_name() -> Option<&'static str> {
+    SCHED.lock().cpu().current.as_ref().map(|task| task.name)
+}
+
+/// Address space of the running task, `None` for kernel tasks.
+pub fn current_space() -> Option<Arc<AddressSpace>> {
+    SCHED.lock().cpu().current.as_ref().and_then(|task| task.ctx.mmap.clone())
+}
+
+/// Descriptor table of the running task.
+pub fn current_files() -> Option<Arc<FdTable>> {
+    SCHED.lock().cpu().current.as_ref().map(|task| task.files.clone())
+}
+
+/// Saves `frame` as the current task's context and picks the next task to run,
+/// returning the frame `strap_return` should resume.
+pub fn schedule(frame: *mut Frame) -> *mut Frame {
+    // dropped without the lock, freeing an address space waits on a TLB shootdown
+    // which other harts spinning on the lock with interrupts off could never answer
+    let reaped = core::mem::take(&mut SCHED.lock().reaped);
+    drop(reaped);
+
+    let mut guard = SCHED.lock();
+    let sched = &mut *guard;
+
+    let cpu = &mut sched.cpus[arch::current_hart()];
+    let Some(mut prev) = cpu.current.take() else {
+        return frame;
+    };
+    prev.ctx.arch.frame = frame;
+
+    let mut next = match sched.run_queue.pop_front() {
+        Some(next) => next,
+        None if prev.state == TaskState::Running => {
+            cpu.current = Some(prev);
+            return frame;
+        }
+        None => cpu.idle.take().expect("idle task missing"),
+    };
+
+    // the idle task never leaves this hart, everything else waits for `finish_switch`
+    if prev.id == TaskId::IDLE {
+        cpu.idle = Some(prev);
+    } else {
+        cpu.prev = Some(prev);
+    }
+
+    next.state = TaskState::Running;
+    match &next.ctx.mmap {
+        Some(space) => space.activate(),
+        None => crate::mem::space::activate_kernel(),
+    }
+    let frame = next.ctx.arch.frame;
+    unsafe {
+        // kernel code finds its hart through `tp`, which must follow the task to this hart
+        if !(*frame).from_user() {
+            (*frame).regs[Frame::TP] = arch::tp();
+        }
+    }
+    cpu.current = Some(next);
+    frame
+}
+
+/// Requeues the task [`schedule`] switched away from. Called by the trap
+/// vector once it moved onto the stack of the next task.
+pub extern "C" fn finish_switch() {
+    let mut guard = SCHED.lock();
+    let sched = &mut *guard;
+
+    let Some(mut prev) = sched.cpu_mut().prev.take() else {
+        return;
+    };
+    match prev.state {
+        TaskState::Running | TaskState::Ready => {
+            prev.state = TaskState::Ready;
+            sched.run_queue.push_back(prev);
+        }
+        TaskState::Blocked => {
+            sched.blocked.insert(prev.id, prev);
+        }
+        TaskState::Exited => sched.reaped.push(prev),
+    }
+}
+
+/// Timer interrupt entry, runs due timers and preempts the current task once
+/// its time slice is over.
+pub fn tick(frame: *mut Frame) -> *mut Frame {
+    if crate::timer::queue::handle_interrupt() {
+        schedule(frame)
+    } else {
+        frame
+    }
+}
+
+/// Gives up the rest of the current time slice.
+///
+/// This raises a supervisor software interrupt, so it only takes effect once
+/// interrupts are enabled.
+pub fn yield_now() {
+    unsafe {
+        asm!("csrs sip, {}", in(reg) 1 << 1);
+    }
+}
+
+/// Lets other tasks run before coming back, usable from inside a trap handler
+/// since it briefly enables interrupts to take the pending switch.
+pub fn relax() {
+    let ie = riscv::register::sstatus::read().sie();
+    yield_now();
+    unsafe {
+        riscv::register::sstatus::set_sie();
+        // the switch was taken as soon as interrupts came on, leave them how the caller had them
+        if !ie {
+            riscv::register::sstatus::clear_sie();
+        }
+    }
+}
+
+/// Marks the current task blocked without switching away yet, it stops
+/// running at the next [`schedule`] unless [`wake`] comes first.
+pub fn mark_blocked() {
+    if let Some(current) = SCHED.lock().cpu_mut().current.as_mut() {
+        current.state = TaskState::Blocked;
+    }
+}
+
+/// Parks the current task until someone calls [`wake`] with its id.
+pub fn block_current() {
+    mark_blocked();
+    yield_now();
+}
+
+pub fn wake(id: TaskId) {
+    let mut sched = SCHED.lock();
+    if let Some(mut task) = sched.blocked.remove(&id) {
+        task.state = TaskState::Ready;
+        sched.run_queue.push_back(task);
+    } else if let Some(task) = sched.on_cpu(id)
+        && task.state == TaskState::Blocked
+    {
+        // woken before it managed to switch out
+        task.state = TaskState::Running;
+    }
+}
+
+/// Terminates the current task, its stack is freed by a later [`schedule`].
+pub fn exit(exit_code: usize) -> ! {
+    let join = SCHED.lock().cpu_mut().current.as_mut().and_then(|current| {
+        current.exit_code = exit_code;
+        current.join.take()
+    });
+    // before we are marked exited, a switch in between would reap us without waking the joiner
+    if let Some(join) = join {
+        join.finish(exit_code);
+    }
+    if let Some(current) = SCHED.lock().cpu_mut().current.as_mut() {
+        current.state = TaskState::Exited;
+    }
+    loop {
+        relax();
+        riscv::asm::wfi();
+    }
+}
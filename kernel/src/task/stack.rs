@@ -0,0 +1,93 @@
+use core::arch::asm;
+
+use crate::{
+    alloc::vec::Vec,
+    arch::page::{Page, PageTableEntry},
+    mem::{KSTACK_REGION, KSTACK_REGION_SIZE},
+    sync::mutex::CriticalSpinLock,
+};
+
+/// Number of usable pages in every kernel stack.
+pub const KSTACK_PAGES: usize = 4;
+
+const PAGE_SIZE: usize = core::mem::size_of::<Page>();
+
+/// Every slot is an unmapped guard page followed by the stack itself, so running
+/// off the bottom of a stack faults instead of corrupting its neighbour.
+const SLOT_SIZE: usize = (KSTACK_PAGES + 1) * PAGE_SIZE;
+
+struct Slots {
+    next: usize,
+    /// Slots which are still mapped and backed, ready to be handed out again.
+    free: Vec<usize>,
+}
+
+static SLOTS: CriticalSpinLock<Slots> = CriticalSpinLock::new(Slots {
+    next: KSTACK_REGION,
+    free: Vec::new(),
+});
+
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Hands out a guarded stack mapped into the kernel address space.
+    ///
+    /// The first allocation creates the page table covering the whole stack region,
+    /// so it must happen before any user address space copies the kernel mappings.
+    pub fn alloc() -> Self {
+        let mut slots = SLOTS.lock();
+        if let Some(slot) = slots.free.pop() {
+            return Self { slot };
+        }
+
+        let slot = slots.next;
+        if slot + SLOT_SIZE > KSTACK_REGION + KSTACK_REGION_SIZE {
+            panic!("Out of kernel stack slots");
+        }
+        slots.next += SLOT_SIZE;
+        drop(slots);
+
+        let pages = unsafe { crate::mem::pages::pages_zeroed(KSTACK_PAGES) };
+
+        crate::mem::KERNEL_MAP
+            .lock()
+            .as_mut()
+            .expect("kernel map not initialized")
+            .map_phys_region(
+                slot + PAGE_SIZE,
+                pages.phys() as usize,
+                KSTACK_PAGES * PAGE_SIZE,
+                PageTableEntry::COM_RW | PageTableEntry::DIRTY_ACCESSED,
+                || unsafe { crate::mem::pages::pages_zeroed(1).cast() },
+            )
+            .expect("kernel stack slot already mapped");
+
+        unsafe {
+            asm!("sfence.vma");
+        }
+
+        Self { slot }
+    }
+
+    /// Lowest usable address, directly above the guard page.
+    pub fn bottom(&self) -> usize {
+        self.slot + PAGE_SIZE
+    }
+
+    pub fn top(&self) -> usize {
+        self.slot + SLOT_SIZE
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.bottom()..self.top()).contains(&addr)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        SLOTS.lock().free.push(self.slot);
+    }
+}
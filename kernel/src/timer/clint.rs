@@ -1,8 +1,10 @@
-use core::ptr::{read_volatile, write_volatile};
+use core::{
+    ptr::{read_volatile, write_volatile},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{
     dtb::{ByteStream, DtbNodes, DtbProperties},
-    interrupt::plic::{Plic, PlicDev},
     mem::Pointer,
     println,
 };
@@ -117,22 +119,18 @@ impl Clint {
     }
 }
 
-#[allow(static_mut_refs)]
-pub fn init(dtb: &crate::dtb::Dtb) {
-    for plic in dtb.nodes().compatible(b"riscv,plic0") {
-        let [start, _size] = plic.properties().expect_value(b"reg", |stream| {
-            stream.usize_cells_arr(dtb.root().addr_size_cells())
-        });
-        let max_int = plic
-            .properties()
-            .expect_value(b"riscv,ndev", ByteStream::u32);
-        unsafe {
-            let mut plic = PlicDev::new(Pointer::from_phys(start as *mut Plic).virt(), max_int);
+static CLINT_BASE: AtomicUsize = AtomicUsize::new(0);
 
-            plic.clear();
-        }
+/// The CLINT discovered by [`init`], if the platform has one.
+pub fn clint() -> Option<Clint> {
+    match CLINT_BASE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(unsafe { Clint::new(base) }),
     }
+}
 
+#[allow(static_mut_refs)]
+pub fn init(dtb: &crate::dtb::Dtb) {
     let timebase_freq = dtb
         .nodes()
         .nammed(b"cpus")
@@ -141,28 +139,18 @@ pub fn init(dtb: &crate::dtb::Dtb) {
         .properties()
         .expect_value(b"timebase-frequency", ByteStream::u32);
 
-    for clint in dtb.nodes().compatible(b"riscv,clint0") {
+    crate::timer::set_timebase_frequency(timebase_freq as u64);
+
+    if let Some(clint) = dtb.nodes().compatible(b"riscv,clint0").next() {
         let [start, _size] = clint.properties().expect_value(b"reg", |stream| {
             stream.usize_cells_arr(dtb.root().addr_size_cells())
         });
 
-        unsafe {
-            // println!("{:?}", riscv::register::medeleg::read());
-
-            let ptr = Pointer::from_phys(start as *mut ()).virt() as usize;
-            let clint = Clint::new(ptr);
-
-            clint.set_timer_relative(0, timebase_freq as u64);
-
-            // sbi_set_timer(riscv::register::time::read64() + 10000000);
-            riscv::register::sie::set_stimer();
-            // riscv::register::sie::set_ssoft();
-            riscv::register::sie::set_sext();
-            riscv::register::sstatus::set_sie();
+        let ptr = Pointer::from_phys(start as *mut ()).virt() as usize;
+        CLINT_BASE.store(ptr, Ordering::Relaxed);
+    }
 
-            // clint.send_ipi(0);
+    crate::timer::init_hart();
 
-            println!("meow");
-        }
-    }
+    println!("Initialized timer, timebase {timebase_freq}Hz");
 }
@@ -0,0 +1,85 @@
+use core::{
+    ops::{Add, AddAssign, Sub},
+    time::Duration,
+};
+
+use super::{now, timebase_frequency};
+
+/// A point on the monotonic clock, counted in ticks of the `time` CSR.
+///
+/// Every hart reads the same `mtime` through it, so instants from different harts compare.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Further away than anything we will ever wait for.
+    pub const FOREVER: Instant = Instant(u64::MAX);
+
+    pub fn now() -> Self {
+        Self(now())
+    }
+
+    pub const fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub const fn ticks(self) -> u64 {
+        self.0
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is actually later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(self) -> Duration {
+        Self::now().duration_since(self)
+    }
+}
+
+/// Whole ticks in `duration`, rounded up so waiting never ends early.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * timebase_frequency() as u128).div_ceil(1_000_000_000);
+    ticks.try_into().unwrap_or(u64::MAX)
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    match timebase_frequency() {
+        0 => Duration::ZERO,
+        frequency => {
+            let nanos = ticks as u128 * 1_000_000_000 / frequency as u128;
+            Duration::from_nanos(nanos.try_into().unwrap_or(u64::MAX))
+        }
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Saturates at [`Instant::FOREVER`].
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ticks(rhs)))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_sub(duration_to_ticks(rhs)))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
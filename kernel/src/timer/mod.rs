@@ -1 +1,48 @@
+//! The monotonic clock and per-hart timers.
+//!
+//! Time is read from the `time` CSR, which mirrors the CLINT's `mtime`, and
+//! converted with the DTB `timebase-frequency`. Timer interrupts are requested
+//! from the SBI firmware, see [`queue`].
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
 pub mod clint;
+pub mod instant;
+pub mod queue;
+
+pub use core::time::Duration;
+pub use instant::Instant;
+pub use queue::{TimerId, after, at, cancel, sleep, sleep_until};
+
+static TIMEBASE_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Frequency of the `time` CSR in Hz, as reported by the DTB `timebase-frequency`.
+pub fn timebase_frequency() -> u64 {
+    TIMEBASE_FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn set_timebase_frequency(frequency: u64) {
+    TIMEBASE_FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// Current value of the `time` CSR.
+pub fn now() -> u64 {
+    riscv::register::time::read64()
+}
+
+/// Raises the next S-mode timer interrupt once `time` reaches `deadline`.
+/// There is one per hart, which [`queue`] keeps track of.
+fn set_deadline(deadline: u64) {
+    crate::sbi::sbi_set_timer(deadline);
+}
+
+/// Arms the first time slice of the calling hart and enables its timer interrupt.
+pub fn init_hart() {
+    queue::init_hart();
+
+    unsafe {
+        riscv::register::sie::set_stimer();
+        // riscv::register::sie::set_ssoft();
+        riscv::register::sstatus::set_sie();
+    }
+}
@@ -0,0 +1,196 @@
+//! Per-hart queues of pending timers.
+//!
+//! Each hart keeps its own timers along with the end of its current time
+//! slice, and programs its timer interrupt for whichever of them comes first.
+//! Timers fire on the hart that armed them.
+
+use core::time::Duration;
+
+use crate::{
+    alloc::collections::BTreeMap,
+    arch::{self, smp::MAX_HARTS},
+    sync::mutex::{CriticalSpinLock, CriticalSpinLockGuard},
+    task::{TaskId, sched},
+};
+
+use super::{Instant, set_deadline};
+
+/// Refers to an armed timer, see [`cancel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    hart: usize,
+    deadline: Instant,
+    seq: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Action {
+    Wake(TaskId),
+    Callback(fn(usize), usize),
+}
+
+struct Queue {
+    /// Ordered by deadline, `seq` keeps timers with the same deadline apart.
+    timers: BTreeMap<(Instant, u64), Action>,
+    next_seq: u64,
+    /// When the running task's time slice ends.
+    slice_end: Instant,
+    /// What the timer interrupt is currently programmed for.
+    programmed: Instant,
+}
+
+impl Queue {
+    const fn new() -> Self {
+        Self {
+            timers: BTreeMap::new(),
+            next_seq: 0,
+            slice_end: Instant::FOREVER,
+            programmed: Instant::FOREVER,
+        }
+    }
+
+    fn insert(&mut self, hart: usize, deadline: Instant, action: Action) -> TimerId {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.timers.insert((deadline, seq), action);
+        self.reprogram();
+        TimerId {
+            hart,
+            deadline,
+            seq,
+        }
+    }
+
+    fn next_deadline(&self) -> Instant {
+        let timer = self
+            .timers
+            .first_key_value()
+            .map_or(Instant::FOREVER, |(&(deadline, _), _)| deadline);
+        timer.min(self.slice_end)
+    }
+
+    /// Moves the timer interrupt to the earliest deadline, must run on the queue's hart.
+    fn reprogram(&mut self) {
+        let next = self.next_deadline();
+        if next != self.programmed {
+            self.programmed = next;
+            set_deadline(next.ticks());
+        }
+    }
+}
+
+static QUEUES: [CriticalSpinLock<Queue>; MAX_HARTS] =
+    [const { CriticalSpinLock::new(Queue::new()) }; MAX_HARTS];
+
+/// The calling hart's queue and its index, locked before the hart is read for good.
+fn queue() -> (usize, CriticalSpinLockGuard<'static, Queue>) {
+    CriticalSpinLock::lock_indexed(&QUEUES, arch::current_hart)
+}
+
+fn quantum() -> Duration {
+    Duration::from_secs(1) / sched::QUANTUM_HZ as u32
+}
+
+/// Starts the first time slice of the calling hart.
+pub(super) fn init_hart() {
+    let (_, mut queue) = queue();
+    queue.slice_end = Instant::now() + quantum();
+    queue.reprogram();
+}
+
+/// Runs the timers of the calling hart that are due, returning whether the
+/// time slice of the running task is over. Called from the timer interrupt.
+pub fn handle_interrupt() -> bool {
+    let now = Instant::now();
+    // the lock is dropped before each action, which may arm timers of its own
+    loop {
+        let action = {
+            let (_, mut queue) = queue();
+            match queue.timers.first_entry() {
+                Some(entry) if entry.key().0 <= now => entry.remove(),
+                _ => break,
+            }
+        };
+        match action {
+            Action::Wake(id) => sched::wake(id),
+            Action::Callback(callback, arg) => callback(arg),
+        }
+    }
+
+    let (_, mut queue) = queue();
+    let slice_over = queue.slice_end <= now;
+    if slice_over {
+        queue.slice_end = now + quantum();
+    }
+    // the interrupt that got us here used up whatever was programmed
+    queue.programmed = Instant::FOREVER;
+    queue.reprogram();
+    slice_over
+}
+
+/// Calls `callback(arg)` once `deadline` has passed. It runs in the timer
+/// interrupt of the calling hart, with interrupts disabled, so it must not block.
+pub fn at(deadline: Instant, callback: fn(usize), arg: usize) -> TimerId {
+    let (hart, mut queue) = queue();
+    queue.insert(hart, deadline, Action::Callback(callback, arg))
+}
+
+/// Like [`at`], `delay` from now.
+pub fn after(delay: Duration, callback: fn(usize), arg: usize) -> TimerId {
+    at(Instant::now() + delay, callback, arg)
+}
+
+/// Disarms `timer`, returning false if it already fired.
+pub fn cancel(timer: TimerId) -> bool {
+    let Some(queue) = QUEUES.get(timer.hart) else {
+        return false;
+    };
+    // another hart's interrupt will notice it has nothing to do
+    queue
+        .lock()
+        .timers
+        .remove(&(timer.deadline, timer.seq))
+        .is_some()
+}
+
+/// Blocks the current task until `deadline`, letting other tasks run.
+///
+/// Outside of a task, early during boot, this spins instead.
+pub fn sleep_until(deadline: Instant) {
+    let Some(id) = sched::current_id() else {
+        while Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+        return;
+    };
+
+    if Instant::now() >= deadline {
+        return;
+    }
+    let timer = {
+        // interrupts stay off until we are marked blocked, so the wake can't get lost
+        let (hart, mut queue) = queue();
+        let timer = queue.insert(hart, deadline, Action::Wake(id));
+        sched::mark_blocked();
+        timer
+    };
+    loop {
+        sched::relax();
+        if Instant::now() >= deadline {
+            break;
+        }
+        // woken early by someone else, the timer armed above is still pending
+        sched::mark_blocked();
+        // it may have fired just before we blocked again
+        if Instant::now() >= deadline {
+            sched::wake(id);
+            break;
+        }
+    }
+    // a timer left behind would wake us out of some unrelated block later
+    cancel(timer);
+}
+
+pub fn sleep(duration: Duration) {
+    sleep_until(Instant::now() + duration)
+}
@@ -1 +1,3 @@
+pub mod crc32;
 pub mod hexdump;
+pub mod ring;
@@ -1 +1,2 @@
-truncate -s 32M fs.img
+truncate -s 64M fs.img
+mkfs.fat -F 32 -n KERNEL fs.img
@@ -0,0 +1,69 @@
+//! The first user program, started from the initramfs once the kernel is up.
+//!
+//! Greets on the console, checks that a forked child runs and exits with how
+//! many of those steps failed.
+
+#![no_std]
+#![no_main]
+
+use core::arch::asm;
+
+const READ: usize = 0;
+const WRITE: usize = 1;
+const EXIT: usize = 2;
+const GETPID: usize = 4;
+const FORK: usize = 7;
+
+const STDOUT: usize = 1;
+
+fn syscall(nr: usize, a0: usize, a1: usize, a2: usize) -> Result<usize, usize> {
+    let ret: usize;
+    unsafe {
+        asm!("ecall", in("a7") nr, inlateout("a0") a0 => ret, in("a1") a1, in("a2") a2, options(nostack));
+    }
+    // -4095..0 is an errno
+    if ret > -4096isize as usize {
+        Err(ret.wrapping_neg())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn write(text: &str) -> Result<usize, usize> {
+    syscall(WRITE, STDOUT, text.as_ptr() as usize, text.len())
+}
+
+fn exit(code: usize) -> ! {
+    _ = syscall(EXIT, code, 0, 0);
+    unreachable!("exit returned")
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn _start() -> ! {
+    let mut failed = 0;
+    if write("Hello from /init\n").is_err() {
+        failed += 1;
+    }
+    if syscall(GETPID, 0, 0, 0).is_err() {
+        failed += 1;
+    }
+    // reading from a bad descriptor has to fail cleanly rather than kill us
+    if syscall(READ, usize::MAX, 0, 0).is_ok() {
+        failed += 1;
+    }
+    match syscall(FORK, 0, 0, 0) {
+        Ok(0) => {
+            _ = write("Hello from a child of /init\n");
+            exit(0)
+        }
+        Ok(_) => {}
+        Err(_) => failed += 1,
+    }
+    exit(failed)
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    _ = write("/init panicked\n");
+    exit(usize::MAX)
+}
@@ -10,6 +10,9 @@ use elf::ElfBytes;
 
 const TARGET: &str = "riscv64gc-unknown-none-elf";
 
+/// Where xrun picks up the initial ramdisk.
+const INITRAMFS: &str = "run/initramfs.cpio";
+
 fn rustflags_string() -> String {
     let flags: &[&str] = &[
         "-C", "link-arg=--emit-relocs",
@@ -61,6 +64,36 @@ fn cargo_nightly() -> OsString {
     OsString::from("cargo")
 }
 
+/// Builds the user program in `user/<name>` for the same target, next to the kernel.
+fn build_user(name: &str, release: bool) -> Result<(), String> {
+    let mut cmd = Command::new(cargo_nightly());
+
+    if cmd.get_program() == "cargo" {
+        cmd.arg("+nightly");
+    }
+
+    cmd.arg("build")
+        .arg("-Z")
+        .arg("build-std=core,compiler_builtins")
+        .arg("-Z")
+        .arg("build-std-features=compiler-builtins-mem")
+        .arg("--target")
+        .arg(TARGET)
+        .arg("--manifest-path")
+        .arg(Path::new("user").join(name).join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg("target");
+
+    if release {
+        cmd.arg("--release");
+    }
+
+    // the kernel's link flags don't apply to user programs
+    cmd.env_remove("RUSTFLAGS");
+
+    run_cmd(&mut cmd)
+}
+
 fn build(bin: &str, release: bool) -> Result<(), String> {
     let mut cmd = Command::new(cargo_nightly());
 
@@ -99,11 +132,22 @@ fn main() -> ExitCode {
         }
     }
 
-    let result = build("kernel", release);
+    let profile = if release {"release"} else {"debug"};
+    let result = build("kernel", release).and_then(|()| {
+        // embeds the symbol table used for backtraces, written next to the kernel as `kernel.elf`
+        relink(kernel_elf_path("kernel", profile))
+    }).and_then(|()| {
+        // what OpenSBI, U-Boot `booti` and the like load
+        let mut elf = kernel_elf_path("kernel", profile);
+        elf.add_extension("elf");
+        write_image(&elf, &kernel_elf_path("Image", profile))
+    }).and_then(|()| {
+        // booted as /init, see xrun
+        build_user("init", release)?;
+        let init = kernel_elf_path("init", profile);
+        write_initramfs(&[("init", init.as_path())], Path::new(INITRAMFS))
+    });
 
-    let path = kernel_elf_path("kernel", if release {"release"} else {"debug"});
-    // relink(path);
-    
     match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(e) => {
@@ -113,7 +157,7 @@ fn main() -> ExitCode {
     }
 }
 
-fn relink(path: PathBuf){
+fn relink(path: PathBuf) -> Result<(), String> {
     emit_and_relink(
     path,
     "link.map".into(),
@@ -121,10 +165,136 @@ fn relink(path: PathBuf){
     "ld.lld",
     "elf64-littleriscv",
     "riscv",
-    ).expect("msg");
+    )
 }
 
 
+/// `text_offset` of the `Image` header, how far past a 2MiB aligned address the
+/// kernel wants to be loaded. It only needs the alignment.
+const IMAGE_TEXT_OFFSET: u64 = 0x20_0000;
+/// Header version 0.2.
+const IMAGE_VERSION: u32 = 2;
+const IMAGE_MAGIC: &[u8; 8] = b"RISCV\0\0\0";
+const IMAGE_MAGIC2: &[u8; 4] = b"RSC\x05";
+
+/// `jal zero, offset`, which the header starts with.
+fn jump(offset: u64) -> u32 {
+    let imm = offset as u32;
+    assert!(offset < 1 << 20 && imm % 2 == 0, "entry point out of jump range");
+    ((imm >> 20) & 1) << 31
+        | ((imm >> 1) & 0x3ff) << 21
+        | ((imm >> 11) & 1) << 20
+        | ((imm >> 12) & 0xff) << 12
+        | 0x6f
+}
+
+/// Flattens the kernel ELF into a RISC-V Linux `Image`, starting at `_kernel_start`.
+///
+/// The ELF headers are not needed once loaded, so the 64 byte `Image` header
+/// takes their place and jumps to the entry point.
+fn write_image(elf_path: &Path, out: &Path) -> Result<(), String> {
+    let file_data = fs::read(elf_path).map_err(|e| format!("reading {}: {e}", elf_path.display()))?;
+    let file = ElfBytes::<AnyEndian>::minimal_parse(&file_data)
+        .map_err(|e| format!("ELF parse: {e}"))?;
+
+    let (symtab, strtab) = file
+        .symbol_table()
+        .map_err(|e| format!("symbol table: {e}"))?
+        .ok_or("no .symtab found")?;
+    let symbol = |name: &str| {
+        symtab
+            .iter()
+            .find(|sym| strtab.get(sym.st_name as usize).is_ok_and(|n| n == name))
+            .map(|sym| sym.st_value)
+            .ok_or_else(|| format!("no {name} symbol"))
+    };
+    let start = symbol("_kernel_start")?;
+    let end = symbol("_kernel_end")?;
+
+    let mut image = Vec::new();
+    let segments = file.segments().ok_or("no program headers")?;
+    for phdr in segments.iter().filter(|phdr| phdr.p_type == elf::abi::PT_LOAD) {
+        let offset = phdr
+            .p_vaddr
+            .checked_sub(start)
+            .ok_or("segment below _kernel_start")? as usize;
+        let data = file_data
+            .get(phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize)
+            .ok_or("segment outside of the file")?;
+        if image.len() < offset + data.len() {
+            image.resize(offset + data.len(), 0);
+        }
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+    if image.len() < 64 {
+        image.resize(64, 0);
+    }
+
+    let mut header = Vec::with_capacity(64);
+    header.extend_from_slice(&jump(file.ehdr.e_entry - start).to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&IMAGE_TEXT_OFFSET.to_le_bytes());
+    // what has to fit in memory, `.bss` and the boot stack included
+    header.extend_from_slice(&(end - start).to_le_bytes());
+    // little endian
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&IMAGE_VERSION.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(IMAGE_MAGIC);
+    header.extend_from_slice(IMAGE_MAGIC2);
+    // no PE/COFF header
+    header.extend_from_slice(&0u32.to_le_bytes());
+    image[..64].copy_from_slice(&header);
+
+    fs::write(out, image).map_err(|e| format!("writing {}: {e}", out.display()))
+}
+
+/// Appends one `newc` cpio entry, the format the kernel's initramfs reads.
+fn cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+    let fields = [
+        0, // ino
+        mode,
+        0, // uid
+        0, // gid
+        1, // nlink
+        0, // mtime
+        data.len() as u32,
+        0, // devmajor
+        0, // devminor
+        0, // rdevmajor
+        0, // rdevminor
+        name.len() as u32 + 1,
+        0, // check
+    ];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08x}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+/// Packs `files`, each a name in the archive and the file to read it from, into a cpio archive.
+fn write_initramfs(files: &[(&str, &Path)], out: &Path) -> Result<(), String> {
+    const S_IFREG: u32 = 0o100000;
+
+    let mut archive = Vec::new();
+    for &(name, path) in files {
+        let data = fs::read(path).map_err(|e| format!("reading {}: {e}", path.display()))?;
+        cpio_entry(&mut archive, name, S_IFREG | 0o755, &data);
+    }
+    cpio_entry(&mut archive, "TRAILER!!!", 0, &[]);
+
+    if let Some(dir) = out.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("creating {}: {e}", dir.display()))?;
+    }
+    fs::write(out, archive).map_err(|e| format!("writing {}: {e}", out.display()))
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
 struct KSym {
@@ -184,6 +354,10 @@ fn build_ksyms_from_elf(elf_path: &Path) -> io::Result<(Vec<u8>, Vec<u8>)> {
         });
     }
 
+    // the kernel binary searches the table
+    ksym_entries.sort_by_key(|e| e.addr);
+    ksym_entries.dedup_by_key(|e| e.addr);
+
     let mut ksymtab: Vec<u8> = Vec::with_capacity(ksym_entries.len() * 16);
     for e in &ksym_entries {
         ksymtab.extend_from_slice(&e.addr.to_le_bytes());
@@ -212,7 +386,8 @@ pub fn emit_and_relink(
     ));
     fs::create_dir_all(&tmp_base).unwrap();
 
-    let (ksymtab_bin, kstrtab_bin) = build_ksyms_from_elf(&existing_elf).unwrap();
+    let (ksymtab_bin, kstrtab_bin) =
+        build_ksyms_from_elf(&existing_elf).map_err(|e| format!("building symbol table: {e}"))?;
 
     let ksymtab_path = tmp_base.join("ksymtab.bin");
     let kstrtab_path = tmp_base.join("kstrtab.bin");
@@ -6,6 +6,9 @@ use std::{
 
 const TARGET: &str = "riscv64gc-unknown-none-elf";
 
+/// Passed as the initial ramdisk whenever it exists.
+const INITRD: &str = "run/initramfs.cpio";
+
 
 fn kernel_elf_path(bin: &str, profile: &str) -> PathBuf {
     PathBuf::from("target")
@@ -39,11 +42,26 @@ fn format_command_for_print(cmd: &Command) -> Option<String> {
 
 
 
-fn qemu_args() -> Vec<String> {
+/// Which firmware runs the kernel in S-mode.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Firmware {
+    /// Our own, the kernel is entered in M-mode. See `arch::riscv64::mtrap`.
+    Builtin,
+    /// QEMU's OpenSBI, which boots the `Image` like on a real board.
+    OpenSbi,
+}
+
+fn qemu_args(firmware: Firmware) -> Vec<String> {
     vec![
         "qemu-system-riscv64".into(),
         "-machine".into(),
         "virt,acpi=off".into(),
+        "-bios".into(),
+        match firmware {
+            Firmware::Builtin => "none",
+            Firmware::OpenSbi => "default",
+        }
+        .into(),
         "-smp".into(),
         "4".into(),
         "-m".into(),
@@ -75,9 +93,22 @@ fn qemu_args() -> Vec<String> {
     ]
 }
 
-fn run(bin: &str, release: bool, extra_qemu_args: &[String]) -> Result<(), String> {
+fn run(
+    bin: &str,
+    release: bool,
+    firmware: Firmware,
+    extra_qemu_args: &[String],
+) -> Result<(), String> {
     let profile = if release { "release" } else { "debug" };
-    let elf = kernel_elf_path(bin, profile);
+    let mut elf = kernel_elf_path(bin, profile);
+    // prefer the relinked kernel, which carries its symbol table
+    elf.add_extension("elf");
+    if !elf.is_file() {
+        elf = kernel_elf_path(bin, profile);
+    }
+    if firmware == Firmware::OpenSbi {
+        elf = kernel_elf_path("Image", profile);
+    }
 
     if !elf.is_file() {
         return Err(format!(
@@ -86,13 +117,18 @@ fn run(bin: &str, release: bool, extra_qemu_args: &[String]) -> Result<(), Strin
         ));
     }
 
-    let mut args = qemu_args();
+    let mut args = qemu_args(firmware);
 
     let program = args.remove(0);
 
     args.push("-kernel".into());
     args.push(elf.to_string_lossy().to_string());
 
+    if PathBuf::from(INITRD).is_file() {
+        args.push("-initrd".into());
+        args.push(INITRD.into());
+    }
+
     args.extend_from_slice(extra_qemu_args);
 
     let mut cmd = Command::new(program);
@@ -104,6 +140,7 @@ fn run(bin: &str, release: bool, extra_qemu_args: &[String]) -> Result<(), Strin
 
 fn main() -> ExitCode {
     let mut release = false;
+    let mut firmware = Firmware::Builtin;
     let mut extra_qemu_args: Vec<String> = Vec::new();
 
     let mut build_command = Command::new("cargo");
@@ -114,6 +151,8 @@ fn main() -> ExitCode {
         if !passthrough && a == "--release" {
             release = true;
             build_command.arg("--").arg("--release");
+        } else if !passthrough && a == "--opensbi" {
+            firmware = Firmware::OpenSbi;
         } else if !passthrough && a == "--" {
             passthrough = true;
         } else {
@@ -129,7 +168,7 @@ fn main() -> ExitCode {
         }
     }
 
-    let result = run("kernel", release, &extra_qemu_args);
+    let result = run("kernel", release, firmware, &extra_qemu_args);
 
     match result {
         Ok(()) => ExitCode::SUCCESS,